@@ -61,6 +61,52 @@ fn benchmark_getting_subset_of_inserted_addresses(
 		});
 }
 
+/// inserts `insert_count` addresses and then benchmarks getting
+/// all addresses from a subset of size `get_subset_count`
+/// with a single `get_many` call on each benchmark iteration
+fn benchmark_getting_many_of_inserted_addresses(
+		b: &mut Bencher,
+		insert_count: usize,
+		get_subset_count: usize,
+		key_index_bits: u8
+) {
+		assert!(insert_count >= get_subset_count);
+
+		let temp = TempDir::new(
+				format!(
+						"benchmark_paritydb_get_many_{}_from_{}_addresses",
+						get_subset_count,
+						insert_count
+						).as_str())
+				.unwrap();
+		let mut db = Database::create(temp.path(), Options {
+				journal_eras: 0,
+				key_len: Address::len(),
+				value_len: ValuesLen::Constant(0),
+				key_index_bits: key_index_bits,
+				..Default::default()
+		}).unwrap();
+
+		let mut addresses_subset: Vec<Address> = Vec::new();
+		let mut rng = StdRng::from_seed(&[1, 9, 4]);
+
+		let mut tx = db.create_transaction();
+		for i in 0..insert_count {
+				let address = Address::rand(&mut rng);
+				tx.insert(address, []).unwrap();
+				if i < get_subset_count {
+						addresses_subset.push(address);
+				}
+		}
+		db.commit(&tx).unwrap();
+
+		db.flush_journal(1).unwrap();
+
+		b.iter(&mut || {
+				db.get_many(&addresses_subset).unwrap();
+		});
+}
+
 const LARGE_KEY_INDEX_BITS: u8 = 20;
 
 #[bench]
@@ -102,3 +148,13 @@ fn paritydb_from_1000000_inserted_get_random_of_1000_addresses(b: &mut Bencher)
 fn paritydb_from_1000000_inserted_get_random_of_1000000_addresses(b: &mut Bencher) {
 		benchmark_getting_subset_of_inserted_addresses(b, 1000000, 1000000, LARGE_KEY_INDEX_BITS);
 }
+
+#[bench]
+fn paritydb_from_100000_inserted_get_many_1000_addresses(b: &mut Bencher) {
+		benchmark_getting_many_of_inserted_addresses(b, 100000, 1000, LARGE_KEY_INDEX_BITS);
+}
+
+#[bench]
+fn paritydb_from_1000000_inserted_get_many_1000_addresses(b: &mut Bencher) {
+		benchmark_getting_many_of_inserted_addresses(b, 1000000, 1000, LARGE_KEY_INDEX_BITS);
+}
@@ -16,7 +16,7 @@ fn read_parameters<'a>(matches: &'a ArgMatches) -> Result<(&'a str, &'a str, Opt
 }
 
 fn do_get(db: &str, key: &str) -> Result<(), Error> {
-	let db = Database::open(db, Options::default())?;
+	let db = Database::open_with_stored_options(db)?;
 	let ret = db.get(key);
 	match ret {
 		Ok(Some(value)) => {
@@ -33,7 +33,7 @@ fn do_get(db: &str, key: &str) -> Result<(), Error> {
 }
 
 fn do_insert(db: &str, key: &str, value: &str) -> Result<(), Error> {
-	let mut db = Database::open(db, Options::default())
+	let mut db = Database::open_with_stored_options(db)
 				.or(Database::create(db, Options::default()))?;
 	let mut tx = db.create_transaction();
 	tx.insert(key, value)?;
@@ -43,7 +43,7 @@ fn do_insert(db: &str, key: &str, value: &str) -> Result<(), Error> {
 }
 
 fn do_delete(db: &str, key: &str) -> Result<(), Error> {
-	let mut db = Database::open(db, Options::default())?;
+	let mut db = Database::open_with_stored_options(db)?;
 	let mut tx = db.create_transaction();
 	tx.delete(key)?;
 	db.commit(&tx)?;
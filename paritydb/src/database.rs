@@ -1,33 +1,89 @@
 use std::cmp::Ordering;
-use std::collections::btree_set;
-use std::io::Write;
+use std::io::{self, Write};
+use std::ops::Range;
 use std::path::{PathBuf, Path};
-use std::{cmp, fs};
+use std::{cmp, fs, vec};
 use std::fs::File;
+use std::sync::Arc;
 
 use fs2::FileExt;
-use memmap::{Mmap, Protection};
 
 use error::{ErrorKind, Result};
+use field;
 use find;
 use flush::Flush;
-use journal::Journal;
+use flush_lock::{self, ReadGuard, ReadLock};
+use journal::{Journal, JournalEntry, JournalEra, PreparedTransaction};
 use key::Key;
-use metadata::{self, Metadata};
-use options::{Options, InternalOptions};
+use merge::{self, SharedMergeOperator};
+use metadata::Metadata;
+use namespace::{self, Namespace};
+use options::{DatabaseOptions, InternalOptions, ValuesLen};
+use preparer::Preparer;
+use reader::DatabaseReader;
 use record::Record;
-use transaction::{Operation, Transaction};
+use snapshot::Snapshot;
+use space::{Space, SpaceIterator};
+use stats::{self, Stats};
+use transaction::{self, Condition, Operation, Transaction};
+use verify::{self, ConsistencyReport};
 
 /// A database record value.
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub enum Value<'a> {
 	/// Raw (cached/journaled) data
 	Raw(&'a [u8]),
 	/// DB record
 	Record(Record<'a>),
+	/// Value combined with merge operands or copied from flushed data
+	Merged(Vec<u8>),
 }
 
 impl<'a> Value<'a> {
+	/// Returns value length.
+	pub fn len(&self) -> usize {
+		match *self {
+			Value::Raw(slice) => slice.len(),
+			Value::Record(ref record) => record.value_len(),
+			Value::Merged(ref merged) => merged.len(),
+		}
+	}
+
+	/// Returns true if the value is empty.
+	pub fn is_empty(&self) -> bool {
+		self.len() == 0
+	}
+
+	/// Reads value starting at `offset` to given slice.
+	///
+	/// Returns number of bytes read, which is lower than the size of the slice
+	/// only if the end of the value is reached.
+	pub fn read_at(&self, offset: usize, slice: &mut [u8]) -> usize {
+		fn read_raw_at(raw: &[u8], offset: usize, slice: &mut [u8]) -> usize {
+			if offset >= raw.len() {
+				return 0;
+			}
+
+			let len = cmp::min(raw.len() - offset, slice.len());
+			slice[..len].copy_from_slice(&raw[offset..offset + len]);
+			len
+		}
+
+		match *self {
+			Value::Raw(raw) => read_raw_at(raw, offset, slice),
+			Value::Record(ref record) => record.read_value_at(offset, slice),
+			Value::Merged(ref merged) => read_raw_at(merged, offset, slice),
+		}
+	}
+
+	/// Returns a reader of the value, which doesn't copy it to memory at once.
+	pub fn reader(&self) -> ValueReader<'a> {
+		ValueReader {
+			value: self.clone(),
+			position: 0,
+		}
+	}
+
 	/// Allocate a `Vec` with the value.
 	pub fn to_vec(&self) -> Vec<u8> {
 		match *self {
@@ -37,7 +93,8 @@ impl<'a> Value<'a> {
 				v.resize(record.value_len(), 0);
 				record.read_value(&mut v);
 				v
-			}
+			},
+			Value::Merged(ref merged) => merged.clone(),
 		}
 	}
 }
@@ -47,6 +104,7 @@ impl<'a, T: AsRef<[u8]>> PartialEq<T> for Value<'a> {
 		match *self {
 			Value::Raw(slice) => slice == other.as_ref(),
 			Value::Record(ref record) => record.value_is_equal(other.as_ref()),
+			Value::Merged(ref merged) => &merged[..] == other.as_ref(),
 		}
 	}
 }
@@ -60,22 +118,80 @@ impl<'a> From<Record<'a>> for Value<'a> {
 	}
 }
 
+/// Implements `io::Read` and `io::Seek` over a database value.
+#[derive(Debug)]
+pub struct ValueReader<'a> {
+	value: Value<'a>,
+	position: u64,
+}
+
+impl<'a> io::Read for ValueReader<'a> {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		if self.position >= self.value.len() as u64 {
+			return Ok(0);
+		}
+
+		let read = self.value.read_at(self.position as usize, buf);
+		self.position += read as u64;
+		Ok(read)
+	}
+}
+
+impl<'a> io::Seek for ValueReader<'a> {
+	fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+		let (base, offset) = match pos {
+			io::SeekFrom::Start(offset) => {
+				self.position = offset;
+				return Ok(offset);
+			},
+			io::SeekFrom::End(offset) => (self.value.len() as u64, offset),
+			io::SeekFrom::Current(offset) => (self.position, offset),
+		};
+
+		let position = if offset >= 0 {
+			base.checked_add(offset as u64)
+		} else {
+			base.checked_sub(offset.wrapping_neg() as u64)
+		};
+
+		match position {
+			Some(position) => {
+				self.position = position;
+				Ok(position)
+			},
+			None => Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid seek to a negative or overflowing position")),
+		}
+	}
+}
+
 /// A top-level database API.
 #[derive(Debug)]
 pub struct Database {
 	path: PathBuf,
+	/// Options of the default namespace.
 	options: InternalOptions,
+	/// Names of named namespaces in the order they were created in.
+	names: Vec<String>,
 	journal: Journal,
-	metadata: Metadata,
-	metadata_mmap: Mmap,
-	mmap: Mmap,
-	lock_file: File,
+	/// The default namespace followed by named namespaces in the order of `names`.
+	namespaces: Vec<Namespace>,
+	reader: DatabaseReader,
+	preparer: Preparer,
+	/// Exclusively locked by the writer. `None` for read-only handles.
+	lock_file: Option<File>,
+	/// Locked exclusively by the writer while it modifies flushed data. `None` for read-only handles.
+	flush_lock_file: Option<File>,
+	/// Locked by read-only handles around each read of flushed data. `None` for the writer.
+	read_lock: Option<ReadLock>,
+	/// True if compaction was deferred, cause flushed data was read by other processes.
+	compaction_pending: bool,
 }
 
 impl Database {
-	const DB_FILE: &'static str = "data.db";
-	const META_FILE: &'static str = "meta.db";
 	const LOCK_FILE: &'static str = "LOCK";
+	const FLUSH_LOCK_FILE: &'static str = "FLUSH_LOCK";
+	const NAMESPACES_FILE: &'static str = "NAMESPACES";
+	const NAMESPACES_DIR: &'static str = "namespaces";
 
 	fn acquire_lock_file<P: AsRef<Path>>(path: P) -> Result<File> {
 		let lock_file_path = path.as_ref().join(Self::LOCK_FILE);
@@ -88,9 +204,23 @@ impl Database {
 		Ok(lock_file)
 	}
 
+	/// Opens the flush lock of a read-only handle and locks it until the guard is dropped.
+	fn open_read_lock<P: AsRef<Path>>(path: P) -> Result<(Option<ReadLock>, Option<ReadGuard>)> {
+		match ReadLock::open(path.as_ref().join(Self::FLUSH_LOCK_FILE))? {
+			Some((read_lock, guard)) => Ok((Some(read_lock), Some(guard))),
+			None => Ok((None, None)),
+		}
+	}
+
+	fn namespace_path<P: AsRef<Path>>(path: P, name: &str) -> PathBuf {
+		path.as_ref().join(Self::NAMESPACES_DIR).join(name)
+	}
+
 	/// Creates new database at given location.
-	pub fn create<P: AsRef<Path>>(path: P, options: Options) -> Result<Self> {
-		let options = InternalOptions::from_external(options)?;
+	pub fn create<P: AsRef<Path>, O: Into<DatabaseOptions>>(path: P, options: O) -> Result<Self> {
+		let database_options = options.into();
+		let internal = database_options.to_internal()?;
+		let options = &internal[0];
 
 		// Create directories if necessary.
 		fs::create_dir_all(&path)?;
@@ -98,411 +228,2840 @@ impl Database {
 		// Create/Acquire Lock file.
 		let lock_file = Self::acquire_lock_file(&path)?;
 
-		// Create DB file.
-		{
-			let db_file_path = path.as_ref().join(Self::DB_FILE);
-			let mut file = fs::OpenOptions::new()
-				.write(true)
-				.create_new(true)
-				.open(&db_file_path)?;
-			file.set_len(options.initial_db_size)?;
-			file.flush()?;
-		}
+		// Create files of the default namespace.
+		Namespace::create(&path, options)?;
+
+		// Create files of named namespaces and remember their order.
+		if !database_options.namespaces.is_empty() {
+			let mut names = String::new();
+			for ((name, _), namespace_options) in database_options.namespaces.iter().zip(&internal[1..]) {
+				let namespace_path = Self::namespace_path(&path, name);
+				fs::create_dir_all(&namespace_path)?;
+				Namespace::create(&namespace_path, namespace_options)?;
+				names.push_str(name);
+				names.push('\n');
+			}
 
-		// Create Metadata file.
-		{
-			let meta_file_path = path.as_ref().join(Self::META_FILE);
 			let mut file = fs::OpenOptions::new()
 				.write(true)
 				.create_new(true)
-				.open(&meta_file_path)?;
-			file.set_len(metadata::bytes::len(options.external.key_index_bits) as u64)?;
+				.open(path.as_ref().join(Self::NAMESPACES_FILE))?;
+			file.write_all(names.as_bytes())?;
 			file.flush()?;
+			options.external.durability.sync_file(&file)?;
+			options.external.durability.sync_dir(path.as_ref().join(Self::NAMESPACES_DIR))?;
 		}
+		options.external.durability.sync_dir(&path)?;
 
-		Self::open_internal(path, lock_file, options.external)
+		Self::open_internal(path, Some(lock_file), database_options)
 	}
 
 	/// Opens an existing DB at given location.
-	pub fn open<P: AsRef<Path>>(path: P, options: Options) -> Result<Self> {
+	///
+	/// Named namespaces must be the same as when the database was created.
+	pub fn open<P: AsRef<Path>, O: Into<DatabaseOptions>>(path: P, options: O) -> Result<Self> {
 		let lock_file = Self::acquire_lock_file(&path)?;
-		Self::open_internal(path, lock_file, options)
+		Self::open_internal(path, Some(lock_file), options.into())
+	}
+
+	/// Opens an existing DB at given location for reading only.
+	///
+	/// It can be opened while the database is used by another process.
+	/// Flushed data is locked only while it's read, so the writer doesn't flush
+	/// its journal during lookups and as long as iterators of the read-only handle exist.
+	/// Values of flushed records are copied, cause they may change once the lock is released.
+	/// Reads fail with `FlushedDataChanged` after the writer modifies the flushed data.
+	/// It's loaded again together with eras committed later by `reload_journal`.
+	///
+	/// Pending flush is not recovered. It's recovered by the writer when it's opened.
+	pub fn open_read_only<P: AsRef<Path>, O: Into<DatabaseOptions>>(path: P, options: O) -> Result<Self> {
+		Self::open_internal(path, None, options.into())
+	}
+
+	/// Rebuilds metadata files of the database and all its namespaces by scanning their data files.
+	///
+	/// It's meant for recovery when `meta.db` is lost or damaged.
+	/// The database must not be opened by any other handle. Journal is not modified.
+	/// Fails with `MisplacedRecord` if number of prefix bits can't be determined from the size of a data file.
+	pub fn rebuild_metadata<P: AsRef<Path>, O: Into<DatabaseOptions>>(path: P, options: O) -> Result<()> {
+		let options = options.into();
+		let internal = options.to_internal()?;
+		let _lock_file = Self::acquire_lock_file(&path)?;
+		Self::check_namespaces(&path, &options.names())?;
+
+		let flush_lock_path = path.as_ref().join(Self::FLUSH_LOCK_FILE);
+		let flush_lock_file = flush_lock::open_writable(&flush_lock_path)?;
+		flush_lock_file.try_lock_exclusive().map_err(|_| ErrorKind::DatabaseLocked(flush_lock_path))?;
+
+		// eras in the journal are considered not flushed
+		let next_era = Journal::open_read_only(&path)?.first_era_index();
+		Namespace::rebuild_metadata(&path, &internal[0], next_era)?;
+		for (name, namespace_options) in options.names().iter().zip(&internal[1..]) {
+			Namespace::rebuild_metadata(Self::namespace_path(&path, name), namespace_options, next_era)?;
+		}
+
+		flush_lock::increment_generation(&flush_lock_file)
+	}
+
+	/// Opens an existing DB at given location with the options it was created with.
+	///
+	/// Options which don't determine the layout of the database have default values.
+	/// Fails with `OptionsNotStored` if the database was created by an older version
+	/// and hasn't been opened with `open` since.
+	pub fn open_with_stored_options<P: AsRef<Path>>(path: P) -> Result<Self> {
+		let options = Self::stored_options(&path)?;
+		Self::open(path, options)
 	}
 
-	fn open_internal<P: AsRef<Path>>(path: P, lock_file: File, options: Options) -> Result<Self> {
-		let options = InternalOptions::from_external(options)?;
-		let journal = Journal::open(&path)?;
+	/// Returns options the database at given location was created with.
+	///
+	/// Only options which determine the layout of the database are stored,
+	/// the other ones have default values. Options are not stored by databases
+	/// created by older versions, until they're opened with `open` for the first time.
+	pub fn stored_options<P: AsRef<Path>>(path: P) -> Result<DatabaseOptions> {
+		let mut options = DatabaseOptions::from(Namespace::stored_options(&path)?);
+		for name in Self::stored_namespaces(&path)? {
+			let namespace_options = Namespace::stored_options(Self::namespace_path(&path, &name))?;
+			options = options.namespace(name, namespace_options);
+		}
+
+		Ok(options)
+	}
 
-		let db_file_path = path.as_ref().join(Self::DB_FILE);
-		let mut mmap = Mmap::open_path(db_file_path, Protection::ReadWrite)?;
+	/// Returns names of namespaces in the order they were created in.
+	fn stored_namespaces<P: AsRef<Path>>(path: P) -> Result<Vec<String>> {
+		match fs::read_to_string(path.as_ref().join(Self::NAMESPACES_FILE)) {
+			Ok(stored) => Ok(stored.lines().map(Into::into).collect()),
+			Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+			Err(err) => Err(err.into()),
+		}
+	}
 
-		let meta_file_path = path.as_ref().join(Self::META_FILE);
-		let mut metadata_mmap = Mmap::open_path(meta_file_path, Protection::ReadWrite)?;
+	/// Fails if named namespaces are not the ones the database was created with.
+	///
+	/// Journal keys refer to namespaces by their indexes, so their order must not change.
+	fn check_namespaces<P: AsRef<Path>>(path: P, names: &[String]) -> Result<()> {
+		let stored = Self::stored_namespaces(path)?;
+		if stored != names {
+			bail!(ErrorKind::InvalidOptions(
+				"namespaces",
+				format!("{:?} differ from namespaces the database was created with: {:?}", names, stored)
+			));
+		}
 
-		let mut metadata = metadata::bytes::read(unsafe { metadata_mmap.as_slice() }, options.external.key_index_bits);
+		Ok(())
+	}
 
-		if let Some(flush) = Flush::open(path.as_ref(), options.external.key_index_bits)? {
-			flush.flush(unsafe { mmap.as_mut_slice() }, unsafe { metadata_mmap.as_mut_slice() }, &mut metadata);
-			mmap.flush()?;
-			metadata_mmap.flush()?;
-			flush.delete()?;
+	fn open_internal<P: AsRef<Path>>(path: P, lock_file: Option<File>, options: DatabaseOptions) -> Result<Self> {
+		let internal = options.to_internal()?;
+		let names = options.names();
+		let read_only = lock_file.is_none();
+		Self::check_namespaces(&path, &names)?;
+
+		let (flush_lock_file, read_lock, _read_guard) = if read_only {
+			let (read_lock, read_guard) = Self::open_read_lock(&path)?;
+			(None, read_lock, read_guard)
+		} else {
+			let flush_lock_path = path.as_ref().join(Self::FLUSH_LOCK_FILE);
+			let flush_lock_file = flush_lock::open_writable(&flush_lock_path)?;
+			// pending flushes are recovered and namespaces migrated before readers see them
+			flush_lock::lock_writable(&flush_lock_file, flush_lock_path)?;
+			(Some(flush_lock_file), None, None)
+		};
+
+		let (namespaces, journal) = Self::load(&path, internal, &names, read_only)?;
+		if let Some(ref flush_lock_file) = flush_lock_file {
+			// read-only handles load the recovered data again
+			flush_lock::increment_generation(flush_lock_file)?;
+			flush_lock_file.unlock()?;
 		}
 
-		Ok(Database {
+		let reader = DatabaseReader::new(names.clone());
+		let preparer = Preparer::new(
+			journal.preparer(),
+			namespaces.iter().map(|namespace| namespace.options.external.merge_operator.clone()).collect(),
+		);
+
+		let db = Database {
 			path: path.as_ref().to_owned(),
-			options,
+			options: namespaces[0].options.clone(),
+			names,
 			journal,
-			metadata,
-			metadata_mmap,
-			mmap,
+			namespaces,
+			reader,
+			preparer,
 			lock_file,
-		})
+			flush_lock_file,
+			read_lock,
+			compaction_pending: false,
+		};
+		db.reader.publish(db.snapshots());
+
+		Ok(db)
+	}
+
+	/// Opens all namespaces and the journal without eras which have already been flushed.
+	///
+	/// `options` are the ones of the default namespace followed by namespaces with given `names`.
+	fn load<P: AsRef<Path>>(path: P, options: Vec<InternalOptions>, names: &[String], read_only: bool) -> Result<(Vec<Namespace>, Journal)> {
+		let durability = options[0].external.durability;
+		let mut namespaces = Vec::with_capacity(options.len());
+		for (index, namespace_options) in options.into_iter().enumerate() {
+			let namespace = match index {
+				0 => Namespace::open(&path, namespace_options, read_only)?,
+				_ => Namespace::open(Self::namespace_path(&path, &names[index - 1]), namespace_options, read_only)?,
+			};
+			namespaces.push(namespace);
+		}
+
+		let mut journal = if read_only {
+			Journal::open_read_only(&path)?
+		} else {
+			Journal::open(&path, durability)?
+		};
+		// eras might have been flushed without being deleted from the journal
+		let next_era = namespaces.iter().map(|namespace| namespace.metadata.next_era).max().unwrap_or(0);
+		if !read_only {
+			Self::flush_skipped(&mut namespaces, &journal, !names.is_empty(), next_era)?;
+		}
+		journal.skip_flushed(next_era)?;
+
+		Ok((namespaces, journal))
+	}
+
+	/// Flushes operations of eras before `next_era` to namespaces which haven't flushed them yet.
+	///
+	/// A crash while flushes of an era are written might leave flushes of only
+	/// some namespaces, which are recovered when they're opened. Eras are skipped
+	/// then, so their operations are flushed to the other namespaces before.
+	/// Each namespace stores the index of the next era it hasn't flushed,
+	/// so no operation is flushed twice.
+	fn flush_skipped(namespaces: &mut [Namespace], journal: &Journal, namespaced: bool, next_era: u64) -> Result<()> {
+		for (index, namespace) in namespaces.iter_mut().enumerate() {
+			if namespace.metadata.next_era >= next_era {
+				continue;
+			}
+
+			let operations = journal.iter_eras(namespace.metadata.next_era, next_era);
+			let operations = namespace::journal_operations(operations, Self::journal_prefix_for(namespaced, index));
+			if operations.len() == 0 {
+				continue;
+			}
+
+			let mut metadata = (*namespace.metadata).clone();
+			metadata.next_era = next_era;
+			let flush = Flush::new(&namespace.path, &namespace.options, namespace.data(), &metadata, operations)?;
+			namespace.apply_flush(flush)?;
+		}
+
+		Ok(())
+	}
+
+	fn ensure_writable(&self) -> Result<()> {
+		if self.lock_file.is_none() {
+			return Err(ErrorKind::ReadOnly.into());
+		}
+
+		Ok(())
+	}
+
+	fn flush_lock_file(&self) -> &File {
+		self.flush_lock_file.as_ref().expect("flushed data is modified only by the writer; qed")
+	}
+
+	/// Locks flushed data of a read-only handle until the guard is dropped.
+	///
+	/// Fails with `FlushedDataChanged` if the writer has modified it since it was loaded.
+	fn lock_flushed(&self) -> Result<Option<ReadGuard>> {
+		flush_lock::lock_read(&self.read_lock)
+	}
+
+	/// Returns the index of the namespace with given `name`.
+	fn namespace_index(&self, name: &str) -> Result<usize> {
+		self.names.iter()
+			.position(|namespace| namespace == name)
+			.map(|index| index + 1)
+			.ok_or_else(|| ErrorKind::UnknownNamespace(name.to_owned()).into())
+	}
+
+	fn journal_prefix_for(namespaced: bool, namespace: usize) -> Option<u8> {
+		if namespaced {
+			Some(namespace as u8)
+		} else {
+			None
+		}
+	}
+
+	/// Returns the prefix of journal keys of the namespace.
+	fn journal_prefix(&self, namespace: usize) -> Option<u8> {
+		Self::journal_prefix_for(!self.names.is_empty(), namespace)
 	}
 
 	/// Create a new transaction.
 	pub fn create_transaction(&self) -> Transaction {
-		Transaction::new(self.options.external.key_len)
+		let namespaces = self.names.iter().zip(&self.namespaces[1..])
+			.map(|(name, namespace)| (name.clone(), namespace.options.external.key_len, namespace.options.external.variable_key_len))
+			.collect();
+		Transaction::with_namespaces(self.options.external.key_len, self.options.external.variable_key_len, namespaces)
+	}
+
+	/// Fails if any of the preconditions doesn't hold.
+	fn check_conditions(&self, conditions: &[Condition]) -> Result<()> {
+		let namespaced = !self.names.is_empty();
+		for condition in conditions {
+			let (namespace, key) = namespace::split_journal_key(namespaced, condition.key());
+			let holds = match (condition, self.get_at(namespace, key)?) {
+				(&Condition::Absent(_), value) => value.is_none(),
+				(&Condition::Equals(_, ref expected), Some(value)) => value == expected,
+				(&Condition::Equals(..), None) => false,
+			};
+
+			if !holds {
+				return Err(ErrorKind::ConditionFailed(key.to_vec()).into());
+			}
+		}
+
+		Ok(())
 	}
 
 	/// Commits changes in the transaction.
+	///
+	/// Fails with `ErrorKind::ConditionFailed` without committing anything
+	/// if a precondition of any conditional operation doesn't hold.
 	pub fn commit(&mut self, tx: &Transaction) -> Result<()> {
+		self.ensure_writable()?;
+		self.preparer.check_merges(tx)?;
+		self.check_conditions(tx.conditions())?;
 		self.journal.push(tx)?;
+		self.reader.publish(self.snapshots());
+		Ok(())
+	}
+
+	/// Writes the transaction to the journal directory without committing it.
+	///
+	/// It does not require write access to the database, so it can be
+	/// done while the database is being read.
+	pub fn prepare(&self, tx: &Transaction) -> Result<PreparedTransaction> {
+		self.ensure_writable()?;
+		self.preparer.prepare(tx)
+	}
+
+	/// Returns a handle preparing transactions of the database, which can be sent to other threads.
+	pub fn preparer(&self) -> Result<Preparer> {
+		self.ensure_writable()?;
+		Ok(self.preparer.clone())
+	}
+
+	/// Commits previously prepared transaction.
+	///
+	/// Preconditions of conditional operations are checked against
+	/// the state of the database at the time it's applied.
+	/// Fails if the transaction was prepared for another database handle.
+	pub fn apply(&mut self, prepared: PreparedTransaction) -> Result<()> {
+		self.ensure_writable()?;
+		self.check_conditions(prepared.conditions())?;
+		self.journal.apply(prepared)?;
+		self.reader.publish(self.snapshots());
 		Ok(())
 	}
 
+	/// Reverts `eras` most recently committed transactions.
+	///
+	/// Only transactions which haven't been flushed yet can be reverted.
+	pub fn rollback(&mut self, eras: usize) -> Result<()> {
+		self.ensure_writable()?;
+		let result = self.journal.pop_back(eras);
+		// some eras might have been deleted even if it failed
+		self.reader.publish(self.snapshots());
+		result
+	}
+
 	/// Flushes up to `max` excessive journal eras to the disk.
+	///
+	/// Snapshots keep reading the data they were created with, so the data file
+	/// pinned by them is copied before it's modified.
+	/// Flushing is deferred while flushed data is read by another process.
+	/// Eras which are not flushed then stay in the journal and they are flushed
+	/// by a later call, together with a deferred compaction.
 	pub fn flush_journal<T: Into<Option<usize>>>(&mut self, max: T) -> Result<()> {
+		self.ensure_writable()?;
 		let len = self.journal.len();
 		let max = max.into().unwrap_or(len);
 
-		if len < self.options.external.journal_eras {
-			return Ok(())
+		if len > self.options.external.journal_eras {
+			let to_flush = cmp::min(len - self.options.external.journal_eras, max);
+			if !self.flush_eras(to_flush)? {
+				return Ok(());
+			}
 		}
 
-		let to_flush = cmp::min(len - self.options.external.journal_eras, max);
-
-		for era in self.journal.drain_front(to_flush) {
-			let flush = Flush::new(
-				&self.path,
-				&self.options,
-				unsafe { self.mmap.as_slice() },
-				&self.metadata,
-				era.iter(),
-			)?;
-			era.delete()?;
-			// TODO: metadata should be a single structure
-			// updating self.metadata should happen after all calls
-			// which may fail ("?")
-			flush.flush(unsafe { self.mmap.as_mut_slice() }, unsafe { self.metadata_mmap.as_mut_slice() }, &mut self.metadata);
-			self.mmap.flush()?;
-			self.metadata_mmap.flush()?;
-			flush.delete()?;
+		if self.compaction_pending {
+			self.compact_namespaces()?;
 		}
-
 		Ok(())
 	}
 
-	/// Lookup a value associated with given `key`.
-	pub fn get<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<Value>> {
-		let key = key.as_ref();
-		if key.len() != self.options.external.key_len {
-			return Err(ErrorKind::InvalidKeyLen(self.options.external.key_len, key.len()).into());
-		}
+	/// Squashes journal eras older than `keep` most recent ones into a single file,
+	/// so that lookups and opening the database need to read less era files.
+	///
+	/// Squashed eras can still be rolled back and flushed one by one.
+	pub fn squash_journal(&mut self, keep: usize) -> Result<()> {
+		self.ensure_writable()?;
+		let result = self.journal.squash(keep).map(|_| ());
+		self.reader.publish(self.snapshots());
+		result
+	}
+
+	/// Moves records of the database and all its namespaces as close
+	/// to the offsets determined by their keys as possible, so that
+	/// lookups read less fields.
+	///
+	/// Records are moved by flushes, so compaction interrupted by a crash
+	/// is finished when the database is opened again.
+	/// Like `flush_journal`, it's deferred while flushed data is read by other processes.
+	pub fn compact(&mut self) -> Result<()> {
+		self.ensure_writable()?;
+		self.compaction_pending = true;
+		self.compact_namespaces()
+	}
 
-		if let Some(res) = self.journal.get(key) {
-			return Ok(Some(Value::Raw(res)));
+	/// Compacts all namespaces, unless flushed data is read by other processes.
+	///
+	/// Compaction stays pending until all namespaces are compacted.
+	fn compact_namespaces(&mut self) -> Result<()> {
+		for index in 0..self.namespaces.len() {
+			let flush = self.namespaces[index].prepare_compact()?;
+			if !self.apply_flushes(vec![(index, flush)], |_| Ok(()))? {
+				return Ok(());
+			}
 		}
 
-		let field_body_size = self.options.field_body_size;
-		let value_size = self.options.value_size;
+		self.compaction_pending = false;
+		Ok(())
+	}
 
-		let key = Key::new(key, self.options.external.key_index_bits);
-		if !self.metadata.prefixes.has(key.prefix).unwrap_or(false) {
-			return Ok(None);
-		}
+	/// Loads records sorted by their keys directly to the empty database.
+	///
+	/// Records are written in a single sequential pass, bypassing the journal,
+	/// so it's much faster than committing and flushing transactions.
+	/// The database grows afterwards if needed, which is avoided
+	/// if it's created with `key_index_bits` big enough for all the records.
+	///
+	/// Fails with `ErrorKind::NotEmpty` if the database has any records, including
+	/// the ones in the journal, or if its data file is not clean.
+	/// Fails with `ErrorKind::FlushedDataInUse` if flushed data is read by other processes.
+	/// Fails if keys are not strictly ascending. Records loaded so far are cleared then.
+	/// If it's interrupted by a crash, the database needs to be created again.
+	pub fn bulk_load<I, K, V>(&mut self, records: I) -> Result<()>
+		where I: IntoIterator<Item = (K, V)>, K: AsRef<[u8]>, V: AsRef<[u8]> {
+		self.bulk_load_at(0, records)
+	}
 
-		let offset = key.prefix as usize * self.options.record_offset;
-		let data = unsafe { &self.mmap.as_slice()[offset..] };
+	/// Loads records sorted by their keys directly to the empty namespace with given `name`.
+	pub fn bulk_load_in<I, K, V>(&mut self, name: &str, records: I) -> Result<()>
+		where I: IntoIterator<Item = (K, V)>, K: AsRef<[u8]>, V: AsRef<[u8]> {
+		let index = self.namespace_index(name)?;
+		self.bulk_load_at(index, records)
+	}
 
-		match find::find_record(data, field_body_size, value_size, key.key)? {
-			find::RecordResult::Found(record) => Ok(Some(Value::from(record))),
-			find::RecordResult::NotFound => Ok(None),
-			find::RecordResult::OutOfRange => unimplemented!(),
+	fn bulk_load_at<I, K, V>(&mut self, index: usize, records: I) -> Result<()>
+		where I: IntoIterator<Item = (K, V)>, K: AsRef<[u8]>, V: AsRef<[u8]> {
+		self.ensure_writable()?;
+		// loaded records would be overwritten by operations flushed later
+		if namespace::journal_operations(self.journal.iter(), self.journal_prefix(index)).len() != 0 {
+			bail!(ErrorKind::NotEmpty);
 		}
-	}
 
-	/// Returns an iterator over the database key-value pairs.
-	pub fn iter(&self) -> Result<DatabaseIterator> {
-		let data = unsafe { &self.mmap.as_slice() };
-		let occupied_offset_iter = self.metadata.prefixes.prefixes_iter();
-		let field_body_size = self.options.field_body_size;
-		let key_size = self.options.external.key_len;
-		let value_size = self.options.value_size;
+		let loaded = self.modify_flushed(|db| {
+			let namespace = &mut db.namespaces[index];
+			namespace.bulk_load(records)?;
 
-		let record_iter = find::iter(data, occupied_offset_iter, field_body_size, key_size, value_size)?;
-		let journal_iter = self.journal.iter();
-		let pending = IteratorValue::None;
+			while namespace.should_grow() {
+				let prefix_bits = namespace.metadata.prefixes.prefix_bits();
+				namespace.grow()?;
+				if namespace.metadata.prefixes.prefix_bits() == prefix_bits {
+					break;
+				}
+			}
+			Ok(())
+		})?;
 
-		Ok(DatabaseIterator { record_iter, journal_iter, pending })
+		if !loaded {
+			bail!(ErrorKind::FlushedDataInUse);
+		}
+		Ok(())
 	}
-}
 
-impl Drop for Database {
-	fn drop(&mut self) {
-		let _ = self.lock_file.unlock();
+	/// Modifies flushed data, unless it's read by other processes.
+	///
+	/// Readers are blocked until `modify` returns, so it should only apply changes prepared beforehand.
+	/// Returns false without calling `modify` if the data is read by other processes.
+	fn modify_flushed<F>(&mut self, modify: F) -> Result<bool> where F: FnOnce(&mut Self) -> Result<()> {
+		// readers are blocked until the new state is published
+		let reader = self.reader.clone();
+		let mut current = reader.lock();
+
+		// flushed data is modified in place, so it can't change while other processes read it
+		let result = if self.flush_lock_file().try_lock_exclusive().is_err() {
+			Ok(false)
+		} else {
+			let result = modify(self);
+			// read-only handles load the modified data again, even if it's modified only partially
+			let result = result.and(flush_lock::increment_generation(self.flush_lock_file()));
+			result.and(self.flush_lock_file().unlock().map_err(Into::into)).map(|_| true)
+		};
+
+		*current = Some(Arc::new(self.snapshots()));
+		result
 	}
-}
 
-#[derive(Debug)]
-enum IteratorValue<'a> {
-	None,
-	Journal(Operation<'a>),
-	DB(Record<'a>),
-}
+	/// Applies flushes written while readers were served and calls `then` before publishing the new state.
+	///
+	/// Flushes are deleted and false is returned if flushed data is read by other processes.
+	fn apply_flushes<F>(&mut self, flushes: Vec<(usize, Flush)>, then: F) -> Result<bool> where F: FnOnce(&mut Self) -> Result<()> {
+		let mut pending = Some(flushes);
+		let result = self.modify_flushed(|db| {
+			for (index, flush) in pending.take().expect("flushes are applied only once; qed") {
+				db.namespaces[index].apply_flush(flush)?;
+			}
+			then(db)
+		});
 
-impl<'a> IteratorValue<'a> {
-	fn take(&mut self) -> Self {
-		::std::mem::replace(self, IteratorValue::None)
+		if let Some(flushes) = pending {
+			for (_, flush) in flushes {
+				let _ = flush.delete();
+			}
+		}
+		result
 	}
-}
-
-pub struct DatabaseIterator<'a> {
-	journal_iter: btree_set::IntoIter<Operation<'a>>,
-	record_iter: find::RecordIterator<'a>,
-	pending: IteratorValue<'a>,
-}
-
-impl<'a> Iterator for DatabaseIterator<'a> {
-	type Item = Result<(&'a [u8], Value<'a>)>;
 
-	fn next(&mut self) -> Option<Self::Item> {
-		loop {
-			let (operation, record) = match self.pending.take() {
-				IteratorValue::None => {
-					let j = self.journal_iter.next().map_or(IteratorValue::None, IteratorValue::Journal);
-					let db = match self.record_iter.next() {
-						None => IteratorValue::None,
-						Some(Ok(r)) => IteratorValue::DB(r),
-						Some(Err(err)) => {
-							self.pending = j;
-							return Some(Err(err.into()));
-						},
-					};
-
-					(j, db)
-				},
-				j @ IteratorValue::Journal(_) => {
-					let db = match self.record_iter.next() {
-						None => IteratorValue::None,
-						Some(Ok(r)) => IteratorValue::DB(r),
-						Some(Err(err)) => {
-							self.pending = j;
-							return Some(Err(err.into()));
-						},
-					};
+	/// Flushes up to `to_flush` oldest journal eras to the disk.
+	///
+	/// Flushes of all namespaces modified by the era are written
+	/// before the era is removed from the journal. They store the index
+	/// of the next era, so that the era is skipped if its file is left by a crash.
+	///
+	/// Returns false if flushing was deferred, cause flushed data is read by other processes.
+	fn flush_eras(&mut self, to_flush: usize) -> Result<bool> {
+		let mut flushed = 0;
+		while let Some(era) = self.journal.front().cloned() {
+			if flushed == to_flush {
+				break;
+			}
 
-					(j, db)
-				},
-				db @ IteratorValue::DB(_) => {
-					let j = self.journal_iter.next().map_or(IteratorValue::None, IteratorValue::Journal);
+			// eras squashed into a single file are flushed partially if needed
+			let eras = cmp::min(era.len(), to_flush - flushed);
+			let flushes = self.prepare_flushes(&era, eras)?;
+			let applied = self.apply_flushes(flushes, |db| {
+				// flushed operations are not seen in the journal anymore by the published state
+				db.journal.skip_flushed(era.first() + eras as u64)
+			})?;
+			if !applied {
+				return Ok(false);
+			}
+			flushed += eras;
 
-					(j, db)
-				},
-			};
+			for index in 0..self.namespaces.len() {
+				if !self.namespaces[index].should_grow() {
+					continue;
+				}
 
-			#[inline]
-			// returns `None` if the operation is a `Delete` and we should skip to the next value
-			fn handle_journal_operation<'a>(o: Operation<'a>) -> Option<Result<(&'a [u8], Value<'a>)>> {
-				match o {
-					Operation::Delete(_) => {
-						None
-					},
-					Operation::Insert(key, value) => {
-						Some(Ok((key, Value::Raw(value))))
-					},
+				// namespace grows after a later flush if it's deferred
+				if let Some(flush) = self.namespaces[index].prepare_grow()? {
+					if !self.apply_flushes(vec![(index, flush)], |_| Ok(()))? {
+						return Ok(false);
+					}
 				}
 			}
+		}
 
-			match (operation, record) {
-				(IteratorValue::Journal(o), IteratorValue::None) => {
-					match handle_journal_operation(o) {
-						None => continue,
-						s => return s,
-					};
-				},
-				(IteratorValue::None, IteratorValue::DB(r)) => {
-					return Some(Ok((r.key(), Value::from(r))));
-				},
-				(IteratorValue::Journal(o), IteratorValue::DB(r)) => {
-					let ord = r.key().partial_cmp(o.key()).expect(
-						"only returns None when compared keys don't have the same size; \
-						 all keys should have the same size; qed");
+		Ok(true)
+	}
 
-					match ord {
-						Ordering::Equal => {
-							match handle_journal_operation(o) {
-								None => continue,
-								s => return s,
-							};
-						},
-						Ordering::Greater => {
-							self.pending = IteratorValue::DB(r);
+	/// Writes flushes of operations of `eras` oldest eras of the file to all namespaces modified by them.
+	///
+	/// Flushes already written are deleted if any of them fails.
+	fn prepare_flushes(&self, era: &JournalEra, eras: usize) -> Result<Vec<(usize, Flush)>> {
+		let mut flushes = Vec::new();
+		for (index, namespace) in self.namespaces.iter().enumerate() {
+			let operations = namespace::journal_operations(era.iter_front(eras), self.journal_prefix(index));
+			if operations.len() == 0 {
+				continue;
+			}
 
-							match handle_journal_operation(o) {
-								None => continue,
-								s => return s,
-							};
-						},
-						Ordering::Less => {
-							self.pending = IteratorValue::Journal(o);
-							return Some(Ok((r.key(), Value::from(r))));
-						},
-					};
+			let mut metadata = (*namespace.metadata).clone();
+			metadata.next_era = era.first() + eras as u64;
+			let flush = Flush::new(
+				&namespace.path,
+				&namespace.options,
+				namespace.data(),
+				&metadata,
+				operations,
+			);
+
+			match flush {
+				Ok(flush) => flushes.push((index, flush)),
+				Err(err) => {
+					for (_, flush) in flushes {
+						let _ = flush.delete();
+					}
+					return Err(err);
 				},
-				(IteratorValue::None, IteratorValue::None) => return None,
-				(o, r) => unreachable!("operation: {:?}, record: {:?}", o, r)
-			};
+			}
 		}
+
+		Ok(flushes)
 	}
-}
 
-#[cfg(test)]
-mod tests {
-	extern crate tempdir;
+	/// Loads journal eras committed and data flushed since the database was opened read-only.
+	///
+	/// Does nothing if the database is writable, cause it always sees its own eras.
+	pub fn reload_journal(&mut self) -> Result<()> {
+		if self.lock_file.is_some() {
+			return Ok(());
+		}
 
-	use super::{Database, Options};
-	use options::ValuesLen;
-	use error::ErrorKind;
-	use quickcheck::TestResult;
+		let (read_lock, _read_guard) = Self::open_read_lock(&self.path)?;
+		let options = self.namespaces.iter().map(|namespace| namespace.options.clone()).collect();
+		let (namespaces, journal) = Self::load(&self.path, options, &self.names, true)?;
+		self.namespaces = namespaces;
+		self.journal = journal;
+		self.read_lock = read_lock;
+		self.reader.publish(self.snapshots());
+		Ok(())
+	}
 
-	#[test]
-	fn create_insert_and_query() {
-		let temp = tempdir::TempDir::new("create_insert_and_query").unwrap();
+	/// Lookup a value associated with given `key`.
+	pub fn get<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<Value>> {
+		self.get_at(0, key.as_ref())
+	}
 
-		let mut db = Database::create(temp.path(), Options {
-			journal_eras: 0,
-			key_len: 3,
-			value_len: ValuesLen::Constant(3),
-			..Default::default()
-		}).unwrap();
+	/// Lookup a value associated with given `key` in the namespace with given `name`.
+	pub fn get_in<K: AsRef<[u8]>>(&self, name: &str, key: K) -> Result<Option<Value>> {
+		let index = self.namespace_index(name)?;
+		self.get_at(index, key.as_ref())
+	}
 
-		let mut tx = db.create_transaction();
-		tx.insert("abc", "xyz").unwrap();
-		tx.insert("cde", "123").unwrap();
+	fn get_at(&self, index: usize, key: &[u8]) -> Result<Option<Value>> {
+		let namespace = &self.namespaces[index];
+		validate_key_len(&namespace.options, key)?;
+
+		let read_guard = self.lock_flushed()?;
+		let get_flushed = || get_flushed(&namespace.options, &namespace.metadata, namespace.data(), key);
+		let value = match self.journal.get(&namespace::journal_key(self.journal_prefix(index), key)) {
+			Some(entry) => {
+				let external = &namespace.options.external;
+				resolve_journal_entry(&external.merge_operator, &external.value_len, key, entry, get_flushed)?
+			},
+			None => get_flushed()?,
+		};
+
+		Ok(detach_value(&read_guard, value))
+	}
 
-		db.commit(&tx).unwrap();
+	/// Lookup values associated with given `keys`.
+	///
+	/// Keys are looked up in ascending order in a single pass over the database.
+	/// Values are returned in the order of `keys`.
+	pub fn get_many<K: AsRef<[u8]>>(&self, keys: &[K]) -> Result<Vec<Option<Value>>> {
+		self.get_many_at(0, keys)
+	}
 
-		assert_eq!(db.get("abc").unwrap().unwrap(), b"xyz");
-		assert_eq!(db.get("cde").unwrap().unwrap(), b"123");
+	/// Lookup values associated with given `keys` in the namespace with given `name`.
+	pub fn get_many_in<K: AsRef<[u8]>>(&self, name: &str, keys: &[K]) -> Result<Vec<Option<Value>>> {
+		let index = self.namespace_index(name)?;
+		self.get_many_at(index, keys)
+	}
 
-		// Another transaction
-		let mut tx = db.create_transaction();
-		tx.insert("abc", "456").unwrap();
+	fn get_many_at<K: AsRef<[u8]>>(&self, index: usize, keys: &[K]) -> Result<Vec<Option<Value>>> {
+		let namespace = &self.namespaces[index];
+		let prefix = self.journal_prefix(index);
+		let read_guard = self.lock_flushed()?;
+		let values = get_many_with_journal(
+			&namespace.options,
+			&namespace.metadata,
+			namespace.data(),
+			|key| self.journal.get(&namespace::journal_key(prefix, key)),
+			keys,
+		)?;
+
+		Ok(values.into_iter().map(|value| detach_value(&read_guard, value)).collect())
+	}
+
+	/// Returns an iterator over the database key-value pairs.
+	pub fn iter(&self) -> Result<DatabaseIterator> {
+		self.iter_range(0, None, None, false)
+	}
+
+	/// Returns an iterator over the key-value pairs of the namespace with given `name`.
+	pub fn iter_in(&self, name: &str) -> Result<DatabaseIterator> {
+		let index = self.namespace_index(name)?;
+		self.iter_range(index, None, None, false)
+	}
+
+	/// Returns an iterator over the database key-value pairs starting at `key`.
+	pub fn iter_from<K: AsRef<[u8]>>(&self, key: K) -> Result<DatabaseIterator> {
+		let key = key.as_ref();
+		validate_key_len(&self.options, key)?;
+		self.iter_range(0, Some(key), None, false)
+	}
+
+	/// Returns an iterator over the database key-value pairs with keys in `range`.
+	pub fn range<K: AsRef<[u8]>>(&self, range: Range<K>) -> Result<DatabaseIterator> {
+		self.range_at(0, range, false)
+	}
+
+	/// Returns an iterator over the key-value pairs of the namespace with given `name`
+	/// with keys in `range`.
+	pub fn range_in<K: AsRef<[u8]>>(&self, name: &str, range: Range<K>) -> Result<DatabaseIterator> {
+		let index = self.namespace_index(name)?;
+		self.range_at(index, range, false)
+	}
+
+	/// Returns an iterator over the database key-value pairs in descending order.
+	pub fn iter_rev(&self) -> Result<DatabaseIterator> {
+		self.iter_range(0, None, None, true)
+	}
+
+	/// Returns an iterator over the key-value pairs of the namespace with given `name`
+	/// in descending order.
+	pub fn iter_rev_in(&self, name: &str) -> Result<DatabaseIterator> {
+		let index = self.namespace_index(name)?;
+		self.iter_range(index, None, None, true)
+	}
+
+	/// Returns an iterator over the database key-value pairs with keys lower than `key`
+	/// in descending order.
+	pub fn iter_rev_before<K: AsRef<[u8]>>(&self, key: K) -> Result<DatabaseIterator> {
+		let key = key.as_ref();
+		validate_key_len(&self.options, key)?;
+		self.iter_range(0, None, Some(key), true)
+	}
+
+	/// Returns an iterator over the database key-value pairs with keys in `range`
+	/// in descending order.
+	pub fn range_rev<K: AsRef<[u8]>>(&self, range: Range<K>) -> Result<DatabaseIterator> {
+		self.range_at(0, range, true)
+	}
+
+	fn range_at<K: AsRef<[u8]>>(&self, index: usize, range: Range<K>, descending: bool) -> Result<DatabaseIterator> {
+		let start = range.start.as_ref();
+		let end = range.end.as_ref();
+		let options = &self.namespaces[index].options;
+		validate_key_len(options, start)?;
+		validate_key_len(options, end)?;
+		self.iter_range(index, Some(start), Some(end), descending)
+	}
+
+	fn iter_range(&self, index: usize, start: Option<&[u8]>, end: Option<&[u8]>, descending: bool) -> Result<DatabaseIterator> {
+		let namespace = &self.namespaces[index];
+		let read_guard = self.lock_flushed()?;
+		let iter = iter_with_journal(
+			&namespace.options,
+			&namespace.metadata,
+			namespace.data(),
+			namespace::journal_operations(self.journal.iter(), self.journal_prefix(index)),
+			start,
+			end,
+			descending,
+		)?;
+
+		Ok(iter.with_read_guard(read_guard))
+	}
+
+	/// Returns a handle which reads the database from other threads.
+	pub fn reader(&self) -> DatabaseReader {
+		self.reader.clone()
+	}
+
+	/// Returns a read-only view onto the current state of the database.
+	///
+	/// The snapshot doesn't borrow the database and it's not affected by
+	/// later commits, rollbacks and flushes. Data file is copied by flushes
+	/// as long as any snapshot exists, so snapshots shouldn't be kept for long.
+	pub fn snapshot(&self) -> Snapshot {
+		self.snapshot_at(0)
+	}
+
+	/// Returns a read-only view onto the current state of the namespace with given `name`.
+	pub fn snapshot_in(&self, name: &str) -> Result<Snapshot> {
+		let index = self.namespace_index(name)?;
+		Ok(self.snapshot_at(index))
+	}
+
+	/// Returns snapshots of the default namespace followed by named namespaces.
+	fn snapshots(&self) -> Vec<Snapshot> {
+		(0..self.namespaces.len()).map(|index| self.snapshot_at(index)).collect()
+	}
+
+	fn snapshot_at(&self, index: usize) -> Snapshot {
+		let namespace = &self.namespaces[index];
+		Snapshot::new(
+			namespace.options.clone(),
+			self.journal.snapshot(),
+			self.journal_prefix(index),
+			namespace.metadata.clone(),
+			namespace.mmap.clone(),
+			self.read_lock.clone(),
+		)
+	}
+
+	/// Returns statistics of the database.
+	///
+	/// All fields of the database are read, so it may take a while.
+	pub fn stats(&self) -> Result<Stats> {
+		self.stats_at(0)
+	}
+
+	/// Returns statistics of the namespace with given `name`.
+	///
+	/// Journal statistics are the ones of the whole database.
+	pub fn stats_in(&self, name: &str) -> Result<Stats> {
+		let index = self.namespace_index(name)?;
+		self.stats_at(index)
+	}
+
+	fn stats_at(&self, index: usize) -> Result<Stats> {
+		let namespace = &self.namespaces[index];
+		let _read_guard = self.lock_flushed()?;
+		let mut stats = stats::stats(namespace.data(), &namespace.metadata, &namespace.options)?;
+		stats.journal_eras = self.journal.len();
+		stats.journal_bytes = self.journal.file_size();
+		Ok(stats)
+	}
+
+	/// Checks consistency of the flushed data and metadata of the database.
+	///
+	/// All fields of the database are read, so it may take a while.
+	pub fn verify(&self) -> ConsistencyReport {
+		self.verify_at(0)
+	}
+
+	/// Checks consistency of the flushed data and metadata of the namespace with given `name`.
+	pub fn verify_in(&self, name: &str) -> Result<ConsistencyReport> {
+		let index = self.namespace_index(name)?;
+		Ok(self.verify_at(index))
+	}
+
+	fn verify_at(&self, index: usize) -> ConsistencyReport {
+		let namespace = &self.namespaces[index];
+		// flushed data changed since it was loaded by a read-only handle is reported as inconsistent
+		let _read_guard = self.lock_flushed().ok();
+		verify::verify(namespace.data(), &namespace.metadata, &namespace.options)
+	}
+}
+
+pub(crate) fn validate_key_len(options: &InternalOptions, key: &[u8]) -> Result<()> {
+	if !transaction::key_len_is_valid(options.external.key_len, options.external.variable_key_len, key.len()) {
+		return Err(ErrorKind::InvalidKeyLen(options.external.key_len, key.len()).into());
+	}
+
+	Ok(())
+}
+
+/// Lookup a value associated with given `key` in the flushed `data`.
+pub(crate) fn get_flushed<'a>(
+	options: &InternalOptions,
+	metadata: &Metadata,
+	data: &'a [u8],
+	key: &[u8],
+) -> Result<Option<Value<'a>>> {
+	let field_body_size = options.field_body_size;
+	let value_size = options.value_size;
+
+	let key = Key::new(key, metadata.prefixes.prefix_bits());
+	if !metadata.prefixes.has(key.prefix).unwrap_or(false) {
+		return Ok(None);
+	}
+
+	let offset = key.prefix as usize * options.record_offset;
+	let data = &data[offset..];
+
+	match find::find_record(data, field_body_size, options.key_size, value_size, options.external.record_checksum, key.key)? {
+		find::RecordResult::Found(record) => Ok(Some(Value::from(check_record(record)?))),
+		find::RecordResult::NotFound => Ok(None),
+		// the slice spans till the end of the overflow region
+		find::RecordResult::OutOfRange => Ok(None),
+		find::RecordResult::Corrupted => Err(ErrorKind::CorruptedRecord(key.key.to_vec()).into()),
+	}
+}
+
+/// Returns the value of the key resolved from its journal `entry`.
+///
+/// `get_flushed` is called only if merge operands are applied to the flushed value.
+pub(crate) fn resolve_journal_entry<'a, F>(
+	merge_operator: &Option<SharedMergeOperator>,
+	value_len: &ValuesLen,
+	key: &[u8],
+	entry: JournalEntry<'a>,
+	get_flushed: F,
+) -> Result<Option<Value<'a>>> where F: FnOnce() -> Result<Option<Value<'a>>> {
+	let flushed;
+	let existing = match entry.base {
+		Some(value) if entry.operands.is_empty() => return Ok(value.map(Value::Raw)),
+		Some(value) => value,
+		None => {
+			flushed = get_flushed()?.map(|value| value.to_vec());
+			flushed.as_ref().map(|value| &value[..])
+		},
+	};
+
+	let merged = merge::merge(merge_operator, value_len, key, existing, &entry.operands)?;
+	Ok(Some(Value::Merged(merged)))
+}
+
+/// Copies the value read by a read-only handle, cause flushed data
+/// can be modified by the writer as soon as it's unlocked.
+pub(crate) fn detach_value<'a>(read_guard: &Option<ReadGuard>, value: Option<Value<'a>>) -> Option<Value<'a>> {
+	match (read_guard, value) {
+		(&Some(_), Some(value)) => Some(Value::Merged(value.to_vec())),
+		(_, value) => value,
+	}
+}
+
+/// Fails if the checksum of the flushed record doesn't match its key and value.
+fn check_record(record: Record) -> Result<Record> {
+	if !record.has_valid_checksum() {
+		return Err(ErrorKind::CorruptedRecord(record.key().to_vec()).into());
+	}
+
+	Ok(record)
+}
+
+/// Lookup values associated with given `keys` in the journal and then in the flushed `data`.
+pub(crate) fn get_many_with_journal<'a, K, F>(
+	options: &InternalOptions,
+	metadata: &Metadata,
+	data: &'a [u8],
+	journal_get: F,
+	keys: &[K],
+) -> Result<Vec<Option<Value<'a>>>> where
+	K: AsRef<[u8]>,
+	F: Fn(&[u8]) -> Option<JournalEntry<'a>>,
+{
+	for key in keys {
+		validate_key_len(options, key.as_ref())?;
+	}
+
+	let field_body_size = options.field_body_size;
+	let key_size = options.key_size;
+	let value_size = options.value_size;
+	let has_checksum = options.external.record_checksum;
+
+	let mut order: Vec<_> = (0..keys.len()).collect();
+	order.sort_by(|a, b| keys[*a].as_ref().cmp(keys[*b].as_ref()));
+
+	let mut results: Vec<_> = keys.iter().map(|_| None).collect();
+	let mut spaces = SpaceIterator::new(data, field_body_size, 0);
+
+	for index in order {
+		let key = keys[index].as_ref();
+		if let Some(entry) = journal_get(key) {
+			let get_flushed = || get_flushed(options, metadata, data, key);
+			let external = &options.external;
+			results[index] = resolve_journal_entry(&external.merge_operator, &external.value_len, key, entry, get_flushed)?;
+			continue;
+		}
+
+		let key = Key::new(key, metadata.prefixes.prefix_bits());
+		if !metadata.prefixes.has(key.prefix).unwrap_or(false) {
+			continue;
+		}
+
+		// records are never stored before the offset of their prefix
+		spaces.move_offset_forward(key.prefix as usize * options.record_offset);
+
+		loop {
+			// the space is not consumed until a greater key is looked up
+			let space = match spaces.peek() {
+				Some(space) => space?,
+				None => break,
+			};
+
+			let space = match space {
+				Space::Occupied(space) => space,
+				Space::Empty(_) => break,
+			};
+
+			if !Record::has_valid_layout(space.data, field_body_size, value_size, key_size, has_checksum) {
+				return Err(ErrorKind::CorruptedRecord(key.key.to_vec()).into());
+			}
+
+			match Record::extract_key(space.data, key_size)?.cmp(key.key) {
+				Ordering::Less => {
+					let _ = spaces.next();
+				},
+				Ordering::Equal => {
+					let record = Record::new(space.data, field_body_size, value_size, key_size, has_checksum);
+					results[index] = Some(Value::from(check_record(record)?));
+					break;
+				},
+				Ordering::Greater => break,
+			}
+		}
+	}
+
+	Ok(results)
+}
+
+/// Returns an iterator over the flushed `data` merged with journal operations.
+///
+/// Iterated keys are not lower than `start` and lower than `end`.
+/// If `descending` is true, keys are iterated from the greatest one.
+pub(crate) fn iter_with_journal<'a>(
+	options: &InternalOptions,
+	metadata: &'a Metadata,
+	data: &'a [u8],
+	journal_iter: vec::IntoIter<Operation<'a>>,
+	start: Option<&[u8]>,
+	end: Option<&[u8]>,
+	descending: bool,
+) -> Result<DatabaseIterator<'a>> {
+	let prefix_bits = metadata.prefixes.prefix_bits();
+	let field_body_size = options.field_body_size;
+	let key_size = options.key_size;
+	let value_size = options.value_size;
+	let has_checksum = options.external.record_checksum;
+
+	let record_iter = if descending {
+		let end_prefix = end.map(|end| Key::new(end, prefix_bits).prefix);
+		let iter = find::iter_rev(data, &metadata.prefixes, end_prefix, field_body_size, key_size, value_size, has_checksum)?;
+		Records::Descending(iter)
+	} else {
+		// records are never stored before the offset of their prefix
+		let occupied_offset_iter = match start {
+			Some(start) => metadata.prefixes.prefixes_iter_from(Key::new(start, prefix_bits).prefix),
+			None => metadata.prefixes.prefixes_iter(),
+		};
+		let iter = find::iter(data, occupied_offset_iter, field_body_size, key_size, value_size, has_checksum)?;
+		Records::Ascending(iter)
+	};
+	let pending = IteratorValue::None;
+
+	let start = start.map(|start| start.to_vec());
+	let end = end.map(|end| end.to_vec());
+
+	let merge_operator = options.external.merge_operator.clone();
+	let value_len = options.external.value_len.clone();
+
+	Ok(DatabaseIterator { journal_iter, record_iter, pending, start, end, descending, merge_operator, value_len, read_guard: None })
+}
+
+impl Drop for Database {
+	fn drop(&mut self) {
+		if let Some(ref flush_lock_file) = self.flush_lock_file {
+			let _ = flush_lock_file.unlock();
+		}
+		if let Some(ref lock_file) = self.lock_file {
+			let _ = lock_file.unlock();
+		}
+	}
+}
+
+#[derive(Debug)]
+enum IteratorValue<'a> {
+	None,
+	Journal(&'a [u8], JournalEntry<'a>),
+	DB(Record<'a>),
+}
+
+impl<'a> IteratorValue<'a> {
+	fn take(&mut self) -> Self {
+		::std::mem::replace(self, IteratorValue::None)
+	}
+}
+
+/// Flushed records in the order of iteration.
+enum Records<'a> {
+	Ascending(find::RecordIterator<'a>),
+	Descending(find::RecordRevIterator<'a>),
+}
+
+impl<'a> Iterator for Records<'a> {
+	type Item = ::std::result::Result<Record<'a>, field::Error>;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		match *self {
+			Records::Ascending(ref mut iter) => iter.next(),
+			Records::Descending(ref mut iter) => iter.next(),
+		}
+	}
+}
+
+pub struct DatabaseIterator<'a> {
+	/// Merge operands follow the operation they're applied to.
+	journal_iter: vec::IntoIter<Operation<'a>>,
+	record_iter: Records<'a>,
+	pending: IteratorValue<'a>,
+	/// Keys lower than `start` are not iterated.
+	start: Option<Vec<u8>>,
+	/// Keys not lower than `end` are not iterated.
+	end: Option<Vec<u8>>,
+	descending: bool,
+	merge_operator: Option<SharedMergeOperator>,
+	value_len: ValuesLen,
+	/// Keeps flushed data of a read-only handle locked while it's iterated.
+	read_guard: Option<ReadGuard>,
+}
+
+impl<'a> DatabaseIterator<'a> {
+	pub(crate) fn with_read_guard(mut self, read_guard: Option<ReadGuard>) -> Self {
+		self.read_guard = read_guard;
+		self
+	}
+
+	fn is_before_start(&self, key: &[u8]) -> bool {
+		self.start.as_ref().map_or(false, |start| key < &start[..])
+	}
+
+	fn is_past_end(&self, key: &[u8]) -> bool {
+		self.end.as_ref().map_or(false, |end| key >= &end[..])
+	}
+
+	/// Returns true if the key is not reached yet in the order of iteration.
+	fn is_not_reached(&self, key: &[u8]) -> bool {
+		if self.descending {
+			self.is_past_end(key)
+		} else {
+			self.is_before_start(key)
+		}
+	}
+
+	/// Returns true if the key is already behind the range in the order of iteration.
+	fn is_passed(&self, key: &[u8]) -> bool {
+		if self.descending {
+			self.is_before_start(key)
+		} else {
+			self.is_past_end(key)
+		}
+	}
+
+	/// Returns the next journal operation in the order of iteration.
+	fn next_journal_operation(&mut self) -> Option<Operation<'a>> {
+		if self.descending {
+			self.journal_iter.next_back()
+		} else {
+			self.journal_iter.next()
+		}
+	}
+
+	/// Returns the next key in the iterated range with its journal entry.
+	fn next_operation(&mut self) -> Option<(&'a [u8], JournalEntry<'a>)> {
+		loop {
+			let operation = self.next_journal_operation()?;
+			let key = operation.key();
+
+			// all operations on the key are stacked together
+			let mut stack = vec![operation];
+			loop {
+				let next = if self.descending {
+					self.journal_iter.as_slice().last()
+				} else {
+					self.journal_iter.as_slice().first()
+				};
+
+				match next {
+					Some(next) if next.key() == key => stack.extend(self.next_journal_operation()),
+					_ => break,
+				}
+			}
+
+			if self.descending {
+				stack.reverse();
+			}
+
+			if self.is_not_reached(key) {
+				continue;
+			}
+
+			if self.is_passed(key) {
+				return None;
+			}
+
+			return Some((key, JournalEntry::from_stack(&stack)));
+		}
+	}
+
+	/// Returns the next flushed record in the iterated range.
+	fn next_record(&mut self) -> Option<Result<Record<'a>>> {
+		loop {
+			let record = match self.record_iter.next()? {
+				Ok(record) => record,
+				Err(err) => return Some(Err(err.into())),
+			};
+
+			// records with lower prefixes may be shifted past the first iterated prefix
+			if self.is_not_reached(record.key()) {
+				continue;
+			}
+
+			if self.is_passed(record.key()) {
+				return None;
+			}
+
+			return Some(check_record(record));
+		}
+	}
+}
+
+impl<'a> Iterator for DatabaseIterator<'a> {
+	type Item = Result<(&'a [u8], Value<'a>)>;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		loop {
+			let (operation, record) = match self.pending.take() {
+				IteratorValue::None => {
+					let j = self.next_operation().map_or(IteratorValue::None, |(key, entry)| IteratorValue::Journal(key, entry));
+					let db = match self.next_record() {
+						None => IteratorValue::None,
+						Some(Ok(r)) => IteratorValue::DB(r),
+						Some(Err(err)) => {
+							self.pending = j;
+							return Some(Err(err));
+						},
+					};
+
+					(j, db)
+				},
+				j @ IteratorValue::Journal(..) => {
+					let db = match self.next_record() {
+						None => IteratorValue::None,
+						Some(Ok(r)) => IteratorValue::DB(r),
+						Some(Err(err)) => {
+							self.pending = j;
+							return Some(Err(err));
+						},
+					};
+
+					(j, db)
+				},
+				db @ IteratorValue::DB(_) => {
+					let j = self.next_operation().map_or(IteratorValue::None, |(key, entry)| IteratorValue::Journal(key, entry));
+
+					(j, db)
+				},
+			};
+
+			#[inline]
+			// returns `None` if the key is deleted and we should skip to the next value
+			fn handle_journal_entry<'a>(
+				merge_operator: &Option<SharedMergeOperator>,
+				value_len: &ValuesLen,
+				key: &'a [u8],
+				entry: JournalEntry<'a>,
+				record: Option<Record<'a>>,
+			) -> Option<Result<(&'a [u8], Value<'a>)>> {
+				match resolve_journal_entry(merge_operator, value_len, key, entry, || Ok(record.map(Value::from))) {
+					Ok(None) => None,
+					Ok(Some(value)) => Some(Ok((key, value))),
+					Err(err) => Some(Err(err)),
+				}
+			}
+
+			match (operation, record) {
+				(IteratorValue::Journal(key, entry), IteratorValue::None) => {
+					match handle_journal_entry(&self.merge_operator, &self.value_len, key, entry, None) {
+						None => continue,
+						s => return s,
+					};
+				},
+				(IteratorValue::None, IteratorValue::DB(r)) => {
+					return Some(Ok((r.key(), Value::from(r))));
+				},
+				(IteratorValue::Journal(key, entry), IteratorValue::DB(r)) => {
+					let ord = r.key().cmp(key);
+					let ord = if self.descending { ord.reverse() } else { ord };
+
+					match ord {
+						Ordering::Equal => {
+							// merge operands may be applied to the flushed value
+							match handle_journal_entry(&self.merge_operator, &self.value_len, key, entry, Some(r)) {
+								None => continue,
+								s => return s,
+							};
+						},
+						Ordering::Greater => {
+							self.pending = IteratorValue::DB(r);
+
+							match handle_journal_entry(&self.merge_operator, &self.value_len, key, entry, None) {
+								None => continue,
+								s => return s,
+							};
+						},
+						Ordering::Less => {
+							self.pending = IteratorValue::Journal(key, entry);
+							return Some(Ok((r.key(), Value::from(r))));
+						},
+					};
+				},
+				(IteratorValue::None, IteratorValue::None) => return None,
+				(o, r) => unreachable!("operation: {:?}, record: {:?}", o, r)
+			};
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	extern crate tempdir;
+
+	use std::io::{Read, Seek, SeekFrom};
+	use std::thread;
+	use byteorder::{ByteOrder, LittleEndian};
+	use tiny_keccak;
+	use super::{Database, DatabaseIterator};
+	use options::{Durability, InternalOptions, Options, ValuesLen};
+	use error::ErrorKind;
+	use flush::Flush;
+	use merge::{MergeOperator, SharedMergeOperator};
+	use migration;
+	use verify::Inconsistency;
+	use quickcheck::TestResult;
+
+	#[test]
+	fn create_insert_and_query() {
+		let temp = tempdir::TempDir::new("create_insert_and_query").unwrap();
+
+		let mut db = Database::create(temp.path(), Options {
+			journal_eras: 0,
+			key_len: 3,
+			value_len: ValuesLen::Constant(3),
+			..Default::default()
+		}).unwrap();
+
+		let mut tx = db.create_transaction();
+		tx.insert("abc", "xyz").unwrap();
+		tx.insert("cde", "123").unwrap();
+
+		db.commit(&tx).unwrap();
+
+		assert_eq!(db.get("abc").unwrap().unwrap(), b"xyz");
+		assert_eq!(db.get("cde").unwrap().unwrap(), b"123");
+
+		// Another transaction
+		let mut tx = db.create_transaction();
+		tx.insert("abc", "456").unwrap();
 		tx.delete("cde").unwrap();
 
-		db.commit(&tx).unwrap();
+		db.commit(&tx).unwrap();
+
+		assert_eq!(db.get("abc").unwrap().unwrap(), b"456");
+		assert_eq!(db.get("cde").unwrap(), None); // from DB
+
+		// Flush journal and fetch everything from DB.
+		db.flush_journal(2).unwrap();
+
+		assert_eq!(db.get("abc").unwrap().unwrap(), b"456");
+		assert_eq!(db.get("cde").unwrap(), None);
+	}
+
+	#[test]
+	fn validate_key_length_at_insert() {
+		let temp = tempdir::TempDir::new("validate_key_length").unwrap();
+
+		let db = Database::create(temp.path(), Options {
+			journal_eras: 0,
+			key_len: 3,
+			..Default::default()
+		}).unwrap();
+
+		let mut tx = db.create_transaction();
+		assert_eq!(*tx.insert("abcdef", "456").unwrap_err().kind(), ErrorKind::InvalidKeyLen(3, 6));
+	}
+
+	#[test]
+	fn should_validate_key_length() {
+		let temp = tempdir::TempDir::new("create_insert_and_query").unwrap();
+
+		let db = Database::create(temp.path(), Options {
+			journal_eras: 0,
+			key_len: 3,
+			..Default::default()
+		}).unwrap();
+
+		assert_eq!(*db.get("a").unwrap_err().kind(), ErrorKind::InvalidKeyLen(3, 1));
+	}
+
+	#[test]
+	fn test_same_key_operation_ordering() {
+		let temp = tempdir::TempDir::new("test_fail").unwrap();
+
+		let mut db = Database::create(temp.path(), Options {
+			journal_eras: 0,
+			key_len: 3,
+			..Default::default()
+		}).unwrap();
+
+		let mut tx = db.create_transaction();
+		tx.insert("abc", "123").unwrap();
+		tx.delete("abc").unwrap();
+
+		db.commit(&tx).unwrap();
+		db.flush_journal(1).unwrap();
+
+		assert_eq!(db.get("abc").unwrap(), None);
+	}
+
+	#[test]
+	fn test_iter() {
+		let temp = tempdir::TempDir::new("test_iter").unwrap();
+
+		let mut db = Database::create(temp.path(), Options {
+			journal_eras: 0,
+			key_len: 3,
+			value_len: ValuesLen::Constant(3),
+			..Default::default()
+		}).unwrap();
+
+		let mut tx1 = db.create_transaction();
+		tx1.insert("abc", "123").unwrap();
+		tx1.insert("def", "467").unwrap();
+		tx1.insert("ghi", "zzz").unwrap();
+
+		db.commit(&tx1).unwrap();
+		db.flush_journal(1).unwrap();
+
+		let mut tx2 = db.create_transaction();
+		tx2.insert("jkl", "999").unwrap();
+		tx2.insert("def", "333").unwrap();
+		tx2.insert("pqr", "aaa").unwrap();
+		tx2.delete("ghi").unwrap();
+
+		db.commit(&tx2).unwrap();
+
+		let records = db.iter().unwrap().map(|item| {
+			let (k, v) = item.unwrap();
+			(::std::str::from_utf8(&k).unwrap().to_string(),
+			 ::std::str::from_utf8(&v.to_vec()).unwrap().to_string())
+		});
+
+		let expected = vec![
+			("abc", "123"),
+			("def", "333"),
+			("jkl", "999"),
+			("pqr", "aaa"),
+		];
+
+		assert_eq!(
+			records.collect::<Vec<_>>(),
+			expected.iter().map(|x| (x.0.to_string(), x.1.to_string())).collect::<Vec<_>>()
+		);
+	}
+
+	#[test]
+	fn test_range_iteration() {
+		let temp = tempdir::TempDir::new("test_range_iteration").unwrap();
+
+		let mut db = Database::create(temp.path(), Options {
+			journal_eras: 0,
+			extend_threshold_percent: 100,
+			key_index_bits: 4,
+			key_len: 3,
+			variable_key_len: false,
+			value_len: ValuesLen::Constant(3),
+			record_checksum: false,
+			merge_operator: None,
+			durability: Durability::default(),
+		}).unwrap();
+
+		// records with prefix 0 are shifted past the offsets of the following prefixes
+		let mut tx = db.create_transaction();
+		for i in 0..40u8 {
+			tx.insert([0x00, i, 0], [i, i, i]).unwrap();
+		}
+		for i in 0..5u8 {
+			tx.insert([0x20, i, 0], [i, i, i]).unwrap();
+		}
+		db.commit(&tx).unwrap();
+		db.flush_journal(None).unwrap();
+
+		let mut tx = db.create_transaction();
+		tx.insert([0x10, 1, 0], [1, 1, 1]).unwrap();
+		tx.delete([0x00, 5, 0]).unwrap();
+		tx.insert([0x20, 9, 0], [9, 9, 9]).unwrap();
+		db.commit(&tx).unwrap();
+
+		let keys = |iter: DatabaseIterator| iter.map(|item| item.unwrap().0.to_vec()).collect::<Vec<_>>();
+
+		assert_eq!(keys(db.iter_from([0x10, 0, 0]).unwrap()), vec![
+			vec![0x10, 1, 0],
+			vec![0x20, 0, 0],
+			vec![0x20, 1, 0],
+			vec![0x20, 2, 0],
+			vec![0x20, 3, 0],
+			vec![0x20, 4, 0],
+			vec![0x20, 9, 0],
+		]);
+		assert_eq!(keys(db.iter_from([0x00, 38, 0]).unwrap()).len(), 9);
+		assert_eq!(keys(db.iter_from([0x00, 0, 0]).unwrap()), keys(db.iter().unwrap()));
+
+		assert_eq!(keys(db.range([0x00, 3, 0]..[0x00, 8, 0]).unwrap()), vec![
+			vec![0x00, 3, 0],
+			vec![0x00, 4, 0],
+			vec![0x00, 6, 0],
+			vec![0x00, 7, 0],
+		]);
+		assert_eq!(keys(db.range([0x00, 39, 0]..[0x20, 1, 0]).unwrap()), vec![
+			vec![0x00, 39, 0],
+			vec![0x10, 1, 0],
+			vec![0x20, 0, 0],
+		]);
+		assert_eq!(keys(db.range([0x20, 5, 0]..[0x20, 9, 0]).unwrap()).len(), 0);
+
+		assert_eq!(*db.iter_from([0x00]).err().unwrap().kind(), ErrorKind::InvalidKeyLen(3, 1));
+
+		let mut all = keys(db.iter().unwrap());
+		all.reverse();
+		assert_eq!(keys(db.iter_rev().unwrap()), all);
+		assert_eq!(keys(db.iter_rev_before([0x20, 0, 0]).unwrap()), all[6..].to_vec());
+		assert_eq!(keys(db.iter_rev_before([0x10, 0, 0]).unwrap()).len(), 39);
+		assert_eq!(keys(db.range_rev([0x00, 3, 0]..[0x00, 8, 0]).unwrap()), vec![
+			vec![0x00, 7, 0],
+			vec![0x00, 6, 0],
+			vec![0x00, 4, 0],
+			vec![0x00, 3, 0],
+		]);
+		assert_eq!(keys(db.range_rev([0x00, 39, 0]..[0x20, 1, 0]).unwrap()), vec![
+			vec![0x20, 0, 0],
+			vec![0x10, 1, 0],
+			vec![0x00, 39, 0],
+		]);
+	}
+
+	#[test]
+	fn should_not_get_value_deleted_in_journal() {
+		let temp = tempdir::TempDir::new("deleted_in_journal").unwrap();
+
+		let mut db = Database::create(temp.path(), Options {
+			journal_eras: 1,
+			key_len: 3,
+			value_len: ValuesLen::Constant(3),
+			..Default::default()
+		}).unwrap();
+
+		let mut tx = db.create_transaction();
+		tx.insert("abc", "001").unwrap();
+		db.commit(&tx).unwrap();
+		db.commit(&db.create_transaction()).unwrap();
+		db.flush_journal(None).unwrap();
+
+		let mut tx = db.create_transaction();
+		tx.delete("abc").unwrap();
+		db.commit(&tx).unwrap();
+
+		assert_eq!(db.get("abc").unwrap(), None);
+		assert!(db.get_many(&["abc"]).unwrap()[0].is_none());
+	}
+
+	#[test]
+	fn test_get_many() {
+		let temp = tempdir::TempDir::new("test_get_many").unwrap();
+
+		let mut db = Database::create(temp.path(), Options {
+			journal_eras: 0,
+			extend_threshold_percent: 100,
+			key_index_bits: 4,
+			key_len: 3,
+			variable_key_len: false,
+			value_len: ValuesLen::Constant(3),
+			record_checksum: false,
+			merge_operator: None,
+			durability: Durability::default(),
+		}).unwrap();
+
+		let mut tx = db.create_transaction();
+		for i in 0..40u8 {
+			tx.insert([0x00, i, 0], [i, i, i]).unwrap();
+			tx.insert([i * 6, i, 1], [i, i, i]).unwrap();
+		}
+		db.commit(&tx).unwrap();
+		db.flush_journal(None).unwrap();
+
+		let mut tx = db.create_transaction();
+		tx.insert([0x10, 1, 0], [1, 1, 1]).unwrap();
+		tx.delete([0x00, 5, 0]).unwrap();
+		db.commit(&tx).unwrap();
+
+		let mut keys = Vec::new();
+		for i in (0..45u8).rev() {
+			keys.push([0x00, i, 0]);
+			keys.push([i.wrapping_mul(6), i, 1]);
+			keys.push([0x10, i, 0]);
+		}
+		keys.push([0x00, 3, 0]);
+
+		let values = db.get_many(&keys).unwrap();
+		assert_eq!(values.len(), keys.len());
+		for (key, value) in keys.iter().zip(values) {
+			assert_eq!(value.map(|v| v.to_vec()), db.get(key).unwrap().map(|v| v.to_vec()));
+		}
+		assert_eq!(db.get_many(&keys).unwrap().iter().filter(|v| v.is_some()).count(), 81);
+
+		assert_eq!(*db.get_many(&[[0x00, 1]]).unwrap_err().kind(), ErrorKind::InvalidKeyLen(3, 2));
+	}
+
+	#[test]
+	fn test_value_reader() {
+		let temp = tempdir::TempDir::new("test_value_reader").unwrap();
+
+		let mut db = Database::create(temp.path(), Options {
+			journal_eras: 0,
+			key_len: 3,
+			value_len: ValuesLen::Variable { expected: 4 },
+			..Default::default()
+		}).unwrap();
+
+		let value: Vec<u8> = (0..100).collect();
+		let mut tx = db.create_transaction();
+		tx.insert("abc", &value).unwrap();
+		db.commit(&tx).unwrap();
+
+		// journaled and flushed values are read the same way
+		for _ in 0..2 {
+			let mut reader = db.get("abc").unwrap().unwrap().reader();
+			let mut buf = [0u8; 30];
+			assert_eq!(reader.read(&mut buf).unwrap(), 30);
+			assert_eq!(&buf[..], &value[0..30]);
+
+			assert_eq!(reader.seek(SeekFrom::Current(20)).unwrap(), 50);
+			assert_eq!(reader.read(&mut buf).unwrap(), 30);
+			assert_eq!(&buf[..], &value[50..80]);
+
+			assert_eq!(reader.seek(SeekFrom::End(-10)).unwrap(), 90);
+			let mut rest = Vec::new();
+			reader.read_to_end(&mut rest).unwrap();
+			assert_eq!(&rest[..], &value[90..]);
+
+			assert!(reader.seek(SeekFrom::Current(-101)).is_err());
+			assert_eq!(reader.seek(SeekFrom::Start(200)).unwrap(), 200);
+			assert_eq!(reader.read(&mut buf).unwrap(), 0);
+
+			db.flush_journal(None).unwrap();
+		}
+	}
+
+	/// Appends operands to the existing value.
+	struct Concat;
+
+	impl MergeOperator for Concat {
+		fn merge(&self, _key: &[u8], existing: Option<&[u8]>, operands: &[&[u8]]) -> Vec<u8> {
+			let mut value = existing.unwrap_or_default().to_vec();
+			for operand in operands {
+				value.extend_from_slice(operand);
+			}
+			value
+		}
+	}
+
+	#[test]
+	fn test_merge() {
+		let temp = tempdir::TempDir::new("test_merge").unwrap();
+		let mut db = Database::create(temp.path(), Options {
+			journal_eras: 0,
+			key_len: 3,
+			value_len: ValuesLen::Variable { expected: 4 },
+			merge_operator: Some(SharedMergeOperator::new(Concat)),
+			..Default::default()
+		}.namespace("plain", Options {
+			key_len: 3,
+			..Default::default()
+		})).unwrap();
+
+		let mut tx = db.create_transaction();
+		tx.namespace("plain").unwrap().merge("abc", "1").unwrap();
+		assert_eq!(*db.commit(&tx).unwrap_err().kind(), ErrorKind::MissingMergeOperator);
+		assert_eq!(*db.prepare(&tx).unwrap_err().kind(), ErrorKind::MissingMergeOperator);
+
+		let mut tx = db.create_transaction();
+		tx.insert("abc", "1").unwrap();
+		tx.merge("abc", "2").unwrap();
+		tx.merge("def", "3").unwrap();
+		db.commit(&tx).unwrap();
+		assert_eq!(db.get("abc").unwrap().unwrap(), b"12");
+		assert_eq!(db.get("def").unwrap().unwrap(), b"3");
+
+		db.flush_journal(None).unwrap();
+		assert_eq!(db.get("abc").unwrap().unwrap(), b"12");
+		assert_eq!(db.get("def").unwrap().unwrap(), b"3");
+
+		// operands are applied to flushed values
+		let mut tx = db.create_transaction();
+		tx.merge("abc", "4").unwrap();
+		tx.merge("ghi", "5").unwrap();
+		db.commit(&tx).unwrap();
+		let mut tx = db.create_transaction();
+		tx.merge("abc", "6").unwrap();
+		tx.delete("def").unwrap();
+		tx.merge("def", "7").unwrap();
+		db.commit(&tx).unwrap();
+
+		let expected = vec![
+			(b"abc".to_vec(), b"1246".to_vec()),
+			(b"def".to_vec(), b"7".to_vec()),
+			(b"ghi".to_vec(), b"5".to_vec()),
+		];
+		assert_eq!(db.get("abc").unwrap().unwrap(), b"1246");
+		assert_eq!(db.get("def").unwrap().unwrap(), b"7");
+		let values = db.get_many(&["ghi", "abc"]).unwrap();
+		assert_eq!(values.iter().map(|v| v.as_ref().map(|v| v.to_vec())).collect::<Vec<_>>(), vec![Some(b"5".to_vec()), Some(b"1246".to_vec())]);
+		assert_eq!(db.snapshot().get("abc").unwrap().unwrap(), b"1246");
+		assert_eq!(db.iter().unwrap().map(|v| v.map(|(k, v)| (k.to_vec(), v.to_vec()))).collect::<Result<Vec<_>, _>>().unwrap(), expected);
+		let descending = db.iter_rev().unwrap().map(|v| v.map(|(k, v)| (k.to_vec(), v.to_vec()))).collect::<Result<Vec<_>, _>>().unwrap();
+		assert_eq!(descending, expected.iter().rev().cloned().collect::<Vec<_>>());
+
+		db.flush_journal(None).unwrap();
+		assert_eq!(db.iter().unwrap().map(|v| v.map(|(k, v)| (k.to_vec(), v.to_vec()))).collect::<Result<Vec<_>, _>>().unwrap(), expected);
+		assert!(db.verify().is_consistent());
+	}
+
+	#[test]
+	fn test_merge_constant_len() {
+		let temp = tempdir::TempDir::new("test_merge_constant_len").unwrap();
+		let mut db = Database::create(temp.path(), Options {
+			journal_eras: 0,
+			key_len: 3,
+			value_len: ValuesLen::Constant(2),
+			merge_operator: Some(SharedMergeOperator::new(Concat)),
+			..Default::default()
+		}).unwrap();
+
+		let mut tx = db.create_transaction();
+		tx.insert("abc", "1").unwrap();
+		tx.merge("abc", "2").unwrap();
+		tx.merge("def", "3").unwrap();
+		db.commit(&tx).unwrap();
+		assert_eq!(db.get("abc").unwrap().unwrap(), b"12");
+		assert_eq!(*db.get("def").unwrap_err().kind(), ErrorKind::InvalidMergedValueLen(b"def".to_vec(), 2, 1));
+		assert_eq!(*db.flush_journal(None).unwrap_err().kind(), ErrorKind::InvalidMergedValueLen(b"def".to_vec(), 2, 1));
+		assert_eq!(db.get("abc").unwrap().unwrap(), b"12");
+	}
+
+	#[test]
+	fn test_flushed_eras_are_skipped() {
+		use std::fs;
+
+		let temp = tempdir::TempDir::new("test_flushed_eras_are_skipped").unwrap();
+		let options = || Options {
+			journal_eras: 0,
+			key_len: 3,
+			value_len: ValuesLen::Variable { expected: 4 },
+			merge_operator: Some(SharedMergeOperator::new(Concat)),
+			..Default::default()
+		};
+
+		let mut db = Database::create(temp.path(), options()).unwrap();
+		let mut tx = db.create_transaction();
+		tx.merge("abc", "1").unwrap();
+		db.commit(&tx).unwrap();
+		let era = fs::read(temp.path().join("0.era")).unwrap();
+		db.flush_journal(None).unwrap();
+		drop(db);
+
+		// the era file is left behind, as if the database crashed before deleting it
+		fs::write(temp.path().join("0.era"), era).unwrap();
+		let mut db = Database::open(temp.path(), options()).unwrap();
+		assert!(!temp.path().join("0.era").exists());
+		assert_eq!(db.get("abc").unwrap().unwrap(), b"1");
+
+		let mut tx = db.create_transaction();
+		tx.merge("abc", "2").unwrap();
+		db.commit(&tx).unwrap();
+		assert!(temp.path().join("1.era").exists());
+		db.flush_journal(None).unwrap();
+		assert_eq!(db.get("abc").unwrap().unwrap(), b"12");
+	}
+
+	#[test]
+	fn test_skipped_eras_are_flushed_to_other_namespaces() {
+		let temp = tempdir::TempDir::new("test_skipped_eras_are_flushed_to_other_namespaces").unwrap();
+		let namespace_options = || Options {
+			key_len: 3,
+			value_len: ValuesLen::Variable { expected: 4 },
+			merge_operator: Some(SharedMergeOperator::new(Concat)),
+			..Default::default()
+		};
+		let options = || Options { journal_eras: 0, ..namespace_options() }.namespace("code", namespace_options());
+
+		let mut db = Database::create(temp.path(), options()).unwrap();
+		let mut tx = db.create_transaction();
+		tx.merge("abc", "1").unwrap();
+		tx.namespace("code").unwrap().merge("abc", "1").unwrap();
+		db.commit(&tx).unwrap();
+		db.flush_journal(None).unwrap();
+
+		let mut tx = db.create_transaction();
+		tx.merge("abc", "2").unwrap();
+		tx.namespace("code").unwrap().merge("abc", "2").unwrap();
+		db.commit(&tx).unwrap();
+
+		// the database crashes after writing the flush of the default namespace only
+		let era = db.journal.front().cloned().unwrap();
+		let mut flushes = db.prepare_flushes(&era, era.len()).unwrap();
+		assert_eq!(flushes.len(), 2);
+		flushes.pop().unwrap().1.delete().unwrap();
+		drop(flushes);
+		drop(db);
+
+		for _ in 0..2 {
+			let db = Database::open(temp.path(), options()).unwrap();
+			assert_eq!(db.journal.len(), 0);
+			assert_eq!(db.get("abc").unwrap().unwrap(), b"12");
+			assert_eq!(db.get_in("code", "abc").unwrap().unwrap(), b"12");
+		}
+	}
+
+	#[test]
+	fn test_conditional_operations() {
+		let temp = tempdir::TempDir::new("test_conditional_operations").unwrap();
+
+		let mut db = Database::create(temp.path(), Options {
+			journal_eras: 1,
+			key_len: 3,
+			value_len: ValuesLen::Constant(3),
+			..Default::default()
+		}).unwrap();
+
+		let mut tx = db.create_transaction();
+		tx.insert_if_absent("abc", "001").unwrap();
+		tx.insert_if_absent("def", "002").unwrap();
+		db.commit(&tx).unwrap();
+		db.commit(&db.create_transaction()).unwrap();
+		db.flush_journal(None).unwrap();
+
+		// checked against flushed values
+		let mut tx = db.create_transaction();
+		tx.insert("ghi", "003").unwrap();
+		tx.insert_if_absent("abc", "004").unwrap();
+		assert_eq!(*db.commit(&tx).unwrap_err().kind(), ErrorKind::ConditionFailed(b"abc".to_vec()));
+		assert_eq!(db.get("ghi").unwrap(), None);
+
+		let mut tx = db.create_transaction();
+		tx.swap("abc", "001", "005").unwrap();
+		tx.delete_if_equals("def", "002").unwrap();
+		db.commit(&tx).unwrap();
+		assert_eq!(db.get("abc").unwrap().unwrap(), b"005");
+		assert_eq!(db.get("def").unwrap(), None);
+
+		// checked against journal values
+		let mut tx = db.create_transaction();
+		tx.swap("abc", "001", "006").unwrap();
+		assert_eq!(*db.commit(&tx).unwrap_err().kind(), ErrorKind::ConditionFailed(b"abc".to_vec()));
+
+		let mut tx = db.create_transaction();
+		tx.delete_if_equals("def", "002").unwrap();
+		assert_eq!(*db.commit(&tx).unwrap_err().kind(), ErrorKind::ConditionFailed(b"def".to_vec()));
+
+		let mut tx = db.create_transaction();
+		tx.insert_if_absent("def", "007").unwrap();
+		let prepared = db.prepare(&tx).unwrap();
+		let mut tx = db.create_transaction();
+		tx.insert("def", "008").unwrap();
+		db.commit(&tx).unwrap();
+		assert_eq!(*db.apply(prepared).unwrap_err().kind(), ErrorKind::ConditionFailed(b"def".to_vec()));
+		assert_eq!(db.get("def").unwrap().unwrap(), b"008");
+	}
+
+	#[test]
+	fn test_prepare_and_apply() {
+		let temp = tempdir::TempDir::new("test_prepare_and_apply").unwrap();
+
+		let mut db = Database::create(temp.path(), Options {
+			journal_eras: 0,
+			key_len: 3,
+			value_len: ValuesLen::Constant(3),
+			..Default::default()
+		}).unwrap();
+
+		let mut tx = db.create_transaction();
+		tx.insert("abc", "001").unwrap();
+		let prepared = db.prepare(&tx).unwrap();
+		assert_eq!(db.get("abc").unwrap(), None);
+
+		let prepared = thread::spawn(move || prepared).join().unwrap();
+		db.apply(prepared).unwrap();
+		assert_eq!(db.get("abc").unwrap().unwrap(), b"001");
+
+		db.flush_journal(None).unwrap();
+		assert_eq!(db.get("abc").unwrap().unwrap(), b"001");
+	}
+
+	#[test]
+	fn test_snapshot() {
+		let temp = tempdir::TempDir::new("test_snapshot").unwrap();
+
+		let mut db = Database::create(temp.path(), Options {
+			journal_eras: 1,
+			key_len: 3,
+			value_len: ValuesLen::Constant(3),
+			..Default::default()
+		}).unwrap();
+
+		let mut tx = db.create_transaction();
+		tx.insert("abc", "001").unwrap();
+		tx.insert("def", "002").unwrap();
+		db.commit(&tx).unwrap();
+		let mut tx = db.create_transaction();
+		tx.insert("ghi", "003").unwrap();
+		db.commit(&tx).unwrap();
+		db.flush_journal(None).unwrap();
+
+		let snapshot = db.snapshot();
+
+		let mut tx = db.create_transaction();
+		tx.delete("abc").unwrap();
+		tx.insert("def", "004").unwrap();
+		db.commit(&tx).unwrap();
+		db.rollback(2).unwrap();
+		let mut tx = db.create_transaction();
+		tx.insert("jkl", "005").unwrap();
+		db.commit(&tx).unwrap();
+		let mut tx = db.create_transaction();
+		tx.insert("mno", "006").unwrap();
+		db.commit(&tx).unwrap();
+		// snapshot keeps reading the data it was created with
+		db.flush_journal(None).unwrap();
+		assert_eq!(db.journal.len(), 1);
+
+		assert_eq!(snapshot.get("abc").unwrap().unwrap(), b"001");
+		assert_eq!(snapshot.get("def").unwrap().unwrap(), b"002");
+		assert_eq!(snapshot.get("ghi").unwrap().unwrap(), b"003");
+		assert_eq!(snapshot.get("jkl").unwrap(), None);
+		assert_eq!(snapshot.get("mno").unwrap(), None);
+		assert_eq!(snapshot.iter().unwrap().count(), 3);
+
+		assert_eq!(db.get("ghi").unwrap(), None);
+		assert_eq!(db.get("jkl").unwrap().unwrap(), b"005");
+		assert_eq!(db.get("mno").unwrap().unwrap(), b"006");
+
+		drop(snapshot);
+		let mut tx = db.create_transaction();
+		tx.insert("pqr", "007").unwrap();
+		db.commit(&tx).unwrap();
+		db.flush_journal(None).unwrap();
+		assert_eq!(db.journal.len(), 1);
+		assert_eq!(db.get("def").unwrap().unwrap(), b"002");
+		assert_eq!(db.get("mno").unwrap().unwrap(), b"006");
+		assert_eq!(db.get("pqr").unwrap().unwrap(), b"007");
+
+		// reopened database reads the copied data file
+		drop(db);
+		let db = Database::open(temp.path(), Options {
+			journal_eras: 1,
+			key_len: 3,
+			value_len: ValuesLen::Constant(3),
+			..Default::default()
+		}).unwrap();
+		assert_eq!(db.get("jkl").unwrap().unwrap(), b"005");
+		assert_eq!(db.get("pqr").unwrap().unwrap(), b"007");
+		assert!(db.verify().is_consistent());
+	}
+
+	#[test]
+	fn test_rollback() {
+		let temp = tempdir::TempDir::new("test_rollback").unwrap();
+		let options = || Options {
+			journal_eras: 2,
+			key_len: 3,
+			value_len: ValuesLen::Constant(3),
+			..Default::default()
+		};
+
+		{
+			let mut db = Database::create(temp.path(), options()).unwrap();
+
+			for value in &["001", "002", "003", "004"] {
+				let mut tx = db.create_transaction();
+				tx.insert("abc", value).unwrap();
+				db.commit(&tx).unwrap();
+			}
+			db.flush_journal(None).unwrap();
+
+			assert_eq!(*db.rollback(3).unwrap_err().kind(), ErrorKind::InvalidRollback(3, 2));
+			db.rollback(1).unwrap();
+			assert_eq!(db.get("abc").unwrap().unwrap(), b"003");
+			db.rollback(1).unwrap();
+			assert_eq!(db.get("abc").unwrap().unwrap(), b"002");
+
+			let mut tx = db.create_transaction();
+			tx.insert("abc", "005").unwrap();
+			db.commit(&tx).unwrap();
+		}
+
+		let db = Database::open(temp.path(), options()).unwrap();
+		assert_eq!(db.get("abc").unwrap().unwrap(), b"005");
+	}
+
+	#[test]
+	fn test_squash_journal() {
+		let temp = tempdir::TempDir::new("test_squash_journal").unwrap();
+		let options = || Options {
+			journal_eras: 2,
+			key_len: 3,
+			value_len: ValuesLen::Constant(3),
+			..Default::default()
+		};
+
+		{
+			let mut db = Database::create(temp.path(), options()).unwrap();
+
+			for (key, value) in &[("abc", "001"), ("def", "002"), ("abc", "003"), ("ghi", "004"), ("abc", "005")] {
+				let mut tx = db.create_transaction();
+				tx.insert(key, value).unwrap();
+				db.commit(&tx).unwrap();
+			}
+
+			db.squash_journal(1).unwrap();
+			assert_eq!(db.journal.len(), 5);
+			assert_eq!(db.stats().unwrap().journal_eras, 5);
+			assert_eq!(db.get("abc").unwrap().unwrap(), b"005");
+			assert_eq!(db.get("def").unwrap().unwrap(), b"002");
+
+			// squashed eras are rolled back one by one
+			db.rollback(2).unwrap();
+			assert_eq!(db.get("abc").unwrap().unwrap(), b"003");
+			assert_eq!(db.get("ghi").unwrap(), None);
+		}
+
+		let mut db = Database::open(temp.path(), options()).unwrap();
+		assert_eq!(db.journal.len(), 3);
+		assert_eq!(db.get("abc").unwrap().unwrap(), b"003");
+
+		// squashed eras are flushed one by one
+		db.flush_journal(Some(1)).unwrap();
+		assert_eq!(db.journal.len(), 2);
+		assert_eq!(db.get("abc").unwrap().unwrap(), b"003");
+		drop(db);
+
+		// flushed eras of the squashed file are skipped when it's opened again
+		let mut db = Database::open(temp.path(), options()).unwrap();
+		assert_eq!(db.journal.len(), 2);
+		assert!(temp.path().join("0-2.era").exists());
+		assert_eq!(db.get("abc").unwrap().unwrap(), b"003");
+
+		db.rollback(1).unwrap();
+		assert_eq!(db.journal.len(), 1);
+		assert!(!temp.path().join("0-2.era").exists());
+		assert_eq!(db.get("abc").unwrap().unwrap(), b"001");
+		assert_eq!(db.get("def").unwrap().unwrap(), b"002");
+
+		let mut tx = db.create_transaction();
+		tx.insert("ghi", "006").unwrap();
+		db.commit(&tx).unwrap();
+		let mut tx = db.create_transaction();
+		tx.insert("ghi", "007").unwrap();
+		db.commit(&tx).unwrap();
+		db.flush_journal(None).unwrap();
+		assert_eq!(db.journal.len(), 2);
+		assert!(!temp.path().join("0-1.era").exists());
+		assert_eq!(db.get("abc").unwrap().unwrap(), b"001");
+		assert_eq!(db.get("def").unwrap().unwrap(), b"002");
+		assert_eq!(db.get("ghi").unwrap().unwrap(), b"007");
+		assert!(db.verify().is_consistent());
+	}
+
+	#[test]
+	fn test_durability() {
+		for durability in &[Durability::None, Durability::Data, Durability::DataAndDirectory] {
+			let temp = tempdir::TempDir::new("test_durability").unwrap();
+			let options = |durability: Durability| Options {
+				journal_eras: 1,
+				key_len: 3,
+				value_len: ValuesLen::Constant(3),
+				durability,
+				..Default::default()
+			}.namespace("accounts", Options {
+				key_len: 3,
+				value_len: ValuesLen::Constant(3),
+				durability,
+				..Default::default()
+			});
+
+			{
+				let mut db = Database::create(temp.path(), options(*durability)).unwrap();
+				for value in &["001", "002", "003"] {
+					let mut tx = db.create_transaction();
+					tx.insert("abc", value).unwrap();
+					tx.namespace("accounts").unwrap().insert("def", value).unwrap();
+					db.commit(&tx).unwrap();
+				}
+				db.flush_journal(None).unwrap();
+				db.rollback(1).unwrap();
+			}
+
+			// durability is not stored, so it may change
+			let db = Database::open(temp.path(), options(Durability::default())).unwrap();
+			assert_eq!(db.get("abc").unwrap().unwrap(), b"002");
+			assert_eq!(db.get_in("accounts", "def").unwrap().unwrap(), b"002");
+		}
+	}
+
+	#[test]
+	fn test_open_read_only() {
+		let temp = tempdir::TempDir::new("test_open_read_only").unwrap();
+		let options = || Options {
+			journal_eras: 0,
+			key_len: 3,
+			value_len: ValuesLen::Constant(3),
+			..Default::default()
+		};
+
+		let mut db = Database::create(temp.path(), options()).unwrap();
+		let mut tx = db.create_transaction();
+		tx.insert("abc", "001").unwrap();
+		db.commit(&tx).unwrap();
+		db.flush_journal(None).unwrap();
+
+		let mut read_only = Database::open_read_only(temp.path(), options()).unwrap();
+		assert_eq!(read_only.get("abc").unwrap().unwrap(), b"001");
+		let tx = read_only.create_transaction();
+		assert_eq!(*read_only.commit(&tx).unwrap_err().kind(), ErrorKind::ReadOnly);
+		assert_eq!(*read_only.flush_journal(None).unwrap_err().kind(), ErrorKind::ReadOnly);
+
+		let mut tx = db.create_transaction();
+		tx.insert("abc", "002").unwrap();
+		tx.insert("def", "003").unwrap();
+		db.commit(&tx).unwrap();
+
+		assert_eq!(read_only.get("abc").unwrap().unwrap(), b"001");
+		assert_eq!(read_only.get("def").unwrap(), None);
+		read_only.reload_journal().unwrap();
+		assert_eq!(read_only.get("abc").unwrap().unwrap(), b"002");
+		assert_eq!(read_only.get("def").unwrap().unwrap(), b"003");
+
+		// flushing is deferred while flushed data is read
+		{
+			let iter = read_only.iter().unwrap();
+			db.flush_journal(None).unwrap();
+			assert_eq!(db.journal.len(), 1);
+			db.compact().unwrap();
+			assert!(db.compaction_pending);
+			assert_eq!(iter.count(), 2);
+		}
+
+		// and it's done between reads
+		let value = read_only.get("abc").unwrap().unwrap();
+		db.flush_journal(None).unwrap();
+		assert!(!db.compaction_pending);
+		assert_eq!(db.journal.len(), 0);
+		assert_eq!(value, b"002");
+		assert_eq!(*read_only.get("abc").unwrap_err().kind(), ErrorKind::FlushedDataChanged);
+		assert_eq!(*read_only.snapshot().get("abc").unwrap_err().kind(), ErrorKind::FlushedDataChanged);
+
+		read_only.reload_journal().unwrap();
+		assert_eq!(read_only.journal.len(), 0);
+		assert_eq!(read_only.get("abc").unwrap().unwrap(), b"002");
+		assert_eq!(read_only.get("def").unwrap().unwrap(), b"003");
+		assert_eq!(read_only.reader().get("def").unwrap().unwrap(), b"003");
+
+		// the writer isn't opened until reads of other processes finish
+		drop(db);
+		{
+			let _iter = read_only.iter().unwrap();
+			assert!(matches!(Database::open(temp.path(), options()).unwrap_err().kind(), &ErrorKind::DatabaseLocked(_)));
+		}
+		Database::open(temp.path(), options()).unwrap();
+	}
 
-		assert_eq!(db.get("abc").unwrap().unwrap(), b"456");
-		assert_eq!(db.get("cde").unwrap(), None); // from DB
+	#[test]
+	fn test_open_read_only_does_not_create_flush_lock() {
+		let temp = tempdir::TempDir::new("test_open_read_only_does_not_create_flush_lock").unwrap();
+		let options = || Options {
+			journal_eras: 0,
+			key_len: 3,
+			value_len: ValuesLen::Constant(3),
+			..Default::default()
+		};
 
-		// Flush journal and fetch everything from DB.
-		db.flush_journal(2).unwrap();
+		{
+			let mut db = Database::create(temp.path(), options()).unwrap();
+			let mut tx = db.create_transaction();
+			tx.insert("abc", "001").unwrap();
+			db.commit(&tx).unwrap();
+			db.flush_journal(None).unwrap();
+		}
 
-		assert_eq!(db.get("abc").unwrap().unwrap(), b"456");
-		assert_eq!(db.get("cde").unwrap(), None);
+		// as if it was created by an older version
+		let flush_lock_path = temp.path().join(Database::FLUSH_LOCK_FILE);
+		::std::fs::remove_file(&flush_lock_path).unwrap();
+		let read_only = Database::open_read_only(temp.path(), options()).unwrap();
+		assert_eq!(read_only.get("abc").unwrap().unwrap(), b"001");
+		assert!(!flush_lock_path.exists());
 	}
 
 	#[test]
-	fn validate_key_length_at_insert() {
-		let temp = tempdir::TempDir::new("validate_key_length").unwrap();
+	fn test_namespaces() {
+		let temp = tempdir::TempDir::new("test_namespaces").unwrap();
+		let options = || Options {
+			journal_eras: 1,
+			key_len: 3,
+			value_len: ValuesLen::Constant(3),
+			..Default::default()
+		}.namespace("accounts", Options {
+			key_len: 2,
+			value_len: ValuesLen::Variable { expected: 4 },
+			..Default::default()
+		}).namespace("code", Options {
+			key_len: 3,
+			value_len: ValuesLen::Constant(1),
+			..Default::default()
+		});
 
-		let db = Database::create(temp.path(), Options {
+		let mut db = Database::create(temp.path(), options()).unwrap();
+		let mut tx = db.create_transaction();
+		tx.insert("abc", "001").unwrap();
+		tx.namespace("accounts").unwrap().insert("ab", "account").unwrap();
+		tx.namespace("code").unwrap().insert("abc", "c").unwrap();
+		assert_eq!(*tx.namespace("accounts").unwrap().insert("abc", "001").unwrap_err().kind(), ErrorKind::InvalidKeyLen(2, 3));
+		assert_eq!(*tx.namespace("storage").err().unwrap().kind(), ErrorKind::UnknownNamespace("storage".into()));
+		db.commit(&tx).unwrap();
+
+		// the same key is independent in each namespace
+		assert_eq!(db.get("abc").unwrap().unwrap(), b"001");
+		assert_eq!(db.get_in("code", "abc").unwrap().unwrap(), b"c");
+		assert_eq!(db.get_in("accounts", "ab").unwrap().unwrap(), b"account");
+		assert!(db.get_in("storage", "abc").is_err());
+
+		let mut tx = db.create_transaction();
+		tx.namespace("code").unwrap().delete_if_equals("abc", "c").unwrap();
+		tx.namespace("accounts").unwrap().insert_if_absent("cd", "other").unwrap();
+		db.commit(&tx).unwrap();
+		db.flush_journal(None).unwrap();
+		assert_eq!(db.journal.len(), 1);
+
+		assert_eq!(db.get("abc").unwrap().unwrap(), b"001");
+		assert_eq!(db.get_in("code", "abc").unwrap(), None);
+		let accounts: Vec<_> = db.iter_in("accounts").unwrap().map(|r| r.unwrap()).map(|(k, v)| (k.to_vec(), v.to_vec())).collect();
+		assert_eq!(accounts, vec![(b"ab".to_vec(), b"account".to_vec()), (b"cd".to_vec(), b"other".to_vec())]);
+		let accounts: Vec<_> = db.iter_rev_in("accounts").unwrap().map(|r| r.unwrap()).map(|(k, _)| k.to_vec()).collect();
+		assert_eq!(accounts, vec![b"cd".to_vec(), b"ab".to_vec()]);
+		assert_eq!(db.range_in("accounts", "ab".."cd").unwrap().count(), 1);
+		assert_eq!(*db.range_in("accounts", "abc".."cde").err().unwrap().kind(), ErrorKind::InvalidKeyLen(2, 3));
+		let values = db.get_many_in("accounts", &["cd", "ef", "ab"]).unwrap();
+		assert_eq!(values.iter().map(|v| v.as_ref().map(|v| v.to_vec())).collect::<Vec<_>>(), vec![Some(b"other".to_vec()), None, Some(b"account".to_vec())]);
+		assert_eq!(db.snapshot_in("accounts").unwrap().get("cd").unwrap().unwrap(), b"other");
+
+		// a failed condition in one namespace rejects the whole transaction
+		let mut tx = db.create_transaction();
+		tx.insert("def", "002").unwrap();
+		tx.namespace("accounts").unwrap().insert_if_absent("ab", "new").unwrap();
+		assert_eq!(*db.commit(&tx).unwrap_err().kind(), ErrorKind::ConditionFailed(b"ab".to_vec()));
+		assert_eq!(db.get("def").unwrap(), None);
+		drop(db);
+
+		let mut other = options();
+		other.namespaces.reverse();
+		assert!(Database::open(temp.path(), other).is_err());
+
+		let mut db = Database::open(temp.path(), options()).unwrap();
+		db.flush_journal(None).unwrap();
+		assert_eq!(db.get_in("accounts", "cd").unwrap().unwrap(), b"other");
+		assert_eq!(db.iter_in("code").unwrap().count(), 0);
+		assert_eq!(db.iter().unwrap().count(), 1);
+	}
+
+	#[test]
+	fn test_invalid_key_len_in_data() {
+		use std::fs;
+
+		let temp = tempdir::TempDir::new("test_invalid_key_len_in_data").unwrap();
+		let options = || Options {
 			journal_eras: 0,
-			key_len: 3,
+			key_index_bits: 4,
+			key_len: 4,
+			variable_key_len: true,
+			value_len: ValuesLen::Constant(1),
+			..Default::default()
+		};
+
+		let mut db = Database::create(temp.path(), options()).unwrap();
+		let mut tx = db.create_transaction();
+		tx.insert([0u8, 1], [1]).unwrap();
+		db.commit(&tx).unwrap();
+		db.flush_journal(None).unwrap();
+		drop(db);
+
+		// length of the key of the first record points past its end
+		let path = temp.path().join("data.db");
+		let mut data = fs::read(&path).unwrap();
+		data[1] = 0xff;
+		fs::write(&path, data).unwrap();
+
+		let mut db = Database::open(temp.path(), options()).unwrap();
+		assert_eq!(*db.stats().unwrap_err().kind(), ErrorKind::InvalidRecord);
+		assert_eq!(*db.compact().unwrap_err().kind(), ErrorKind::InvalidRecord);
+
+		let mut tx = db.create_transaction();
+		tx.insert([0u8, 2], [2]).unwrap();
+		db.commit(&tx).unwrap();
+		assert_eq!(*db.flush_journal(None).unwrap_err().kind(), ErrorKind::InvalidRecord);
+	}
+
+	#[test]
+	fn test_variable_key_len() {
+		let temp = tempdir::TempDir::new("test_variable_key_len").unwrap();
+		let mut db = Database::create(temp.path(), Options {
+			journal_eras: 0,
+			key_index_bits: 4,
+			key_len: 4,
+			variable_key_len: true,
+			value_len: ValuesLen::Constant(1),
 			..Default::default()
 		}).unwrap();
 
+		let keys: Vec<Vec<u8>> = (0..100u8)
+			.map(|i| {
+				let key = [i.wrapping_mul(37), i, 0, i];
+				key[..(i % 5) as usize].to_vec()
+			})
+			.collect();
+
 		let mut tx = db.create_transaction();
-		assert_eq!(*tx.insert("abcdef", "456").unwrap_err().kind(), ErrorKind::InvalidKeyLen(3, 6));
+		assert_eq!(*tx.insert("abcde", "0").unwrap_err().kind(), ErrorKind::InvalidKeyLen(4, 5));
+		for (i, key) in keys.iter().enumerate() {
+			tx.insert(key, [i as u8]).unwrap();
+		}
+		db.commit(&tx).unwrap();
+		db.flush_journal(None).unwrap();
+		assert!(db.namespaces[0].metadata.prefixes.prefix_bits() > 4);
+
+		let mut expected: Vec<_> = keys.iter().enumerate().map(|(i, key)| (key.clone(), vec![i as u8])).collect();
+		expected.sort();
+		expected.dedup_by(|a, b| a.0 == b.0);
+
+		for &(ref key, _) in &expected {
+			assert!(db.get(key).unwrap().is_some(), "missing key {:?}", key);
+		}
+		assert_eq!(db.get([0u8, 0, 0]).unwrap(), None);
+
+		let mut tx = db.create_transaction();
+		tx.delete([]).unwrap();
+		tx.insert([37u8, 1, 0], [200]).unwrap();
+		db.commit(&tx).unwrap();
+
+		let iterated: Vec<_> = db.iter().unwrap().map(|r| r.unwrap()).map(|(k, v)| (k.to_vec(), v.to_vec())).collect();
+		let mut reversed: Vec<_> = db.iter_rev().unwrap().map(|r| r.unwrap()).map(|(k, v)| (k.to_vec(), v.to_vec())).collect();
+		reversed.reverse();
+		assert_eq!(iterated, reversed);
+
+		db.flush_journal(None).unwrap();
+		let flushed: Vec<_> = db.iter().unwrap().map(|r| r.unwrap()).map(|(k, v)| (k.to_vec(), v.to_vec())).collect();
+		assert_eq!(iterated, flushed);
+		assert!(flushed.windows(2).all(|w| w[0].0 < w[1].0));
+		assert!(flushed.iter().all(|&(ref k, _)| !k.is_empty()));
+		assert_eq!(db.get([37u8, 1, 0]).unwrap().unwrap(), [200]);
+		assert_eq!(flushed.len(), expected.len());
 	}
 
 	#[test]
-	fn should_validate_key_length() {
-		let temp = tempdir::TempDir::new("create_insert_and_query").unwrap();
+	fn test_record_checksum() {
+		use std::fs;
 
-		let db = Database::create(temp.path(), Options {
+		let temp = tempdir::TempDir::new("test_record_checksum").unwrap();
+		let options = || Options {
 			journal_eras: 0,
 			key_len: 3,
+			value_len: ValuesLen::Variable { expected: 4 },
+			record_checksum: true,
 			..Default::default()
-		}).unwrap();
+		};
 
-		assert_eq!(*db.get("a").unwrap_err().kind(), ErrorKind::InvalidKeyLen(3, 1));
+		let mut db = Database::create(temp.path(), options()).unwrap();
+		let mut tx = db.create_transaction();
+		tx.insert("abc", "value001").unwrap();
+		tx.insert("def", "value002").unwrap();
+		db.commit(&tx).unwrap();
+		db.flush_journal(None).unwrap();
+		assert_eq!(db.get("abc").unwrap().unwrap(), b"value001");
+		drop(db);
+
+		let path = temp.path().join("data.db");
+		let mut data = fs::read(&path).unwrap();
+		let position = data.windows(4).position(|w| w == b"e002").unwrap();
+		data[position] = b'E';
+		fs::write(&path, data).unwrap();
+
+		let db = Database::open(temp.path(), options()).unwrap();
+		assert_eq!(db.get("abc").unwrap().unwrap(), b"value001");
+		assert_eq!(*db.get("def").unwrap_err().kind(), ErrorKind::CorruptedRecord(b"def".to_vec()));
+		assert_eq!(*db.get_many(&["def"]).unwrap_err().kind(), ErrorKind::CorruptedRecord(b"def".to_vec()));
+		let results: Vec<_> = db.iter().unwrap().collect();
+		assert!(results[0].is_ok());
+		assert!(results[1].is_err());
 	}
 
 	#[test]
-	fn test_same_key_operation_ordering() {
-		let temp = tempdir::TempDir::new("test_fail").unwrap();
+	fn test_corrupted_record_len() {
+		use std::fs;
 
-		let mut db = Database::create(temp.path(), Options {
+		let temp = tempdir::TempDir::new("test_corrupted_record_len").unwrap();
+		let options = || Options {
 			journal_eras: 0,
 			key_len: 3,
+			value_len: ValuesLen::Variable { expected: 4 },
 			..Default::default()
-		}).unwrap();
+		};
 
+		let mut db = Database::create(temp.path(), options()).unwrap();
 		let mut tx = db.create_transaction();
-		tx.insert("abc", "123").unwrap();
-		tx.delete("abc").unwrap();
-
+		tx.insert("abc", "value001").unwrap();
+		tx.insert("def", "value002").unwrap();
 		db.commit(&tx).unwrap();
-		db.flush_journal(1).unwrap();
+		db.flush_journal(None).unwrap();
+		drop(db);
+
+		// value length of `def` points past the end of the database
+		let path = temp.path().join("data.db");
+		let mut data = fs::read(&path).unwrap();
+		let position = data.windows(3).position(|w| w == b"def").unwrap() + 3;
+		data[position..position + 4].copy_from_slice(&[0xff; 4]);
+		fs::write(&path, data).unwrap();
+
+		let db = Database::open(temp.path(), options()).unwrap();
+		assert_eq!(db.get("abc").unwrap().unwrap(), b"value001");
+		assert_eq!(*db.get("def").unwrap_err().kind(), ErrorKind::CorruptedRecord(b"def".to_vec()));
+		assert_eq!(*db.get_many(&["def"]).unwrap_err().kind(), ErrorKind::CorruptedRecord(b"def".to_vec()));
+		let results: Vec<_> = db.iter().unwrap().collect();
+		assert!(results[0].is_ok());
+		assert!(results[1].is_err());
+		assert!(db.iter_rev().unwrap().next().unwrap().is_err());
+	}
 
-		assert_eq!(db.get("abc").unwrap(), None);
+	#[test]
+	fn test_verify() {
+		use std::fs;
+
+		let temp = tempdir::TempDir::new("test_verify").unwrap();
+		let options = || Options {
+			journal_eras: 0,
+			key_len: 3,
+			key_index_bits: 4,
+			value_len: ValuesLen::Variable { expected: 4 },
+			..Default::default()
+		};
+
+		let mut db = Database::create(temp.path(), options()).unwrap();
+		for i in 0..100u8 {
+			let mut tx = db.create_transaction();
+			tx.insert([i, i, 0], [i; 10]).unwrap();
+			if i % 3 == 0 {
+				tx.delete([i / 2, i / 2, 0]).unwrap();
+			}
+			db.commit(&tx).unwrap();
+			db.flush_journal(None).unwrap();
+		}
+
+		let report = db.verify();
+		assert!(report.is_consistent(), "{:?}", report);
+		assert_eq!(report.records, db.iter().unwrap().count() as u64);
+		assert_eq!(report.occupied_bytes, db.namespaces[0].metadata.occupied_bytes);
+		assert_eq!(*db.verify_in("missing").unwrap_err().kind(), ErrorKind::UnknownNamespace("missing".into()));
+		drop(db);
+
+		let path = temp.path().join("data.db");
+		let mut data = fs::read(&path).unwrap();
+		let position = data.windows(3).position(|w| w == [50, 50, 0]).unwrap();
+		data[position - 1] = 7;
+		fs::write(&path, data).unwrap();
+
+		let db = Database::open(temp.path(), options()).unwrap();
+		let report = db.verify();
+		assert!(report.inconsistencies.contains(&Inconsistency::InvalidHeader { offset: position - 1, header: 7 }));
+		assert!(matches!(report.inconsistencies.last(), Some(&Inconsistency::OccupiedBytesMismatch { .. })));
 	}
 
 	#[test]
-	fn test_iter() {
-		let temp = tempdir::TempDir::new("test_iter").unwrap();
+	fn test_rebuild_metadata() {
+		use std::fs;
 
-		let mut db = Database::create(temp.path(), Options {
+		let temp = tempdir::TempDir::new("test_rebuild_metadata").unwrap();
+		let options = || Options {
 			journal_eras: 0,
 			key_len: 3,
+			key_index_bits: 4,
 			value_len: ValuesLen::Constant(3),
 			..Default::default()
-		}).unwrap();
+		}.namespace("accounts", Options {
+			key_len: 2,
+			key_index_bits: 4,
+			value_len: ValuesLen::Variable { expected: 4 },
+			..Default::default()
+		});
 
-		let mut tx1 = db.create_transaction();
-		tx1.insert("abc", "123").unwrap();
-		tx1.insert("def", "467").unwrap();
-		tx1.insert("ghi", "zzz").unwrap();
+		let mut db = Database::create(temp.path(), options()).unwrap();
+		for i in 0..200u8 {
+			let mut tx = db.create_transaction();
+			tx.insert([i, 0, i], [i; 3]).unwrap();
+			tx.namespace("accounts").unwrap().insert([i, 1], vec![i; i as usize % 20]).unwrap();
+			db.commit(&tx).unwrap();
+			db.flush_journal(None).unwrap();
+		}
+		assert!(db.namespaces[0].metadata.prefixes.prefix_bits() > 4);
+		assert!(db.namespaces[1].metadata.prefixes.prefix_bits() > 4);
+		assert!(matches!(Database::rebuild_metadata(temp.path(), options()).unwrap_err().kind(), &ErrorKind::DatabaseLocked(_)));
+		drop(db);
+
+		let meta_path = temp.path().join("meta.db");
+		let namespace_meta_path = temp.path().join("namespaces").join("accounts").join("meta.db");
+		// the journal is empty, so rebuilt metadata starts counting eras from 0
+		let without_next_era = |mut meta: Vec<u8>| { meta[11..19].copy_from_slice(&[0; 8]); meta };
+		let meta = without_next_era(fs::read(&meta_path).unwrap());
+		let namespace_meta = without_next_era(fs::read(&namespace_meta_path).unwrap());
+		fs::remove_file(&meta_path).unwrap();
+		fs::write(&namespace_meta_path, vec![0xff; 3]).unwrap();
+
+		Database::rebuild_metadata(temp.path(), options()).unwrap();
+		assert_eq!(fs::read(&meta_path).unwrap(), meta);
+		assert_eq!(fs::read(&namespace_meta_path).unwrap(), namespace_meta);
+
+		let db = Database::open(temp.path(), options()).unwrap();
+		assert!(db.verify().is_consistent());
+		assert!(db.verify_in("accounts").unwrap().is_consistent());
+		assert_eq!(db.get([7, 0, 7]).unwrap().unwrap(), [7; 3]);
+		assert_eq!(db.get_in("accounts", [7, 1]).unwrap().unwrap(), [7; 7]);
+	}
 
-		db.commit(&tx1).unwrap();
-		db.flush_journal(1).unwrap();
+	#[test]
+	fn test_rebuild_metadata_misplaced_record() {
+		use std::fs;
 
-		let mut tx2 = db.create_transaction();
-		tx2.insert("jkl", "999").unwrap();
-		tx2.insert("def", "333").unwrap();
-		tx2.insert("pqr", "aaa").unwrap();
-		tx2.delete("ghi").unwrap();
+		let temp = tempdir::TempDir::new("test_rebuild_metadata_misplaced_record").unwrap();
+		let options = || Options {
+			journal_eras: 0,
+			key_len: 3,
+			key_index_bits: 4,
+			value_len: ValuesLen::Constant(3),
+			..Default::default()
+		};
 
-		db.commit(&tx2).unwrap();
+		let mut db = Database::create(temp.path(), options()).unwrap();
+		let mut tx = db.create_transaction();
+		tx.insert([0xff, 0, 0], [1; 3]).unwrap();
+		db.commit(&tx).unwrap();
+		db.flush_journal(None).unwrap();
+		drop(db);
+
+		// the data file looks like the database has grown
+		let meta_path = temp.path().join("meta.db");
+		let mut meta = fs::read(&meta_path).unwrap();
+		// the journal is empty, so rebuilt metadata starts counting eras from 0
+		meta[11..19].copy_from_slice(&[0; 8]);
+		let data = fs::OpenOptions::new().write(true).open(temp.path().join("data.db")).unwrap();
+		let len = data.metadata().unwrap().len();
+		data.set_len(len * 4).unwrap();
+		fs::write(&meta_path, vec![0xff; 3]).unwrap();
+
+		assert!(matches!(Database::rebuild_metadata(temp.path(), options()).unwrap_err().kind(), &ErrorKind::MisplacedRecord(..)));
+		assert_eq!(fs::read(&meta_path).unwrap(), vec![0xff; 3]);
+		assert!(!temp.path().join("db.flush").exists());
+
+		data.set_len(len).unwrap();
+		Database::rebuild_metadata(temp.path(), options()).unwrap();
+		assert_eq!(fs::read(&meta_path).unwrap(), meta);
+	}
 
-		let records = db.iter().unwrap().map(|item| {
-			let (k, v) = item.unwrap();
-			(::std::str::from_utf8(&k).unwrap().to_string(),
-			 ::std::str::from_utf8(&v.to_vec()).unwrap().to_string())
-		});
+	#[test]
+	fn test_migrate_v0() {
+		use std::fs;
 
-		let expected = vec![
-			("abc", "123"),
-			("def", "333"),
-			("jkl", "999"),
-			("pqr", "aaa"),
-		];
+		let temp = tempdir::TempDir::new("test_migrate_v0").unwrap();
+		let options = || Options {
+			journal_eras: 0,
+			key_len: 3,
+			value_len: ValuesLen::Constant(3),
+			..Default::default()
+		};
 
+		let mut db = Database::create(temp.path(), options()).unwrap();
+		let mut tx = db.create_transaction();
+		tx.insert("abc", "001").unwrap();
+		tx.insert("def", "002").unwrap();
+		db.commit(&tx).unwrap();
+		db.flush_journal(None).unwrap();
+		drop(db);
+
+		// version 0 didn't store prefix bits, next era and options
+		let meta_path = temp.path().join("meta.db");
+		let mut meta = fs::read(&meta_path).unwrap();
+		let mut meta_v0 = vec![0, 0];
+		meta_v0.extend_from_slice(&meta[2..10]);
+		meta_v0.extend_from_slice(&meta[39..]);
+		// migrated metadata starts counting eras from 0
+		meta[11..19].copy_from_slice(&[0; 8]);
+		fs::write(&meta_path, &meta_v0).unwrap();
+
+		// version 0 stored metadata after operations of the flush
+		let data = fs::read(temp.path().join("data.db")).unwrap();
+		let position = data.windows(6).position(|w| w == b"abc001").unwrap() + 3;
+		let mut flush = vec![0u8; 12];
+		LittleEndian::write_u64(&mut flush[..8], position as u64);
+		LittleEndian::write_u32(&mut flush[8..], 3);
+		flush.extend_from_slice(b"009");
+		flush.extend_from_slice(&meta_v0);
+		let mut flush_file = tiny_keccak::sha3_256(&flush).to_vec();
+		flush_file.extend_from_slice(&flush);
+		fs::write(temp.path().join("db.flush"), flush_file).unwrap();
+
+		assert_eq!(*Database::open_read_only(temp.path(), options()).unwrap_err().kind(), ErrorKind::UnsupportedVersion(0));
+		assert_eq!(*Database::open_with_stored_options(temp.path()).unwrap_err().kind(), ErrorKind::OptionsNotStored(0));
+
+		let db = Database::open(temp.path(), options()).unwrap();
+		assert!(!temp.path().join("db.flush").exists());
+		assert_eq!(fs::read(&meta_path).unwrap(), meta);
+		assert_eq!(db.get("abc").unwrap().unwrap(), b"009");
+		assert_eq!(db.get("def").unwrap().unwrap(), b"002");
+		assert!(db.verify().is_consistent());
+		drop(db);
+
+		// migration interrupted after its flush is written
+		fs::write(&meta_path, &meta_v0).unwrap();
+		let internal_options = InternalOptions::from_external(options()).unwrap();
+		let migrated = migration::migrate(&meta_v0, &internal_options).unwrap().unwrap();
+		Flush::with_metadata(temp.path(), migrated, Durability::None).unwrap();
+		assert_eq!(Database::stored_options(temp.path()).unwrap().options().value_len, ValuesLen::Constant(3));
+		let db = Database::open(temp.path(), options()).unwrap();
+		assert_eq!(fs::read(&meta_path).unwrap(), meta);
+		assert_eq!(db.get("abc").unwrap().unwrap(), b"009");
+		drop(db);
+
+		fs::write(&meta_path, &meta_v0[..5]).unwrap();
 		assert_eq!(
-			records.collect::<Vec<_>>(),
-			expected.iter().map(|x| (x.0.to_string(), x.1.to_string())).collect::<Vec<_>>()
+			*Database::open(temp.path(), options()).unwrap_err().kind(),
+			ErrorKind::CorruptedMetadata(format!("Expected at least {} bytes, got 5", meta_v0.len()))
+		);
+	}
+
+	#[test]
+	fn test_stored_options() {
+		let temp = tempdir::TempDir::new("test_stored_options").unwrap();
+		let options = Options {
+			journal_eras: 2,
+			key_len: 3,
+			variable_key_len: true,
+			value_len: ValuesLen::Variable { expected: 5 },
+			..Default::default()
+		};
+		let code = Options {
+			key_len: 2,
+			value_len: ValuesLen::Constant(1),
+			record_checksum: true,
+			..Default::default()
+		};
+
+		let mut db = Database::create(temp.path(), options.clone().namespace("code", code.clone())).unwrap();
+		let mut tx = db.create_transaction();
+		tx.insert("ab", "001").unwrap();
+		tx.namespace("code").unwrap().insert("ab", "c").unwrap();
+		db.commit(&tx).unwrap();
+		drop(db);
+
+		// options which don't change the layout of the database are not stored
+		let stored = Options { journal_eras: Options::default().journal_eras, ..options.clone() }.namespace("code", code.clone());
+		assert_eq!(Database::stored_options(temp.path()).unwrap(), stored);
+
+		let db = Database::open_with_stored_options(temp.path()).unwrap();
+		assert_eq!(db.get("ab").unwrap().unwrap(), b"001");
+		assert_eq!(db.get_in("code", "ab").unwrap().unwrap(), b"c");
+		drop(db);
+
+		let conflicting = Options { value_len: ValuesLen::Constant(3), ..options.clone() }.namespace("code", code.clone());
+		assert_eq!(
+			*Database::open(temp.path(), conflicting).unwrap_err().kind(),
+			ErrorKind::InvalidOptions("value_len", "Constant(3) differs from Variable { expected: 5 } the database was created with.".into())
+		);
+
+		let conflicting = options.clone().namespace("code", Options { record_checksum: false, ..code.clone() });
+		assert_eq!(
+			*Database::open(temp.path(), conflicting).unwrap_err().kind(),
+			ErrorKind::InvalidOptions("record_checksum", "false differs from true the database was created with.".into())
 		);
+
+		// options which don't change the layout of the database may differ
+		let db = Database::open(temp.path(), Options { journal_eras: 5, ..options.clone() }.namespace("code", code.clone())).unwrap();
+		assert_eq!(db.get("ab").unwrap().unwrap(), b"001");
+
+		// options are checked before the pending flush is recovered
+		Flush::with_metadata(temp.path(), (*db.namespaces[0].metadata).clone(), Durability::None).unwrap();
+		drop(db);
+		let conflicting = Options { value_len: ValuesLen::Constant(3), ..options.clone() }.namespace("code", code.clone());
+		assert!(matches!(Database::open(temp.path(), conflicting).unwrap_err().kind(), &ErrorKind::InvalidOptions("value_len", _)));
+		assert!(temp.path().join("db.flush").exists());
+		Database::open(temp.path(), options.namespace("code", code)).unwrap();
+		assert!(!temp.path().join("db.flush").exists());
+	}
+
+	#[test]
+	fn test_stats() {
+		let temp = tempdir::TempDir::new("test_stats").unwrap();
+		let mut db = Database::create(temp.path(), Options {
+			journal_eras: 2,
+			key_len: 3,
+			key_index_bits: 4,
+			value_len: ValuesLen::Constant(3),
+			..Default::default()
+		}.namespace("code", Options::default())).unwrap();
+
+		for i in 0..10u8 {
+			let mut tx = db.create_transaction();
+			tx.insert([i, 0, 0], [i; 3]).unwrap();
+			tx.insert([i, 0, 1], [i; 3]).unwrap();
+			db.commit(&tx).unwrap();
+			db.flush_journal(None).unwrap();
+		}
+
+		// the last 2 eras are not flushed
+		let stats = db.stats().unwrap();
+		// records fit in a single field
+		assert_eq!(stats.occupied_bytes, 16 * 7);
+		assert_eq!(stats.db_size, db.options.initial_db_size);
+		assert_eq!(stats.fill_ratio, stats.occupied_bytes as f64 / stats.db_size as f64);
+		assert_eq!(stats.records, 16);
+		assert_eq!(stats.prefix_bits, 4);
+		// all keys start with bits 0000
+		assert_eq!(stats.occupied_prefixes, 1);
+		assert_eq!(stats.displacement, vec![1; 16]);
+		assert_eq!(stats.journal_eras, 2);
+		assert!(stats.journal_bytes > 0);
+
+		let stats = db.stats_in("code").unwrap();
+		assert_eq!(stats.records, 0);
+		assert_eq!(stats.journal_eras, 2);
+		assert_eq!(*db.stats_in("missing").unwrap_err().kind(), ErrorKind::UnknownNamespace("missing".into()));
+	}
+
+	#[test]
+	fn test_compact() {
+		use std::fs;
+		use std::sync::Arc;
+
+		let temp = tempdir::TempDir::new("test_compact").unwrap();
+		let options = || Options {
+			journal_eras: 0,
+			key_len: 3,
+			key_index_bits: 4,
+			value_len: ValuesLen::Constant(3),
+			..Default::default()
+		};
+
+		let mut db = Database::create(temp.path(), options()).unwrap();
+		let mut tx = db.create_transaction();
+		tx.insert([0x00, 0, 0], "001").unwrap();
+		tx.insert([0x10, 0, 0], "002").unwrap();
+		tx.insert([0x10, 1, 0], "003").unwrap();
+		db.commit(&tx).unwrap();
+		db.flush_journal(None).unwrap();
+		drop(db);
+
+		// move records of the second prefix 3 fields further
+		let field_size = 7;
+		let path = temp.path().join("data.db");
+		let mut data = fs::read(&path).unwrap();
+		let records = data[field_size..3 * field_size].to_vec();
+		for byte in &mut data[field_size..3 * field_size] {
+			*byte = 0;
+		}
+		data[4 * field_size..6 * field_size].copy_from_slice(&records);
+		fs::write(&path, data).unwrap();
+
+		let mut db = Database::open(temp.path(), options()).unwrap();
+		assert_eq!(db.stats().unwrap().displacement, vec![1, 0, 0, 1, 1]);
+		assert!(db.verify().is_consistent());
+
+		db.compact().unwrap();
+		assert_eq!(db.stats().unwrap().displacement, vec![2, 1]);
+		assert!(db.verify().is_consistent());
+		assert_eq!(db.get([0x00, 0, 0]).unwrap().unwrap(), b"001");
+		assert_eq!(db.get([0x10, 0, 0]).unwrap().unwrap(), b"002");
+		assert_eq!(db.get([0x10, 1, 0]).unwrap().unwrap(), b"003");
+
+		// snapshot keeps reading records at their old locations
+		let snapshot = db.snapshot();
+		db.compact().unwrap();
+		assert_eq!(snapshot.get([0x10, 1, 0]).unwrap().unwrap(), b"003");
+		assert_eq!(db.get([0x10, 1, 0]).unwrap().unwrap(), b"003");
+		drop(snapshot);
+
+		// metadata which doesn't match the data file is reported instead of moving records
+		let mut metadata = (*db.namespaces[0].metadata).clone();
+		metadata.occupied_bytes += field_size as u64;
+		db.namespaces[0].metadata = Arc::new(metadata);
+		assert!(matches!(db.compact().unwrap_err().kind(), &ErrorKind::CorruptedMetadata(_)));
+		// partially written flush is not left behind
+		assert!(!temp.path().join("db.flush").exists());
+	}
+
+	#[test]
+	fn test_bulk_load() {
+		let temp = tempdir::TempDir::new("test_bulk_load").unwrap();
+		let mut db = Database::create(temp.path(), Options {
+			journal_eras: 0,
+			key_len: 3,
+			key_index_bits: 4,
+			value_len: ValuesLen::Variable { expected: 4 },
+			..Default::default()
+		}.namespace("code", Options {
+			key_len: 2,
+			value_len: ValuesLen::Constant(1),
+			..Default::default()
+		})).unwrap();
+
+		let records = (0..1000u32).map(|i| ([(i >> 8) as u8, i as u8, 0], vec![i as u8; i as usize % 10]));
+		db.bulk_load(records.clone()).unwrap();
+		assert!(db.namespaces[0].metadata.prefixes.prefix_bits() > 4);
+		assert!(db.verify().is_consistent());
+		assert_eq!(db.stats().unwrap().records, 1000);
+		for (key, value) in records {
+			assert_eq!(db.get(key).unwrap().unwrap(), value);
+		}
+		assert_eq!(*db.bulk_load(vec![("abc", "1")]).unwrap_err().kind(), ErrorKind::NotEmpty);
+
+		// loaded records are cleared if keys are not sorted
+		assert_eq!(*db.bulk_load_in("code", vec![("ab", "1"), ("cd", "2"), ("bc", "3")]).unwrap_err().kind(), ErrorKind::UnsortedKey(b"bc".to_vec()));
+		assert_eq!(*db.bulk_load_in("code", vec![("ab", "1"), ("ab", "2")]).unwrap_err().kind(), ErrorKind::UnsortedKey(b"ab".to_vec()));
+		assert_eq!(*db.bulk_load_in("code", vec![("ab", "1"), ("abc", "2")]).unwrap_err().kind(), ErrorKind::InvalidKeyLen(2, 3));
+		assert_eq!(db.iter_in("code").unwrap().count(), 0);
+		assert!(db.verify_in("code").unwrap().is_consistent());
+
+		let snapshot = db.snapshot_in("code").unwrap();
+		db.bulk_load_in("code", vec![("ab", "1"), ("cd", "2")]).unwrap();
+		assert_eq!(snapshot.iter().unwrap().count(), 0);
+		assert_eq!(db.get_in("code", "ab").unwrap().unwrap(), b"1");
+		assert_eq!(db.get_in("code", "cd").unwrap().unwrap(), b"2");
+		assert!(db.verify_in("code").unwrap().is_consistent());
+	}
+
+	#[test]
+	fn test_bulk_load_not_empty() {
+		use std::fs;
+		use std::io::Write;
+
+		let temp = tempdir::TempDir::new("test_bulk_load_not_empty").unwrap();
+		let options = || Options {
+			journal_eras: 1,
+			key_len: 3,
+			value_len: ValuesLen::Constant(3),
+			..Default::default()
+		};
+
+		{
+			let mut db = Database::create(temp.path(), options()).unwrap();
+			let mut tx = db.create_transaction();
+			tx.delete("abc").unwrap();
+			db.commit(&tx).unwrap();
+
+			// operations in the journal are not flushed yet
+			assert_eq!(*db.bulk_load(vec![("abc", "001")]).unwrap_err().kind(), ErrorKind::NotEmpty);
+			db.rollback(1).unwrap();
+		}
+
+		// a record left by an interrupted bulk load
+		{
+			let mut file = fs::OpenOptions::new().write(true).open(temp.path().join("data.db")).unwrap();
+			file.write_all(&[1, b'a', b'b', b'c', b'0', b'0', b'1']).unwrap();
+		}
+
+		let mut db = Database::open(temp.path(), options()).unwrap();
+		assert_eq!(db.get("abc").unwrap(), None);
+		assert_eq!(*db.bulk_load(vec![("abc", "001")]).unwrap_err().kind(), ErrorKind::NotEmpty);
 	}
 
 	#[test]
@@ -512,25 +3071,114 @@ mod tests {
 		{
 
 			// Acquire lock
-			let _db = Database::create(temp.path(), Default::default());
+			let _db = Database::create(temp.path(), Options::default());
 			// attempt to open again
 			assert!(matches!(
-				Database::open(temp.path(), Default::default()).unwrap_err().kind(),
+				Database::open(temp.path(), Options::default()).unwrap_err().kind(),
 				&ErrorKind::DatabaseLocked(_)
 			));
 		}
 
 		{
 			// Acquire lock
-			let _db = Database::open(temp.path(), Default::default());
+			let _db = Database::open(temp.path(), Options::default());
 			// attempt to create
 			assert!(matches!(
-				Database::create(temp.path(), Default::default()).unwrap_err().kind(),
+				Database::create(temp.path(), Options::default()).unwrap_err().kind(),
 				&ErrorKind::DatabaseLocked(_)
 			));
 		}
 
-		assert!(Database::open(temp.path(), Default::default()).is_ok());
+		assert!(Database::open(temp.path(), Options::default()).is_ok());
+	}
+
+	#[test]
+	fn should_grow_when_extend_threshold_is_reached() {
+		let temp = tempdir::TempDir::new("should_grow").unwrap();
+		let options = || Options {
+			journal_eras: 0,
+			extend_threshold_percent: 50,
+			key_index_bits: 4,
+			key_len: 3,
+			variable_key_len: false,
+			value_len: ValuesLen::Constant(3),
+			record_checksum: false,
+			merge_operator: None,
+			durability: Durability::default(),
+		};
+
+		let key = |i: u8| [i.wrapping_mul(37), i, 0];
+
+		{
+			let mut db = Database::create(temp.path(), options()).unwrap();
+			for chunk in 0..10u8 {
+				let mut tx = db.create_transaction();
+				for i in chunk * 10..(chunk + 1) * 10 {
+					tx.insert(key(i), [i, i, i]).unwrap();
+				}
+				db.commit(&tx).unwrap();
+				db.flush_journal(None).unwrap();
+			}
+
+			assert!(db.namespaces[0].metadata.prefixes.prefix_bits() > 4);
+			for i in 0..100u8 {
+				assert_eq!(db.get(key(i)).unwrap().unwrap(), [i, i, i]);
+			}
+		}
+
+		let db = Database::open(temp.path(), options()).unwrap();
+		assert!(db.namespaces[0].metadata.prefixes.prefix_bits() > 4);
+		assert_eq!(db.iter().unwrap().count(), 100);
+		for i in 0..100u8 {
+			assert_eq!(db.get(key(i)).unwrap().unwrap(), [i, i, i]);
+		}
+	}
+
+	#[test]
+	fn should_store_records_in_overflow_region() {
+		let temp = tempdir::TempDir::new("overflow_region").unwrap();
+		let options = || Options {
+			journal_eras: 0,
+			extend_threshold_percent: 100,
+			key_index_bits: 4,
+			key_len: 3,
+			variable_key_len: false,
+			value_len: ValuesLen::Constant(3),
+			record_checksum: false,
+			merge_operator: None,
+			durability: Durability::default(),
+		};
+
+		{
+			let mut db = Database::create(temp.path(), options()).unwrap();
+			let initial_len = db.namespaces[0].mmap.len();
+
+			// all keys have the last prefix
+			let mut tx = db.create_transaction();
+			for i in 0..100u8 {
+				tx.insert([0xff, i, 0], [i, i, i]).unwrap();
+			}
+			db.commit(&tx).unwrap();
+			db.flush_journal(None).unwrap();
+
+			assert!(db.namespaces[0].mmap.len() > initial_len);
+			assert_eq!(db.get([0xff, 100, 0]).unwrap(), None);
+
+			let mut tx = db.create_transaction();
+			tx.delete([0xff, 0, 0]).unwrap();
+			tx.insert([0xff, 200, 0], [1, 2, 3]).unwrap();
+			db.commit(&tx).unwrap();
+			db.flush_journal(None).unwrap();
+		}
+
+		let db = Database::open(temp.path(), options()).unwrap();
+		assert_eq!(db.get([0xff, 0, 0]).unwrap(), None);
+		for i in 1..100u8 {
+			assert_eq!(db.get([0xff, i, 0]).unwrap().unwrap(), [i, i, i]);
+		}
+		assert_eq!(db.get([0xff, 200, 0]).unwrap().unwrap(), [1, 2, 3]);
+		assert_eq!(db.iter().unwrap().count(), 100);
+		assert_eq!(db.iter_rev().unwrap().count(), 100);
 	}
 
 	quickcheck! {
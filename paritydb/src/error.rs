@@ -5,6 +5,7 @@ use std::{io, num};
 use std::path::PathBuf;
 
 use field;
+use metadata::Metadata;
 
 error_chain! {
 	links {
@@ -29,6 +30,27 @@ error_chain! {
 			description("Hash of journal data is invalid"),
 			display("Database journal corruption detected in file at {}. {}", path.display(), msg),
 		}
+		CorruptedRecord(key: Vec<u8>) {
+			description("Checksum of a record is invalid"),
+			display("Database record corruption detected for key {:?}", key),
+		}
+		InvalidRecord {
+			description("Record is invalid"),
+			display("Database record corruption detected. Key of a record doesn't fit in its data."),
+		}
+		CorruptedMetadata(msg: String) {
+			description("Metadata is invalid"),
+			display("Database metadata corruption detected. {}", msg),
+		}
+		UnsupportedVersion(version: u16) {
+			description("Database version is not supported"),
+			display("Database version {} is not supported. The latest supported version is {}.", version, Metadata::DB_VERSION),
+		}
+		OptionsNotStored(version: u16) {
+			description("Database version does not store options"),
+			display("Database version {} does not store options it was created with. \
+					 Open it with given options to upgrade it.", version),
+		}
 		InvalidJournalLocation(path: PathBuf) {
 			description("Path to journal is a file"),
 			display("Expected a directory at {}, got file.", path.display()),
@@ -37,10 +59,59 @@ error_chain! {
 			description("Eras are not consecutive"),
 			display("Missing era file with index {}", idx),
 		}
+		InvalidRollback(requested: usize, available: usize) {
+			description("Cannot rollback more eras than there are in the journal"),
+			display("Cannot rollback {} eras. There are only {} eras in the journal.", requested, available),
+		}
 		InvalidOptions(field: &'static str, error: String) {
 			description("Invalid options were provided"),
 			display("Invalid value of `{}`: {}", field, error),
 		}
+		ConditionFailed(key: Vec<u8>) {
+			description("Precondition of a conditional operation does not hold"),
+			display("Precondition of a conditional operation does not hold for key {:?}", key),
+		}
+		UnsortedKey(key: Vec<u8>) {
+			description("Keys are not strictly ascending"),
+			display("Key {:?} is not greater than the previous key", key),
+		}
+		NotEmpty {
+			description("Database is not empty"),
+			display("Database is not empty. Records can be loaded only to an empty database."),
+		}
+		FlushedDataInUse {
+			description("Flushed data is in use"),
+			display("Flushed data is read by other processes."),
+		}
+		FlushedDataChanged {
+			description("Flushed data has changed"),
+			display("Flushed data was modified by the writer since it was loaded. It's loaded again by `reload_journal`."),
+		}
+		MissingMergeOperator {
+			description("Merge operator is not set"),
+			display("Values can't be merged, cause merge operator is not set in options."),
+		}
+		ForeignTransaction {
+			description("Transaction was prepared for another journal"),
+			display("Transaction was prepared for another database and can't be applied to this one."),
+		}
+		InvalidMergedValueLen(key: Vec<u8>, expected: usize, got: usize) {
+			description("Merged value has invalid length"),
+			display("Merge operator returned a value of length {} for key {:?}. Expected: {}", got, key, expected),
+		}
+		MisplacedRecord(offset: usize, min_offset: usize) {
+			description("Record is stored before the offset of its prefix"),
+			display("Record at offset {} is stored before the lowest offset it may have: {}. \
+					 Number of prefix bits of the database can't be determined.", offset, min_offset),
+		}
+		UnknownNamespace(name: String) {
+			description("Namespace does not exist"),
+			display("Namespace {} does not exist", name),
+		}
+		ReadOnly {
+			description("Database is opened in read-only mode"),
+			display("Database is opened in read-only mode and can't be modified."),
+		}
 		DatabaseLocked(path: PathBuf) {
 			description("Database file lock is currently acquired"),
 			display("Could not acquire database file lock: {}. \
@@ -59,12 +130,39 @@ impl PartialEq for ErrorKind {
 				if expected == expected2 && got == got2 => true,
 			(&CorruptedJournal(ref path, ref msg), &CorruptedJournal(ref path2, ref msg2))
 				if path == path2 && msg == msg2 => true,
+			(&CorruptedRecord(ref key), &CorruptedRecord(ref key2))
+				if key == key2 => true,
+			(&InvalidRecord, &InvalidRecord) => true,
+			(&CorruptedMetadata(ref msg), &CorruptedMetadata(ref msg2))
+				if msg == msg2 => true,
+			(&UnsupportedVersion(version), &UnsupportedVersion(version2))
+				if version == version2 => true,
+			(&OptionsNotStored(version), &OptionsNotStored(version2))
+				if version == version2 => true,
 			(&InvalidJournalLocation(ref path), &InvalidJournalLocation(ref path2))
 				if path == path2 => true,
 			(&JournalEraMissing(idx), &JournalEraMissing(idx2))
 				if idx == idx2 => true,
+			(&InvalidRollback(requested, available), &InvalidRollback(requested2, available2))
+				if requested == requested2 && available == available2 => true,
 			(&InvalidOptions(field, ref error), &InvalidOptions(field2, ref error2))
 				if field == field2 && error == error2 => true,
+			(&ConditionFailed(ref key), &ConditionFailed(ref key2))
+				if key == key2 => true,
+			(&UnsortedKey(ref key), &UnsortedKey(ref key2))
+				if key == key2 => true,
+			(&NotEmpty, &NotEmpty) => true,
+			(&FlushedDataInUse, &FlushedDataInUse) => true,
+			(&FlushedDataChanged, &FlushedDataChanged) => true,
+			(&MissingMergeOperator, &MissingMergeOperator) => true,
+			(&ForeignTransaction, &ForeignTransaction) => true,
+			(&InvalidMergedValueLen(ref key, expected, got), &InvalidMergedValueLen(ref key2, expected2, got2))
+				if key == key2 && expected == expected2 && got == got2 => true,
+			(&MisplacedRecord(offset, min_offset), &MisplacedRecord(offset2, min_offset2))
+				if offset == offset2 && min_offset == min_offset2 => true,
+			(&UnknownNamespace(ref name), &UnknownNamespace(ref name2))
+				if name == name2 => true,
+			(&ReadOnly, &ReadOnly) => true,
 			_ => false,
 		}
 	}
@@ -15,5 +15,9 @@ error_chain! {
 			description("invalid length"),
 			display("invalid length"),
 		}
+		InvalidRecord {
+			description("invalid record"),
+			display("record can't hold its key and value"),
+		}
 	}
 }
@@ -39,7 +39,7 @@ macro_rules! on_body_slice {
 }
 
 /// A view onto multiple consecutive fields
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct FieldsView<'a> {
 	data: &'a [u8],
 	field_body_size: usize,
@@ -161,6 +161,36 @@ impl<'a> FieldsView<'a> {
 		}
 	}
 
+	/// Returns continuous part of the view starting at `pos`.
+	/// It ends at the end of the field or at the end of the view.
+	pub fn chunk_at(&self, pos: usize) -> &'a [u8] {
+		assert!(self.len >= pos, "Cannot read beyond length: {} < {}", self.len, pos);
+		if self.len == pos {
+			return &[];
+		}
+
+		let body_pos = self.offset + pos;
+		let start = body_pos + HEADER_SIZE * (body_pos / self.field_body_size) + HEADER_SIZE;
+		let len = cmp::min(self.field_body_size - body_pos % self.field_body_size, self.len - pos);
+		&self.data[start..start + len]
+	}
+
+	/// Copy field content starting at `pos` to given slice.
+	///
+	/// Returns number of copied bytes, which is lower than the length of the slice
+	/// only if the end of the view is reached.
+	pub fn read_at(&self, pos: usize, slice: &mut [u8]) -> usize {
+		let mut read = 0;
+		while read < slice.len() && pos + read < self.len {
+			let chunk = self.chunk_at(pos + read);
+			let len = cmp::min(chunk.len(), slice.len() - read);
+			slice[read..read + len].copy_from_slice(&chunk[..len]);
+			read += len;
+		}
+
+		read
+	}
+
 	/// Copy field content to given slice.
 	///
 	/// Panics if the lengths don't match.
@@ -349,6 +379,41 @@ mod tests {
 		assert_eq!(expected_value, result_value);
 	}
 
+	#[test]
+	fn test_fields_view_chunk_at() {
+		let body_size = 3;
+		let data = [0, 1, 2, 3, 0, 4, 5, 6, 0, 7, 8, 9];
+		let fv = FieldsView::new(&data, body_size);
+		let (_, value) = fv.split_at(2);
+		let (value, _) = value.split_at(6);
+
+		assert_eq!(value.chunk_at(0), &[3]);
+		assert_eq!(value.chunk_at(1), &[4, 5, 6]);
+		assert_eq!(value.chunk_at(2), &[5, 6]);
+		assert_eq!(value.chunk_at(4), &[7, 8]);
+		assert_eq!(value.chunk_at(5), &[8]);
+		assert_eq!(value.chunk_at(6), &[] as &[u8]);
+	}
+
+	#[test]
+	fn test_fields_view_read_at() {
+		let body_size = 3;
+		let data = [0, 1, 2, 3, 0, 4, 5, 6, 0, 7, 8, 9];
+		let fv = FieldsView::new(&data, body_size);
+		let (_, value) = fv.split_at(2);
+
+		let mut result = [0u8; 5];
+		assert_eq!(value.read_at(1, &mut result), 5);
+		assert_eq!(result, [4, 5, 6, 7, 8]);
+
+		let mut result = [0u8; 5];
+		assert_eq!(value.read_at(5, &mut result), 2);
+		assert_eq!(result, [8, 9, 0, 0, 0]);
+
+		assert_eq!(value.read_at(7, &mut result), 0);
+		assert_eq!(value.read_at(10, &mut result), 0);
+	}
+
 	#[test]
 	fn test_fields_view_iter() {
 		let body_size = 3;
@@ -1,9 +1,9 @@
 use std::cmp;
 
 use field::iterator::FieldHeaderIterator;
-use field::{Error, Header, field_size};
-use prefix_tree::OccupiedPrefixesIterator;
-use record::{ValueSize, Record};
+use field::{Error, ErrorKind, Header, field_size};
+use prefix_tree::{OccupiedPrefixesIterator, PrefixTree};
+use record::{KeySize, ValueSize, Record};
 
 /// Record location.
 #[derive(Debug)]
@@ -14,12 +14,16 @@ pub enum RecordResult<'a> {
 	NotFound,
 	/// Record does no exist in this memory slice, but may in the next one
 	OutOfRange,
+	/// Record which might have the key can't hold its key and value.
+	Corrupted,
 }
 
 pub fn find_record<'a>(
 	data: &'a [u8],
 	field_body_size: usize,
+	key_size: KeySize,
 	value_size: ValueSize,
+	has_checksum: bool,
 	key: &[u8],
 ) -> Result<RecordResult<'a>, Error> {
 	let iter = FieldHeaderIterator::new(data, field_body_size)?;
@@ -32,10 +36,13 @@ pub fn find_record<'a>(
 			Header::Uninitialized => return Ok(RecordResult::NotFound),
 			Header::Inserted => {
 				let slice = &data[offset..];
-				match Record::extract_key(slice, field_body_size, key.len()).partial_cmp(&key).unwrap() {
+				if !Record::has_valid_layout(slice, field_body_size, value_size, key_size, has_checksum) {
+					return Ok(RecordResult::Corrupted);
+				}
+				match Record::extract_key(slice, key_size).expect("layout has been validated; qed").cmp(key) {
 					cmp::Ordering::Less => {},
 					cmp::Ordering::Equal => {
-						let record = Record::new(slice, field_body_size, value_size, key.len());
+						let record = Record::new(slice, field_body_size, value_size, key_size, has_checksum);
 						return Ok(RecordResult::Found(record));
 					},
 					cmp::Ordering::Greater => return Ok(RecordResult::NotFound),
@@ -52,14 +59,15 @@ pub fn iter<'a>(
 	data: &'a [u8],
 	occupied_offset_iter: OccupiedPrefixesIterator<'a>,
 	field_body_size: usize,
-	key_size: usize,
-	value_size: ValueSize
+	key_size: KeySize,
+	value_size: ValueSize,
+	has_checksum: bool,
 ) -> Result<RecordIterator<'a>, Error> {
 	let offset = 0;
 	let peek_offset = None;
 	let field_size = field_size(field_body_size);
 
-	Ok(RecordIterator { data, occupied_offset_iter, offset, peek_offset, field_body_size, field_size, key_size, value_size })
+	Ok(RecordIterator { data, occupied_offset_iter, offset, peek_offset, field_body_size, field_size, key_size, value_size, has_checksum })
 }
 
 pub struct RecordIterator<'a, T = OccupiedPrefixesIterator<'a>> {
@@ -69,8 +77,9 @@ pub struct RecordIterator<'a, T = OccupiedPrefixesIterator<'a>> {
 	peek_offset: Option<u32>,
 	field_body_size: usize,
 	field_size: usize,
-	key_size: usize,
-	value_size: ValueSize
+	key_size: KeySize,
+	value_size: ValueSize,
+	has_checksum: bool,
 }
 
 impl<'a, T: Iterator<Item=u32>> Iterator for RecordIterator<'a, T> {
@@ -114,7 +123,10 @@ impl<'a, T: Iterator<Item=u32>> Iterator for RecordIterator<'a, T> {
 						},
 						Header::Inserted => {
 							self.peek_offset = Some(offset + 1);
-							let record = Record::new(slice, self.field_body_size, self.value_size, self.key_size);
+							if !Record::has_valid_layout(slice, self.field_body_size, self.value_size, self.key_size, self.has_checksum) {
+								return Some(Err(ErrorKind::InvalidRecord.into()));
+							}
+							let record = Record::new(slice, self.field_body_size, self.value_size, self.key_size, self.has_checksum);
 							return Some(Ok(record))
 						}
 					}
@@ -125,9 +137,115 @@ impl<'a, T: Iterator<Item=u32>> Iterator for RecordIterator<'a, T> {
 	}
 }
 
+/// Returns an iterator over records in descending order of their keys.
+///
+/// If `end_prefix` is given, iteration starts at the end of the run of records
+/// which contains that prefix. Records with lower keys are never stored after it.
+pub fn iter_rev<'a>(
+	data: &'a [u8],
+	prefixes: &'a PrefixTree,
+	end_prefix: Option<u32>,
+	field_body_size: usize,
+	key_size: KeySize,
+	value_size: ValueSize,
+	has_checksum: bool,
+) -> Result<RecordRevIterator<'a>, Error> {
+	let field_size = field_size(field_body_size);
+	let fields = (data.len() / field_size) as u32;
+
+	let mut iter = RecordRevIterator { data, prefixes, offset: fields, in_run: false, field_body_size, field_size, key_size, value_size, has_checksum };
+	if let Some(prefix) = end_prefix {
+		iter.offset = iter.run_end(cmp::min(prefix, fields))?;
+		iter.in_run = true;
+	}
+
+	Ok(iter)
+}
+
+/// Walks fields backwards, run by run.
+///
+/// A run is a continuous sequence of occupied fields. Runs are found using
+/// occupied prefixes, cause every record is stored either at the offset of its prefix
+/// or right after the previous record.
+pub struct RecordRevIterator<'a> {
+	data: &'a [u8],
+	prefixes: &'a PrefixTree,
+	/// All the fields at and after this offset have already been visited.
+	offset: u32,
+	/// Is the field before `offset` part of the current run.
+	in_run: bool,
+	field_body_size: usize,
+	field_size: usize,
+	key_size: KeySize,
+	value_size: ValueSize,
+	has_checksum: bool,
+}
+
+impl<'a> RecordRevIterator<'a> {
+	fn header(&self, offset: u32) -> Result<Header, Error> {
+		Header::from_u8(self.data[offset as usize * self.field_size])
+	}
+
+	/// Returns the offset of the first empty field at or after `offset`.
+	fn run_end(&self, mut offset: u32) -> Result<u32, Error> {
+		let fields = (self.data.len() / self.field_size) as u32;
+		while offset < fields && self.header(offset)? != Header::Uninitialized {
+			offset += 1;
+		}
+
+		Ok(offset)
+	}
+}
+
+impl<'a> Iterator for RecordRevIterator<'a> {
+	type Item = Result<Record<'a>, Error>;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		loop {
+			if !self.in_run {
+				// the last run before `offset` contains records of the last occupied prefix
+				let prefix = self.prefixes.prev_occupied(self.offset)?;
+				self.offset = match self.run_end(prefix) {
+					Ok(offset) => offset,
+					Err(err) => return Some(Err(err)),
+				};
+				self.in_run = true;
+			}
+
+			if self.offset == 0 {
+				self.in_run = false;
+				return None;
+			}
+
+			let offset = self.offset - 1;
+			let header = match self.header(offset) {
+				Ok(header) => header,
+				Err(err) => return Some(Err(err)),
+			};
+
+			self.offset = offset;
+			match header {
+				Header::Uninitialized => {
+					self.in_run = false;
+				},
+				Header::Continued => {},
+				Header::Inserted => {
+					let slice = &self.data[offset as usize * self.field_size..];
+					if !Record::has_valid_layout(slice, self.field_body_size, self.value_size, self.key_size, self.has_checksum) {
+						return Some(Err(ErrorKind::InvalidRecord.into()));
+					}
+					let record = Record::new(slice, self.field_body_size, self.value_size, self.key_size, self.has_checksum);
+					return Some(Ok(record));
+				},
+			}
+		}
+	}
+}
+
 #[cfg(test)]
 mod tests {
-	use super::{find_record, RecordIterator, RecordResult};
+	use super::{find_record, iter_rev, RecordIterator, RecordResult};
+	use prefix_tree::PrefixTree;
 	use record;
 
 	fn expect_record(a: RecordResult, key: &[u8], value: &[u8]) {
@@ -147,7 +265,7 @@ mod tests {
 	fn assert_eq(a: RecordResult, b: RecordResult) {
 		match (a, b) {
 			(RecordResult::NotFound, RecordResult::NotFound) => return,
-			(RecordResult::OutOfRange, RecordResult::OutOfRange) => return,
+			(RecordResult::OutOfRange, RecordResult::OutOfRange) | (RecordResult::Corrupted, RecordResult::Corrupted) => return,
 			(RecordResult::Found(_), RecordResult::Found(_)) => unimplemented!(),
 			(a, b) => {
 				assert!(false, "Invalid record result. Expected: {:?}, got: {:?}", a, b);
@@ -157,40 +275,44 @@ mod tests {
 
 	#[test]
 	fn test_find_record() {
+		let key_size = record::KeySize::Constant(3);
 		let value_size = record::ValueSize::Constant(0);
 		let body_size = 3;
 		let data = [1, 1, 2, 3, 1, 4, 5, 6];
 		let key = [1, 2, 3];
 		let key2 = [4, 5, 6];
 
-		expect_record(find_record(&data, body_size, value_size, &key).unwrap(), &[1, 2, 3], &[]);
-		expect_record(find_record(&data, body_size, value_size, &key2).unwrap(), &[4, 5, 6], &[]);
+		expect_record(find_record(&data, body_size, key_size, value_size, false, &key).unwrap(), &[1, 2, 3], &[]);
+		expect_record(find_record(&data, body_size, key_size, value_size, false, &key2).unwrap(), &[4, 5, 6], &[]);
 	}
 
 	#[test]
 	fn test_find_not_found_record_location_for_reading() {
+		let key_size = record::KeySize::Constant(3);
 		let value_size = record::ValueSize::Constant(0);
 		let body_size = 3;
 		let data = [1, 1, 2, 3, 1, 4, 5, 6];
 		let key = [1, 4, 5];
 		let location = RecordResult::NotFound;
 
-		assert_eq(location, find_record(&data, body_size, value_size, &key).unwrap());
+		assert_eq(location, find_record(&data, body_size, key_size, value_size, false, &key).unwrap());
 	}
 
 	#[test]
 	fn test_find_out_of_range_record_location_for_reading() {
+		let key_size = record::KeySize::Constant(3);
 		let value_size = record::ValueSize::Constant(0);
 		let body_size = 3;
 		let data = [1, 1, 2, 3, 1, 4, 5, 6];
 		let key = [4, 5, 7];
 		let location = RecordResult::OutOfRange;
 
-		assert_eq(location, find_record(&data, body_size, value_size, &key).unwrap());
+		assert_eq(location, find_record(&data, body_size, key_size, value_size, false, &key).unwrap());
 	}
 
 	#[test]
 	fn test_find_uninitialized_record_location_for_reading() {
+		let key_size = record::KeySize::Constant(3);
 		let value_size = record::ValueSize::Constant(0);
 		let body_size = 3;
 		let data = [0, 1, 2, 3, 1, 4, 5, 6];
@@ -199,8 +321,8 @@ mod tests {
 		let location = RecordResult::NotFound;
 		let location2 = RecordResult::NotFound;
 
-		assert_eq(location, find_record(&data, body_size, value_size, &key).unwrap());
-		assert_eq(location2, find_record(&data, body_size, value_size, &key2).unwrap());
+		assert_eq(location, find_record(&data, body_size, key_size, value_size, false, &key).unwrap());
+		assert_eq(location2, find_record(&data, body_size, key_size, value_size, false, &key2).unwrap());
 	}
 
 	#[test]
@@ -212,7 +334,7 @@ mod tests {
 		let peek_offset = None;
 		let field_body_size = 2;
 		let field_size = 3;
-		let key_size = 2;
+		let key_size = record::KeySize::Constant(2);
 		let value_size = record::ValueSize::Constant(0);
 
 		let records = RecordIterator {
@@ -224,6 +346,7 @@ mod tests {
 			field_size,
 			key_size,
 			value_size,
+			has_checksum: false,
 		};
 
 		let keys: Vec<_> = records.map(|record| {
@@ -242,4 +365,60 @@ mod tests {
 			]
 		);
 	}
+
+	#[test]
+	fn test_iter_rev() {
+		// records spanning two fields, some of them shifted past their prefixes
+		let data = &[
+			1, 1, 1, 2, 9, 9,
+			0, 0, 0,
+			1, 2, 2, 2, 8, 8,
+			1, 3, 3, 2, 7, 7,
+			0, 0, 0, 0, 0, 0,
+			1, 4, 4, 2, 6, 6,
+			1, 5, 5, 2, 5, 5,
+		];
+		let mut prefixes = PrefixTree::new(4);
+		prefixes.insert(0);
+		prefixes.insert(3);
+		prefixes.insert(4);
+		prefixes.insert(9);
+		prefixes.insert(10);
+
+		let field_body_size = 2;
+		let key_size = record::KeySize::Constant(2);
+		let value_size = record::ValueSize::Constant(2);
+
+		let keys = |end_prefix| {
+			iter_rev(data, &prefixes, end_prefix, field_body_size, key_size, value_size, false).unwrap().map(|record| {
+				let record = record.unwrap();
+				record.key().to_vec()
+			}).collect::<Vec<_>>()
+		};
+
+		assert_eq!(keys(None), vec![vec![5, 5], vec![4, 4], vec![3, 3], vec![2, 2], vec![1, 1]]);
+		assert_eq!(keys(Some(8)), vec![vec![3, 3], vec![2, 2], vec![1, 1]]);
+		assert_eq!(keys(Some(6)), vec![vec![3, 3], vec![2, 2], vec![1, 1]]);
+		assert_eq!(keys(Some(4)), vec![vec![3, 3], vec![2, 2], vec![1, 1]]);
+		assert_eq!(keys(Some(1)), vec![vec![1, 1]]);
+	}
+
+	#[test]
+	fn test_find_variable_size_key_record() {
+		let key_size = record::KeySize::Variable;
+		let value_size = record::ValueSize::Constant(1);
+		let body_size = 4;
+		// keys are ordered lexicographically regardless of their length
+		let data = [1, 1, 1, 7, 0, 1, 2, 1, 2, 8, 1, 1, 2, 9, 0];
+
+		expect_record(find_record(&data, body_size, key_size, value_size, false, &[1]).unwrap(), &[1], &[7]);
+		expect_record(find_record(&data, body_size, key_size, value_size, false, &[1, 2]).unwrap(), &[1, 2], &[8]);
+		expect_record(find_record(&data, body_size, key_size, value_size, false, &[2]).unwrap(), &[2], &[9]);
+		assert_eq(RecordResult::NotFound, find_record(&data, body_size, key_size, value_size, false, &[1, 1]).unwrap());
+		assert_eq(RecordResult::OutOfRange, find_record(&data, body_size, key_size, value_size, false, &[3]).unwrap());
+
+		// key length doesn't fit in the field
+		let data = [1, 9, 1, 7, 0];
+		assert_eq(RecordResult::Corrupted, find_record(&data, body_size, key_size, value_size, false, &[1]).unwrap());
+	}
 }
@@ -1,13 +1,15 @@
 //! Decision
 //!
 //! Our database supports two types of operations. Inserts and deletes.
+//! Merge operands are folded into inserts before decisions are made.
 //! This module is responsible for comparing existing records with new operations
 //! and making decisions based on the result of this comparison. The decision is
 //! later used to create idempotent database operation.
 
 use std::cmp;
+use error::Result;
 use key::Key;
-use record::Record;
+use record::{KeySize, Record};
 use space::Space;
 use transaction::Operation;
 
@@ -63,8 +65,8 @@ pub enum Decision<'o, 'db> {
 
 /// Compares occupied space data and operation key.
 #[inline]
-fn compare_space_and_operation(space: &[u8], key: &[u8], field_body_size: usize) -> cmp::Ordering {
-	Record::extract_key(space, field_body_size, key.len()).partial_cmp(&key).expect("extract key size is equal to key.len(); qed")
+fn compare_space_and_operation(space: &[u8], key: &[u8], key_size: KeySize) -> Result<cmp::Ordering> {
+	Ok(Record::extract_key(space, key_size)?.cmp(key))
 }
 
 #[inline]
@@ -77,12 +79,9 @@ pub fn is_min_offset_for_key(offset: usize, shift: isize, key: &[u8], prefix_bit
 }
 
 #[inline]
-pub fn is_min_offset_for_space(offset: usize, shift: isize, data: &[u8], prefix_bits: u8, field_body_size: usize) -> bool {
-	let key_prefix_len = (prefix_bits as usize + 7) / 8;
-	let view = Record::extract_key(data, field_body_size, key_prefix_len);
-	let mut prefix = [0u8; 4];
-	view.copy_to_slice(&mut prefix[..key_prefix_len]);
-	is_min_offset_for_key(offset, shift, &prefix, prefix_bits, field_body_size)
+pub fn is_min_offset_for_space(offset: usize, shift: isize, data: &[u8], prefix_bits: u8, field_body_size: usize, key_size: KeySize) -> Result<bool> {
+	let key = Record::extract_key(data, key_size)?;
+	Ok(is_min_offset_for_key(offset, shift, key, prefix_bits, field_body_size))
 }
 
 #[derive(Debug, PartialEq, Clone, Copy)]
@@ -102,9 +101,9 @@ impl From<isize> for Shift {
 	}
 }
 
-pub fn decision<'o, 'db>(operation: Operation<'o>, space: Space<'db>, shift: isize, field_body_size: usize, prefix_bits: u8) -> Decision<'o, 'db> {
+pub fn decision<'o, 'db>(operation: Operation<'o>, space: Space<'db>, shift: isize, field_body_size: usize, prefix_bits: u8, key_size: KeySize) -> Result<Decision<'o, 'db>> {
 	let tip = shift.into();
-	match (operation, space, tip) {
+	let decision = match (operation, space, tip) {
 		(Operation::Insert(key, value), Space::Empty(space), Shift::None) => Decision::InsertOperationIntoEmptySpace {
 			key,
 			value,
@@ -125,9 +124,9 @@ pub fn decision<'o, 'db>(operation: Operation<'o>, space: Space<'db>, shift: isi
 			len: space.len,
 		},
 		(Operation::Insert(key, value), Space::Occupied(space), _) => {
-			match (compare_space_and_operation(space.data, key, field_body_size), tip) {
+			match (compare_space_and_operation(space.data, key, key_size)?, tip) {
 				(cmp::Ordering::Less, Shift::None) => Decision::SeekSpace,
-				(cmp::Ordering::Less, Shift::Backward) => if is_min_offset_for_space(space.offset, shift, space.data, prefix_bits, field_body_size) {
+				(cmp::Ordering::Less, Shift::Backward) => if is_min_offset_for_space(space.offset, shift, space.data, prefix_bits, field_body_size, key_size)? {
 					Decision::ShiftOccupiedSpace {
 						data: space.data,
 					}
@@ -172,9 +171,9 @@ pub fn decision<'o, 'db>(operation: Operation<'o>, space: Space<'db>, shift: isi
 			Decision::IgnoreOperation
 		},
 		(Operation::Delete(key), Space::Occupied(space), _) => {
-			match (compare_space_and_operation(space.data, key, field_body_size), tip) {
+			match (compare_space_and_operation(space.data, key, key_size)?, tip) {
 				(cmp::Ordering::Less, Shift::None) => Decision::SeekSpace,
-				(cmp::Ordering::Less, Shift::Backward) => if is_min_offset_for_space(space.offset, shift, space.data, prefix_bits, field_body_size) {
+				(cmp::Ordering::Less, Shift::Backward) => if is_min_offset_for_space(space.offset, shift, space.data, prefix_bits, field_body_size, key_size)? {
 					Decision::ShiftOccupiedSpace {
 						data: space.data,
 					}
@@ -192,5 +191,8 @@ pub fn decision<'o, 'db>(operation: Operation<'o>, space: Space<'db>, shift: isi
 				(cmp::Ordering::Greater, _) => Decision::IgnoreOperation,
 			}
 		},
-	}
+		(Operation::Merge(..), _, _) => unreachable!("merge operands are folded into inserts before decisions are made; qed"),
+	};
+
+	Ok(decision)
 }
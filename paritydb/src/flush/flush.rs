@@ -1,6 +1,6 @@
-use std::io::{self, Write};
+use std::io::{self, Read, Write};
 use std::path::{Path, PathBuf};
-use std::{mem, fs};
+use std::fs;
 
 use hex_slice::AsHex;
 use memmap::{Mmap, Protection};
@@ -8,18 +8,66 @@ use tiny_keccak::{sha3_256, Keccak};
 
 use error::{ErrorKind, Result};
 use flush::iterator::IdempotentOperationIterator;
+use flush::relayout::{record_prefix, relayout};
 use flush::writer::OperationWriter;
 use metadata::{self, Metadata};
-use options::InternalOptions;
+use options::{Durability, InternalOptions};
+use space::{Space, SpaceIterator};
 use transaction::Operation;
 
+/// Returns minimal length of the database required by idempotent operations.
+fn required_db_len(operations: &[u8]) -> u64 {
+	IdempotentOperationIterator::new(operations)
+		.map(|o| (o.offset + o.data.len()) as u64)
+		.max()
+		.unwrap_or(0)
+}
+
+/// Maps the flush file at `path` if it exists and checks its checksum.
+fn open_checked(path: &Path) -> Result<Option<Mmap>> {
+	let mmap = match Mmap::open_path(path, Protection::Read) {
+		Ok(mmap) => mmap,
+		Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+		Err(err) => return Err(err.into()),
+	};
+
+	if mmap.len() < Flush::CHECKSUM_SIZE {
+		bail!(ErrorKind::CorruptedFlush(path.to_owned(), "File is too short".into()));
+	}
+
+	{
+		let checksum = unsafe { &mmap.as_slice()[..Flush::CHECKSUM_SIZE] };
+		let data = unsafe { &mmap.as_slice()[Flush::CHECKSUM_SIZE..] };
+		let hash = sha3_256(data);
+		if hash != checksum {
+			return Err(ErrorKind::CorruptedFlush(
+				path.to_owned(),
+				format!(
+					"Expected: {:02x}, Got: {:02x}",
+					hash.as_hex(),
+					checksum.as_hex(),
+				)
+			).into());
+		}
+	}
+
+	Ok(Some(mmap))
+}
+
 /// Stores transaction operations as a set of idempotent operations.
+///
+/// ```text
+///  checksum   metadata   idempotent operations
+///   /          /          /
+/// |...|..........|...................|
+/// ```
 #[derive(Debug)]
 pub struct Flush {
 	path: PathBuf,
 	mmap: Mmap,
-	prefix_bits: u8,
 	metadata: Metadata,
+	db_len: u64,
+	durability: Durability,
 }
 
 impl Flush {
@@ -42,87 +90,267 @@ impl Flush {
 			operations.into_iter(),
 			db,
 			&mut metadata,
-			options.field_body_size,
-			options.external.key_index_bits,
-			options.external.value_len.is_const(),
+			options,
 		).run()?;
 
-		let path = dir.as_ref().join(Flush::FILE_NAME);
+		Self::create(dir, &flush_data, metadata, options.external.durability)
+	}
+
+	/// Creates memmap with idempotent operations which move all the records
+	/// to the locations determined by new `prefix_bits`.
+	///
+	/// The database needs to be resized before the flush is applied.
+	pub fn relayout<P: AsRef<Path>>(
+		dir: P,
+		options: &InternalOptions,
+		db: &[u8],
+		metadata: &Metadata,
+		prefix_bits: u8,
+	) -> Result<Flush> {
+		let mut new_metadata = Metadata::new(&metadata.options, prefix_bits);
+		new_metadata.next_era = metadata.next_era;
+		// operations of all the records are written directly to the file
+		Self::write(dir, prefix_bits, options.external.durability, |writer| {
+			relayout(writer, db, &mut new_metadata, options.field_body_size, options.key_size)?;
+			// records are only moved, so they occupy as many bytes as before unless the metadata is corrupted
+			if metadata.occupied_bytes != new_metadata.occupied_bytes {
+				bail!(ErrorKind::CorruptedMetadata(format!(
+					"Expected {} occupied bytes, found {}",
+					metadata.occupied_bytes,
+					new_metadata.occupied_bytes,
+				)));
+			}
+			Ok(new_metadata)
+		})
+	}
+
+	/// Creates memmap without operations, but with metadata rebuilt
+	/// from all the records of `db` for given `prefix_bits`.
+	///
+	/// Journal eras starting with `next_era` are considered not flushed.
+	pub fn rebuild_metadata<P: AsRef<Path>>(
+		dir: P,
+		options: &InternalOptions,
+		db: &[u8],
+		prefix_bits: u8,
+		next_era: u64,
+	) -> Result<Flush> {
+		let mut metadata = Metadata::new(&options.external, prefix_bits);
+		metadata.next_era = next_era;
+		for space in SpaceIterator::new(db, options.field_body_size, 0) {
+			if let Space::Occupied(space) = space? {
+				let prefix = record_prefix(space.data, options.key_size, prefix_bits)?;
+				metadata.insert_record(prefix, space.data.len());
+			}
+		}
+
+		Self::create(dir, &[], metadata, options.external.durability)
+	}
+
+	/// Creates memmap without operations, which only replaces metadata.
+	pub fn with_metadata<P: AsRef<Path>>(dir: P, metadata: Metadata, durability: Durability) -> Result<Flush> {
+		Self::create(dir, &[], metadata, durability)
+	}
+
+	/// Writes the flush file with given operations.
+	fn create<P: AsRef<Path>>(dir: P, flush_data: &[u8], metadata: Metadata, durability: Durability) -> Result<Flush> {
+		let prefix_bits = metadata.prefixes.prefix_bits();
+		Self::write(dir, prefix_bits, durability, |writer| {
+			writer.write_all(flush_data)?;
+			Ok(metadata)
+		})
+	}
 
-		let mut file = fs::OpenOptions::new()
+	/// Writes the flush file with operations written by `write_operations`, which returns
+	/// metadata with `prefix_bits` after the flush. The file is synced according to `durability`,
+	/// so that it's recovered if the system crashes before it's applied.
+	///
+	/// Operations are streamed to the file, so they don't need to fit in memory.
+	/// The file is deleted if writing fails.
+	fn write<P, F>(dir: P, prefix_bits: u8, durability: Durability, write_operations: F) -> Result<Flush>
+		where P: AsRef<Path>, F: FnOnce(&mut io::BufWriter<&fs::File>) -> Result<Metadata> {
+		let path = dir.as_ref().join(Flush::FILE_NAME);
+		let file = fs::OpenOptions::new()
 			.write(true)
 			.read(true)
 			.create_new(true)
 			.open(&path)?;
-		file.set_len(flush_data.len() as u64 + Self::CHECKSUM_SIZE as u64)?;
-		file.flush()?;
-
-		let mut mmap = Mmap::open(&file, Protection::ReadWrite)?;
-		Keccak::sha3_256(&flush_data, unsafe { &mut mmap.as_mut_slice()[..Self::CHECKSUM_SIZE] });
-		unsafe { &mut mmap.as_mut_slice()[Self::CHECKSUM_SIZE..] }.write_all(&flush_data)?;
-		mmap.flush()?;
 
-		Ok(Flush {
-			path,
-			mmap,
-			metadata,
-			prefix_bits: options.external.key_index_bits,
-		})
+		match Self::write_file(&file, prefix_bits, write_operations) {
+			Ok((mmap, metadata)) => {
+				durability.sync_file(&file)?;
+				durability.sync_dir(&dir)?;
+				let meta_end = Self::CHECKSUM_SIZE + metadata::bytes::len(prefix_bits);
+				let db_len = required_db_len(unsafe { &mmap.as_slice()[meta_end..] });
+				Ok(Flush {
+					path,
+					mmap,
+					metadata,
+					db_len,
+					durability,
+				})
+			},
+			Err(err) => {
+				let _ = fs::remove_file(&path);
+				Err(err)
+			},
+		}
 	}
 
-	/// Open flush file if it exists. It it does not, returns None.
-	pub fn open<P: AsRef<Path>>(dir: P, prefix_bits: u8) -> Result<Option<Flush>> {
-		let path = dir.as_ref().join(Self::FILE_NAME);
-		let mmap = match Mmap::open_path(&path, Protection::Read) {
-			Ok(mmap) => mmap,
-			Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
-			Err(err) => return Err(err.into()),
+	/// Writes operations followed by checksum and metadata, which are known only afterwards.
+	fn write_file<F>(file: &fs::File, prefix_bits: u8, write_operations: F) -> Result<(Mmap, Metadata)>
+		where F: FnOnce(&mut io::BufWriter<&fs::File>) -> Result<Metadata> {
+		let meta_len = metadata::bytes::len(prefix_bits);
+		let metadata = {
+			let mut writer = io::BufWriter::new(file);
+			// space for the checksum and metadata
+			io::copy(&mut io::repeat(0).take((Self::CHECKSUM_SIZE + meta_len) as u64), &mut writer)?;
+			let metadata = write_operations(&mut writer)?;
+			writer.flush()?;
+			metadata
 		};
+		assert_eq!(metadata.prefixes.prefix_bits(), prefix_bits);
 
+		let mut mmap = Mmap::open(file, Protection::ReadWrite)?;
 		{
-			let checksum = unsafe { &mmap.as_slice()[..Self::CHECKSUM_SIZE] };
-			let data = unsafe { &mmap.as_slice()[Self::CHECKSUM_SIZE..] };
-			let hash = sha3_256(data);
-			if hash != checksum {
-				return Err(ErrorKind::CorruptedFlush(
-					path,
-					format!(
-						"Expected: {:02x}, Got: {:02x}",
-						hash.as_hex(),
-						checksum.as_hex(),
-					)
-				).into());
-			}
+			let (checksum, data) = unsafe { mmap.as_mut_slice() }.split_at_mut(Self::CHECKSUM_SIZE);
+			metadata.as_bytes().copy_to_slice(&mut data[..meta_len]);
+			Keccak::sha3_256(data, checksum);
 		}
+		mmap.flush()?;
+		Ok((mmap, metadata))
+	}
 
-		let meta_offset = mmap.len() - metadata::bytes::len(prefix_bits);
-		let metadata = metadata::bytes::read(unsafe { &mmap.as_slice()[meta_offset..] }, prefix_bits);
+	/// Open flush file if it exists. It it does not, returns None.
+	///
+	/// Deleting it is synced according to `durability`.
+	pub fn open<P: AsRef<Path>>(dir: P, durability: Durability) -> Result<Option<Flush>> {
+		let path = dir.as_ref().join(Self::FILE_NAME);
+		let mmap = match open_checked(&path)? {
+			Some(mmap) => mmap,
+			None => return Ok(None),
+		};
+
+		let metadata = metadata::bytes::read(unsafe { &mmap.as_slice()[Self::CHECKSUM_SIZE..] })?;
+		let meta_end = Self::CHECKSUM_SIZE + metadata::bytes::len(metadata.prefixes.prefix_bits());
+		let db_len = required_db_len(unsafe { &mmap.as_slice()[meta_end..] });
 		Ok(Some(Flush {
 			path,
 			mmap,
-			prefix_bits,
 			metadata,
+			db_len,
+			durability,
 		}))
 	}
 
+	/// Returns number of prefix bits the database has after this flush.
+	pub fn prefix_bits(&self) -> u8 {
+		self.metadata.prefixes.prefix_bits()
+	}
+
+	/// Returns minimal length of the database required by this flush.
+	///
+	/// It may be greater than current length of the database,
+	/// if records were moved to the overflow region.
+	pub fn db_len(&self) -> u64 {
+		self.db_len
+	}
+
+	/// Returns database metadata after this flush.
+	pub fn metadata(&self) -> &Metadata {
+		&self.metadata
+	}
+
+	/// Returns true if the flush only replaces metadata.
+	pub fn is_metadata_only(&self) -> bool {
+		self.mmap.len() == Self::CHECKSUM_SIZE + metadata::bytes::len(self.prefix_bits())
+	}
+
 	/// Flushes idempotent operations to the database.
-	pub fn flush(&self, db: &mut [u8], raw_metadata: &mut [u8], metadata: &mut Metadata) {
-		let meta_offset = self.mmap.len() - metadata::bytes::len(self.prefix_bits);
-		let operations = unsafe { &self.mmap.as_slice()[Self::CHECKSUM_SIZE..meta_offset] };
+	///
+	/// Records are moved in place, so `db` must not be read until it returns.
+	/// Metadata is written last, after all the records are in their new locations.
+	pub fn flush(&self, db: &mut [u8], raw_metadata: &mut [u8]) {
+		let meta_end = Self::CHECKSUM_SIZE + metadata::bytes::len(self.prefix_bits());
+		let operations = unsafe { &self.mmap.as_slice()[meta_end..] };
 		let operations = IdempotentOperationIterator::new(operations);
 
 		for o in operations {
 			db[o.offset..o.offset + o.data.len()].copy_from_slice(o.data);
 		}
 
-		let meta = unsafe { &self.mmap.as_slice()[meta_offset..] };
+		let meta = unsafe { &self.mmap.as_slice()[Self::CHECKSUM_SIZE..meta_end] };
 		raw_metadata.copy_from_slice(meta);
-		mem::swap(&mut self.metadata.clone(), metadata);
 	}
 
 	/// Delete flush file. Should be called only after database has been successfully flushed.
 	pub fn delete(self) -> Result<()> {
-		fs::remove_file(self.path)?;
+		fs::remove_file(&self.path)?;
+		let dir = self.path.parent().expect("flush file is always in the database directory; qed");
+		self.durability.sync_dir(dir)?;
+		Ok(())
+	}
+}
+
+/// Flush written by version 0, which stored metadata after the operations.
+///
+/// ```text
+///  checksum   idempotent operations   metadata
+///   /          /                       /
+/// |...|...................|..........|
+/// ```
+#[derive(Debug)]
+pub struct FlushV0 {
+	path: PathBuf,
+	mmap: Mmap,
+	meta_len: usize,
+	durability: Durability,
+}
+
+impl FlushV0 {
+	/// Opens flush file written by version 0 if it exists. Its metadata has `meta_len` bytes.
+	///
+	/// Deleting it is synced according to `durability`.
+	pub fn open<P: AsRef<Path>>(dir: P, meta_len: usize, durability: Durability) -> Result<Option<FlushV0>> {
+		let path = dir.as_ref().join(Flush::FILE_NAME);
+		let mmap = match open_checked(&path)? {
+			Some(mmap) => mmap,
+			None => return Ok(None),
+		};
+
+		if mmap.len() < Flush::CHECKSUM_SIZE + meta_len {
+			bail!(ErrorKind::CorruptedFlush(path, "Metadata of version 0 is truncated".into()));
+		}
+
+		Ok(Some(FlushV0 {
+			path,
+			mmap,
+			meta_len,
+			durability,
+		}))
+	}
+
+	/// Returns length of the metadata stored by the flush.
+	pub fn meta_len(&self) -> usize {
+		self.meta_len
+	}
+
+	/// Flushes idempotent operations to the database and writes its metadata of version 0.
+	pub fn flush(&self, db: &mut [u8], raw_metadata: &mut [u8]) {
+		let meta_offset = self.mmap.len() - self.meta_len;
+		let operations = unsafe { &self.mmap.as_slice()[Flush::CHECKSUM_SIZE..meta_offset] };
+		for o in IdempotentOperationIterator::new(operations) {
+			db[o.offset..o.offset + o.data.len()].copy_from_slice(o.data);
+		}
+
+		raw_metadata.copy_from_slice(unsafe { &self.mmap.as_slice()[meta_offset..] });
+	}
+
+	/// Delete flush file. Should be called only after database has been successfully flushed.
+	pub fn delete(self) -> Result<()> {
+		fs::remove_file(&self.path)?;
+		let dir = self.path.parent().expect("flush file is always in the database directory; qed");
+		self.durability.sync_dir(dir)?;
 		Ok(())
 	}
 }
@@ -1,6 +1,8 @@
 mod decision;
 mod flush;
 mod iterator;
+mod relayout;
 mod writer;
 
-pub use self::flush::Flush;
+pub use self::flush::{Flush, FlushV0};
+pub use self::relayout::record_prefix;
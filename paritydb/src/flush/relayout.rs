@@ -0,0 +1,165 @@
+//! Database re-layout
+//!
+//! When the database grows, the number of prefix bits is increased and
+//! every record needs to be moved closer to the offset determined by
+//! the new prefix of its key. This module creates idempotent operations
+//! which move all the records and clear the space they used to occupy.
+
+use std::{cmp, io};
+use std::io::{Read, Write};
+
+use byteorder::{LittleEndian, WriteBytesExt};
+
+use error::Result;
+use field::field_size;
+use key::Key;
+use metadata::Metadata;
+use record::{KeySize, Record};
+use space::{SpaceIterator, Space};
+
+/// Location of the record in the database.
+#[derive(Debug, PartialEq, Clone, Copy)]
+struct Location {
+	offset: usize,
+	len: usize,
+}
+
+impl Location {
+	#[inline]
+	fn end(&self) -> usize {
+		self.offset + self.len
+	}
+}
+
+#[inline]
+fn write_operation<W: Write>(writer: &mut W, offset: usize, data: &[u8]) -> io::Result<()> {
+	writer.write_u64::<LittleEndian>(offset as u64)?;
+	writer.write_u32::<LittleEndian>(data.len() as u32)?;
+	writer.write_all(data)
+}
+
+#[inline]
+fn write_empty_operation<W: Write>(writer: &mut W, offset: usize, len: usize) -> io::Result<()> {
+	writer.write_u64::<LittleEndian>(offset as u64)?;
+	writer.write_u32::<LittleEndian>(len as u32)?;
+	io::copy(&mut io::repeat(0).take(len as u64), writer)?;
+	Ok(())
+}
+
+/// Returns the prefix of a record stored at the beginning of `data`.
+#[inline]
+pub fn record_prefix(data: &[u8], key_size: KeySize, prefix_bits: u8) -> Result<u32> {
+	Ok(Key::new(Record::extract_key(data, key_size)?, prefix_bits).prefix)
+}
+
+/// Writes idempotent operations which move all records of `db` to the
+/// offsets determined by prefix bits of `metadata` to `writer`.
+///
+/// `metadata` should be empty. All moved records are inserted into it.
+///
+/// Operations are written while `db` is read, so that they are never kept in memory.
+/// Runs of records are cleared first and records are written to their new locations
+/// afterwards, so no record is overwritten by clearing the location it was moved from.
+///
+/// Records always fit into a database which is at least twice as big, cause
+/// the last prefix starts in the first quarter of the new database.
+/// If the number of prefix bits doesn't change, records are moved only backwards.
+pub fn relayout<W: Write>(writer: &mut W, db: &[u8], metadata: &mut Metadata, field_body_size: usize, key_size: KeySize) -> Result<()> {
+	// contiguous records which are not cleared yet
+	let mut run: Option<Location> = None;
+	for space in SpaceIterator::new(db, field_body_size, 0) {
+		let space = match space? {
+			Space::Occupied(space) => space,
+			Space::Empty(_) => continue,
+		};
+
+		run = match run {
+			Some(run) if run.end() == space.offset && run.len + space.data.len() <= u32::MAX as usize => {
+				Some(Location { offset: run.offset, len: run.len + space.data.len() })
+			},
+			Some(run) => {
+				write_empty_operation(writer, run.offset, run.len)?;
+				Some(Location { offset: space.offset, len: space.data.len() })
+			},
+			None => Some(Location { offset: space.offset, len: space.data.len() }),
+		};
+	}
+
+	if let Some(run) = run {
+		write_empty_operation(writer, run.offset, run.len)?;
+	}
+
+	let prefix_bits = metadata.prefixes.prefix_bits();
+	// first offset which is not occupied by already moved records
+	let mut free = 0;
+	for space in SpaceIterator::new(db, field_body_size, 0) {
+		let space = match space? {
+			Space::Occupied(space) => space,
+			Space::Empty(_) => continue,
+		};
+
+		let prefix = record_prefix(space.data, key_size, prefix_bits)?;
+		let min_offset = prefix as usize * field_size(field_body_size);
+		let offset = cmp::max(free, min_offset);
+
+		write_operation(writer, offset, space.data)?;
+		metadata.insert_record(prefix, space.data.len());
+		free = offset + space.data.len();
+	}
+
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use metadata::Metadata;
+	use record::KeySize;
+	use flush::iterator::{IdempotentOperation, IdempotentOperationIterator};
+	use super::relayout;
+
+	#[test]
+	fn test_relayout() {
+		let field_body_size = 2;
+		let key_size = KeySize::Constant(2);
+		// records with keys [0x00, 0x01], [0x80, 0x02] and [0x81, 0x03] stored under 1 prefix bit
+		let db = [
+			1, 0x00, 0x01,
+			0, 0, 0,
+			1, 0x80, 0x02,
+			1, 0x81, 0x03,
+			0, 0, 0,
+			0, 0, 0,
+			0, 0, 0,
+			0, 0, 0,
+		];
+
+		let mut metadata = Metadata::new(&Default::default(), 2);
+		let mut operations = Vec::new();
+		relayout(&mut operations, &db, &mut metadata, field_body_size, key_size).unwrap();
+		let operations: Vec<_> = IdempotentOperationIterator::new(&operations).collect();
+
+		assert_eq!(operations, vec![
+			IdempotentOperation { offset: 0, data: &[0, 0, 0] },
+			IdempotentOperation { offset: 6, data: &[0, 0, 0, 0, 0, 0] },
+			IdempotentOperation { offset: 0, data: &[1, 0x00, 0x01] },
+			IdempotentOperation { offset: 6, data: &[1, 0x80, 0x02] },
+			IdempotentOperation { offset: 9, data: &[1, 0x81, 0x03] },
+		]);
+		assert_eq!(metadata.occupied_bytes, 9);
+		assert_eq!(metadata.prefixes.prefixes_iter().collect::<Vec<_>>(), vec![0, 2]);
+
+		let mut metadata = Metadata::new(&Default::default(), 3);
+		let mut operations = Vec::new();
+		relayout(&mut operations, &db, &mut metadata, field_body_size, key_size).unwrap();
+		let operations: Vec<_> = IdempotentOperationIterator::new(&operations).collect();
+
+		assert_eq!(operations, vec![
+			IdempotentOperation { offset: 0, data: &[0, 0, 0] },
+			IdempotentOperation { offset: 6, data: &[0, 0, 0, 0, 0, 0] },
+			IdempotentOperation { offset: 0, data: &[1, 0x00, 0x01] },
+			IdempotentOperation { offset: 12, data: &[1, 0x80, 0x02] },
+			IdempotentOperation { offset: 15, data: &[1, 0x81, 0x03] },
+		]);
+		assert_eq!(metadata.prefixes.prefixes_iter().collect::<Vec<_>>(), vec![0, 4]);
+	}
+}
@@ -4,18 +4,21 @@ use std::iter::Peekable;
 
 use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
 
+use database;
 use error::Result;
 use flush::decision::{decision, Decision, is_min_offset_for_space};
 use key::Key;
+use merge;
 use metadata::Metadata;
-use record::{append_record};
+use options::InternalOptions;
+use record::{append_record, KeySize};
 use space::{SpaceIterator, Space};
 use transaction::Operation;
 
 #[inline]
-fn write_insert_operation(buffer: &mut Vec<u8>, key: &[u8], value: &[u8], field_body_size: usize, const_value: bool) -> usize {
+fn write_insert_operation(buffer: &mut Vec<u8>, key: &[u8], value: &[u8], field_body_size: usize, key_size: KeySize, const_value: bool, with_checksum: bool) -> usize {
 	let buffer_len = buffer.len();
-	append_record(buffer, key, value, field_body_size, const_value);
+	append_record(buffer, key, value, field_body_size, key_size.is_const(), const_value, with_checksum);
 	buffer.len() - buffer_len
 }
 
@@ -61,47 +64,119 @@ enum OperationWriterStep {
 	Finished
 }
 
+/// Operation with merge operands folded into it.
+#[derive(Debug)]
+enum FoldedOperation<'op> {
+	Single(Operation<'op>),
+	Merged(&'op [u8], Vec<u8>),
+}
+
+impl<'op> FoldedOperation<'op> {
+	fn as_operation<'a>(&'a self) -> Operation<'a> {
+		match *self {
+			FoldedOperation::Single(ref operation) => operation.clone(),
+			FoldedOperation::Merged(key, ref value) => Operation::Insert(key, value),
+		}
+	}
+}
+
 /// Writes transactions as a set of idempotent operations
-pub struct OperationWriter<'db, I: Iterator> {
+///
+/// Records shifted past the end of the database are written to the overflow
+/// region, so the operations may require the database to be extended.
+pub struct OperationWriter<'op, 'db, I: Iterator> {
 	operations: Peekable<I>,
+	/// The next operation, which is not written yet.
+	folded: Option<FoldedOperation<'op>>,
+	database: &'db [u8],
 	spaces: SpaceIterator<'db>,
+	options: &'db InternalOptions,
 	metadata: &'db mut Metadata,
 	buffer: OperationBuffer,
 	field_body_size: usize,
 	prefix_bits: u8,
+	key_size: KeySize,
 	const_value: bool,
+	/// Records are written with checksums.
+	with_checksum: bool,
 	/// shift is always increased or decreased by a len of inserted/deleted
 	/// record or an empty field. inserted and deleted records are always
 	/// aligned by function append_record from src/record/append.rs.
 	shift: isize,
 }
 
-impl<'op, 'db, I: Iterator<Item = Operation<'op>>> OperationWriter<'db, I> {
-	/// Creates new operations writer. All operations needs to be ordered by key.
+impl<'op, 'db, I: Iterator<Item = Operation<'op>>> OperationWriter<'op, 'db, I> {
+	/// Creates new operations writer. All operations needs to be ordered by key
+	/// and merge operands need to follow the operation they're applied to.
 	pub fn new(
 		operations: I,
 		database: &'db [u8],
 		metadata: &'db mut Metadata,
-		field_body_size: usize,
-		prefix_bits: u8,
-		const_value: bool,
+		options: &'db InternalOptions,
 	) -> Self {
+		let prefix_bits = metadata.prefixes.prefix_bits();
+		let field_body_size = options.field_body_size;
 		OperationWriter {
 			operations: operations.peekable(),
-			spaces: SpaceIterator::new(database, field_body_size, 0),
+			folded: None,
+			database,
+			spaces: SpaceIterator::with_overflow(database, field_body_size, 0),
+			options,
 			metadata,
 			buffer: OperationBuffer::default(),
 			field_body_size,
 			prefix_bits,
-			const_value,
+			key_size: options.key_size,
+			const_value: options.external.value_len.is_const(),
+			with_checksum: options.external.record_checksum,
 			shift: 0,
 		}
 	}
 
+	/// Returns the next operation with merge operands of its key folded into it.
+	///
+	/// Operands which don't follow an insert or a delete are applied to the flushed value.
+	fn next_folded(&mut self) -> Result<Option<FoldedOperation<'op>>> {
+		let operation = match self.operations.next() {
+			Some(operation) => operation,
+			None => return Ok(None),
+		};
+
+		let key = operation.key();
+		let mut operands = Vec::new();
+		while let Some(&Operation::Merge(next_key, operand)) = self.operations.peek() {
+			if next_key != key {
+				break;
+			}
+
+			operands.push(operand);
+			let _ = self.operations.next();
+		}
+
+		let flushed;
+		let existing = match operation {
+			Operation::Insert(_, _) | Operation::Delete(_) if operands.is_empty() => {
+				return Ok(Some(FoldedOperation::Single(operation)));
+			},
+			Operation::Insert(_, value) => Some(value),
+			Operation::Delete(_) => None,
+			Operation::Merge(_, operand) => {
+				operands.insert(0, operand);
+				flushed = database::get_flushed(self.options, self.metadata, self.database, key)?
+					.map(|value| value.to_vec());
+				flushed.as_ref().map(|value| &value[..])
+			},
+		};
+
+		let external = &self.options.external;
+		let merged = merge::merge(&external.merge_operator, &external.value_len, key, existing, &operands)?;
+		Ok(Some(FoldedOperation::Merged(key, merged)))
+	}
+
 	fn last_step(&mut self) -> Result<()> {
 		// loop until the transaction is finished
 		while self.shift > 0 {
-			let space = self.spaces.next().expect("TODO: db end")?;
+			let space = self.spaces.next().expect("spaces iterator overflows and never ends; qed")?;
 			match space {
 				Space::Empty(space) => {
 					self.shift -= space.len as isize;
@@ -114,14 +189,14 @@ impl<'op, 'db, I: Iterator<Item = Operation<'op>>> OperationWriter<'db, I> {
 		}
 
 		while self.shift < 0 {
-			let space = self.spaces.next().expect("TODO: db end")?;
+			let space = self.spaces.next().expect("spaces iterator overflows and never ends; qed")?;
 			match space {
 				Space::Empty(_) => {
 					write_empty_bytes(self.buffer.as_raw_mut(), (-self.shift) as usize);
 					self.shift = 0;
 				},
 				Space::Occupied(space) => {
-					if is_min_offset_for_space(space.offset, self.shift, space.data, self.prefix_bits, self.field_body_size) {
+					if is_min_offset_for_space(space.offset, self.shift, space.data, self.prefix_bits, self.field_body_size, self.key_size)? {
 						self.buffer.as_raw_mut().extend_from_slice(space.data);
 					} else {
 						write_empty_bytes(self.buffer.as_raw_mut(), (-self.shift) as usize);
@@ -137,13 +212,21 @@ impl<'op, 'db, I: Iterator<Item = Operation<'op>>> OperationWriter<'db, I> {
 	}
 
 	fn step(&mut self) -> Result<OperationWriterStep> {
-		let operation = match self.operations.peek().cloned() {
-			Some(operation) => operation,
+		let folded = match self.folded.take() {
+			Some(folded) => Some(folded),
+			None => self.next_folded()?,
+		};
+
+		let folded = match folded {
+			Some(folded) => folded,
 			None => {
 				self.last_step()?;
 				return Ok(OperationWriterStep::Finished)
 			}
 		};
+		let operation = folded.as_operation();
+		// the operation is kept until it's written or ignored
+		let mut consumed = false;
 
 		let prefixed_key = Key::new(operation.key(), self.prefix_bits);
 
@@ -153,40 +236,40 @@ impl<'op, 'db, I: Iterator<Item = Operation<'op>>> OperationWriter<'db, I> {
 			self.spaces.move_offset_forward(prefixed_key.offset(self.field_body_size));
 		}
 
-		let space = self.spaces.peek().expect("TODO: db end?")?;
-		let d = decision(operation, space, self.shift, self.field_body_size, self.prefix_bits);
+		let space = self.spaces.peek().expect("spaces iterator overflows and never ends; qed")?;
+		let d = decision(operation, space, self.shift, self.field_body_size, self.prefix_bits, self.key_size)?;
 		match d {
 			Decision::InsertOperationIntoEmptySpace { key, value, offset, space_len } => {
 				// advance iterators
-				let _ = self.operations.next();
+				consumed = true;
 				let _ = self.spaces.next();
 
 				// denote operation start
 				self.buffer.denote_operation_start(offset as u64);
-				let written = write_insert_operation(self.buffer.as_raw_mut(), key, value, self.field_body_size, self.const_value);
+				let written = write_insert_operation(self.buffer.as_raw_mut(), key, value, self.field_body_size, self.key_size, self.const_value, self.with_checksum);
 				self.shift += written as isize - space_len as isize;
 				// insert metadata
 				self.metadata.insert_record(prefixed_key.prefix, written);
 			},
 			Decision::InsertOperationBeforeOccupiedSpace { key, value, offset } => {
 				// advance iterators
-				let _ = self.operations.next();
+				consumed = true;
 
 				// denote operation start
 				self.buffer.denote_operation_start(offset as u64);
-				let written = write_insert_operation(self.buffer.as_raw_mut(), key, value, self.field_body_size, self.const_value);
+				let written = write_insert_operation(self.buffer.as_raw_mut(), key, value, self.field_body_size, self.key_size, self.const_value, self.with_checksum);
 				self.shift += written as isize;
 				// insert metadata
 				self.metadata.insert_record(prefixed_key.prefix, written);
 			},
 			Decision::OverwriteOperation { key, value, offset, old_len } => {
 				// advance iterators
-				let _ = self.operations.next();
+				consumed = true;
 				let _ = self.spaces.next();
 
 				// denote operation start
 				self.buffer.denote_operation_start(offset as u64);
-				let written = write_insert_operation(self.buffer.as_raw_mut(), key, value, self.field_body_size, self.const_value);
+				let written = write_insert_operation(self.buffer.as_raw_mut(), key, value, self.field_body_size, self.key_size, self.const_value, self.with_checksum);
 				self.shift += written as isize - old_len as isize;
 				// update metadata
 				self.metadata.update_record_len(old_len, written);
@@ -197,7 +280,7 @@ impl<'op, 'db, I: Iterator<Item = Operation<'op>>> OperationWriter<'db, I> {
 			},
 			Decision::IgnoreOperation => {
 				// ignore this operation
-				let _ = self.operations.next();
+				consumed = true;
 			},
 			Decision::ConsumeEmptySpace { len } => {
 				let _ = self.spaces.next();
@@ -218,7 +301,7 @@ impl<'op, 'db, I: Iterator<Item = Operation<'op>>> OperationWriter<'db, I> {
 			},
 			Decision::DeleteOperation { offset, len } => {
 				// advance operations
-				let _ = self.operations.next();
+				consumed = true;
 				let _ = self.spaces.next();
 
 				// denote operation start
@@ -229,17 +312,16 @@ impl<'op, 'db, I: Iterator<Item = Operation<'op>>> OperationWriter<'db, I> {
 			},
 		}
 
+		if !consumed {
+			self.folded = Some(folded);
+		}
+
 		Ok(OperationWriterStep::Stepped)
 	}
 
 	#[inline]
 	pub fn run(mut self) -> Result<Vec<u8>> {
 		while let OperationWriterStep::Stepped = self.step()? {}
-		let mut result = self.buffer.inner;
-		let meta = self.metadata.as_bytes();
-		let old_len = result.len();
-		result.resize(old_len + meta.len(), 0);
-		meta.copy_to_slice(&mut result[old_len..]);
-		Ok(result)
+		Ok(self.buffer.inner)
 	}
 }
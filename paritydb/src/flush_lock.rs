@@ -0,0 +1,163 @@
+//! Lock of the flushed data shared by the writer and read-only handles
+//!
+//! The writer locks the file exclusively while it modifies flushed data
+//! and increments the generation stored in it afterwards.
+//! Read-only handles lock it shared only around each read and fail
+//! if the generation is not the one their flushed data was loaded at.
+
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use byteorder::{ByteOrder, LittleEndian};
+use fs2::FileExt;
+use parking_lot::Mutex;
+
+use error::{ErrorKind, Result};
+
+/// How long the writer waits for reads of read-only handles when it's opened.
+const LOCK_TIMEOUT: Duration = Duration::from_secs(1);
+/// How often the writer tries to lock the file while it waits.
+const LOCK_RETRY_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Opens the lock file for the writer, creating it if necessary.
+pub(crate) fn open_writable<P: AsRef<Path>>(path: P) -> Result<File> {
+	let file = fs::OpenOptions::new()
+		.read(true)
+		.write(true)
+		.create(true)
+		.truncate(false)
+		.open(path)?;
+
+	Ok(file)
+}
+
+/// Locks the file of the writer exclusively once reads of read-only handles in progress finish.
+///
+/// Reads are short, so it fails with `DatabaseLocked` if the file is still locked after `LOCK_TIMEOUT`.
+pub(crate) fn lock_writable<P: AsRef<Path>>(file: &File, path: P) -> Result<()> {
+	let start = Instant::now();
+	while FileExt::try_lock_exclusive(file).is_err() {
+		if start.elapsed() >= LOCK_TIMEOUT {
+			return Err(ErrorKind::DatabaseLocked(path.as_ref().into()).into());
+		}
+		thread::sleep(LOCK_RETRY_INTERVAL);
+	}
+
+	Ok(())
+}
+
+/// Returns the number of modifications of the flushed data.
+fn generation(mut file: &File) -> Result<u64> {
+	let mut bytes = [0u8; 8];
+	file.seek(SeekFrom::Start(0))?;
+	match file.read_exact(&mut bytes) {
+		Ok(()) => Ok(LittleEndian::read_u64(&bytes)),
+		// the file is empty until the flushed data is modified for the first time
+		Err(ref err) if err.kind() == io::ErrorKind::UnexpectedEof => Ok(0),
+		Err(err) => Err(err.into()),
+	}
+}
+
+/// Increments the generation of the exclusively locked file.
+pub(crate) fn increment_generation(mut file: &File) -> Result<()> {
+	let mut bytes = [0u8; 8];
+	LittleEndian::write_u64(&mut bytes, generation(file)?.wrapping_add(1));
+	file.seek(SeekFrom::Start(0))?;
+	file.write_all(&bytes)?;
+	Ok(())
+}
+
+#[derive(Debug)]
+struct Shared {
+	file: File,
+	/// Number of reads in progress. The file is locked as long as it's not zero.
+	reads: Mutex<usize>,
+}
+
+/// Shared lock of the flushed data of a read-only handle.
+#[derive(Debug, Clone)]
+pub(crate) struct ReadLock {
+	shared: Arc<Shared>,
+	/// Generation the flushed data was loaded at.
+	generation: u64,
+}
+
+impl ReadLock {
+	/// Opens the lock file without creating it and locks it until the guard is dropped.
+	///
+	/// Returns `None` if the file doesn't exist, cause the database
+	/// hasn't been opened by the writer since it was created by an older version.
+	pub fn open<P: AsRef<Path>>(path: P) -> Result<Option<(ReadLock, ReadGuard)>> {
+		let file = match fs::OpenOptions::new().read(true).open(path) {
+			Ok(file) => file,
+			Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+			Err(err) => return Err(err.into()),
+		};
+
+		let shared = Arc::new(Shared {
+			file,
+			reads: Mutex::new(0),
+		});
+		let (guard, generation) = ReadGuard::new(shared.clone())?;
+		Ok(Some((ReadLock { shared, generation }, guard)))
+	}
+
+	/// Locks the flushed data until the guard is dropped.
+	///
+	/// Fails with `FlushedDataChanged` if the writer has modified it since it was loaded.
+	pub fn lock(&self) -> Result<ReadGuard> {
+		let (guard, generation) = ReadGuard::new(self.shared.clone())?;
+		if generation != self.generation {
+			return Err(ErrorKind::FlushedDataChanged.into());
+		}
+
+		Ok(guard)
+	}
+}
+
+/// Locks the flushed data if it's read by a read-only handle.
+pub(crate) fn lock_read(read_lock: &Option<ReadLock>) -> Result<Option<ReadGuard>> {
+	match *read_lock {
+		Some(ref read_lock) => read_lock.lock().map(Some),
+		None => Ok(None),
+	}
+}
+
+/// Keeps the flushed data locked by a read-only handle.
+#[derive(Debug)]
+pub(crate) struct ReadGuard {
+	shared: Arc<Shared>,
+}
+
+impl ReadGuard {
+	/// Returns the guard and the generation of the locked data.
+	fn new(shared: Arc<Shared>) -> Result<(Self, u64)> {
+		let generation = {
+			let mut reads = shared.reads.lock();
+			if *reads == 0 {
+				// waits until the writer finishes modifying the flushed data
+				FileExt::lock_shared(&shared.file)?;
+			}
+			*reads += 1;
+			// the file position is shared, so it's read only by one thread at a time
+			generation(&shared.file)
+		};
+
+		let guard = ReadGuard { shared };
+		generation.map(|generation| (guard, generation))
+	}
+}
+
+impl Drop for ReadGuard {
+	fn drop(&mut self) {
+		let mut reads = self.shared.reads.lock();
+		*reads -= 1;
+		if *reads == 0 {
+			let _ = FileExt::unlock(&self.shared.file);
+		}
+	}
+}
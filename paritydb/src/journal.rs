@@ -1,17 +1,22 @@
-use std::collections::vec_deque::Drain;
-use std::collections::{BTreeSet, HashMap, VecDeque, btree_set};
+use std::collections::{BTreeMap, HashMap, VecDeque};
 use std::fs;
 use std::hash::{Hash, Hasher};
 use std::io::Write;
+use std::ops::Range;
 use std::path::{PathBuf, Path};
-use std::slice;
+use std::{cmp, slice};
+use std::sync::Arc;
+use std::sync::atomic::{self, AtomicUsize};
+use std::vec;
 
+use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
 use hex_slice::AsHex;
 use memmap::{Mmap, Protection};
 use tiny_keccak::sha3_256;
 
 use error::{ErrorKind, Result};
-use transaction::{Operation, OperationsIterator, Transaction};
+use options::Durability;
+use transaction::{Condition, Operation, OperationsIterator, Transaction};
 
 const CHECKSUM_SIZE: usize = 32;
 
@@ -19,6 +24,22 @@ const CHECKSUM_SIZE: usize = 32;
 enum JournalOperation<T> {
 	Insert(T),
 	Delete,
+	/// Merge operands, from the oldest one, applied to the value inserted or deleted
+	/// before them in the same era or to the value from older eras if `base` is `None`.
+	Merge {
+		base: Option<Option<T>>,
+		operands: Vec<T>,
+	},
+}
+
+/// The most recent operations on a key in the journal.
+#[derive(Debug, PartialEq)]
+pub struct JournalEntry<'a> {
+	/// Value the operands are applied to. It's `Some(None)` if the key was deleted
+	/// and `None` if the operands are applied to the flushed value.
+	pub base: Option<Option<&'a [u8]>>,
+	/// Merge operands, from the oldest one.
+	pub operands: Vec<&'a [u8]>,
 }
 
 /// Unsafe view onto memmap file memory which backs journal.
@@ -59,47 +80,145 @@ impl PartialEq for JournalSlice {
 
 impl Eq for JournalSlice {}
 
+impl<'a> JournalEntry<'a> {
+	/// Creates the entry from operations on a single key, stacked by the journal iterator.
+	pub fn from_stack(operations: &[Operation<'a>]) -> Self {
+		let (base, operands) = match operations[0] {
+			Operation::Insert(_, value) => (Some(Some(value)), &operations[1..]),
+			Operation::Delete(_) => (Some(None), &operations[1..]),
+			Operation::Merge(..) => (None, operations),
+		};
+
+		JournalEntry {
+			base,
+			operands: operands.iter().map(|o| match *o {
+				Operation::Merge(_, operand) => operand,
+				_ => unreachable!("only merge operands are stacked on top of other operations; qed"),
+			}).collect(),
+		}
+	}
+}
+
 unsafe fn cache_memory(memory: &[u8]) -> HashMap<JournalSlice, JournalOperation<JournalSlice>> {
-	let iterator = OperationsIterator::new(memory);
-	iterator.map(|o| match o {
-		Operation::Insert(key, value) => (JournalSlice::new(key), JournalOperation::Insert(JournalSlice::new(value))),
-		Operation::Delete(key) => (JournalSlice::new(key), JournalOperation::Delete)
-	}).collect()
+	let mut cache = HashMap::new();
+	for o in OperationsIterator::new(memory) {
+		let key = JournalSlice::new(o.key());
+		match o {
+			Operation::Insert(_, value) => {
+				cache.insert(key, JournalOperation::Insert(JournalSlice::new(value)));
+			},
+			Operation::Delete(_) => {
+				cache.insert(key, JournalOperation::Delete);
+			},
+			Operation::Merge(_, operand) => {
+				let operand = JournalSlice::new(operand);
+				let operation = match cache.remove(&key) {
+					None => JournalOperation::Merge { base: None, operands: vec![operand] },
+					Some(JournalOperation::Insert(value)) => JournalOperation::Merge { base: Some(Some(value)), operands: vec![operand] },
+					Some(JournalOperation::Delete) => JournalOperation::Merge { base: Some(None), operands: vec![operand] },
+					Some(JournalOperation::Merge { base, mut operands }) => {
+						operands.push(operand);
+						JournalOperation::Merge { base, operands }
+					},
+				};
+				cache.insert(key, operation);
+			},
+		}
+	}
+
+	cache
+}
+
+/// Returns operations sorted by key. Only the last insert or delete of each key
+/// is kept, followed by merge operands applied to it.
+fn stack_operations<'a, I>(operations: I) -> Vec<Operation<'a>> where I: IntoIterator<Item = Operation<'a>> {
+	let mut stacks = BTreeMap::new();
+	for o in operations {
+		let stack = stacks.entry(o.key()).or_insert_with(Vec::new);
+		match o {
+			Operation::Merge(..) => {},
+			_ => stack.clear(),
+		}
+		stack.push(o);
+	}
+
+	stacks.into_values().flatten().collect()
 }
 
+/// Transactions of one or more consecutive eras stored in a single file.
+///
+/// A file of a single era stores its transaction after the checksum.
+/// A file squashing many eras stores lengths of their transactions before them,
+/// so that they can still be rolled back one by one. Its oldest eras might be
+/// flushed before the other ones, which are the only ones seen then.
+///
+/// ```text
+///  checksum  eras  transaction lengths  transactions
+///   /         /     /                    /
+/// |....|....|........|...................|
+/// ```
 #[derive(Debug)]
 pub struct JournalEra {
 	file: PathBuf,
-	mmap: Mmap,
+	/// Shared with the same file without eras flushed later.
+	mmap: Arc<Mmap>,
+	/// Index of the first era in the file.
+	first: u64,
+	/// Ranges of transactions of consecutive eras in the file.
+	eras: Vec<Range<usize>>,
+	/// Number of the oldest eras in the file, which have already been flushed.
+	skipped: usize,
+	/// Operations of eras in the file, which haven't been flushed.
 	cache: HashMap<JournalSlice, JournalOperation<JournalSlice>>,
 }
 
+// SAFETY: `JournalSlice`s of the cache point only to the memory of `mmap`, which is
+// kept alive by the era itself and never written to. The era is never modified
+// through them, so it can be sent to and shared by other threads like the memory map.
+unsafe impl Send for JournalEra {}
+unsafe impl Sync for JournalEra {}
+
 impl JournalEra {
-	// TODO [ToDr] Data should be written to a file earlier (for instance when transaction is created).
-	// Consider an API like this:
-	// ```
-	// let mut transaction = Transaction::new();
-	// ...
-	// let prepared = db.prepare(transaction); // writes to a file (doesn't require write access to DB)
-	// db.apply(prepared); // actually insert to db (requires write access)
-	// ```
-	fn create<P: AsRef<Path>>(file_path: P, transaction: &Transaction) -> Result<JournalEra> {
-		let hash = sha3_256(transaction.raw());
+	fn create<P: AsRef<Path>>(file_path: P, transaction: &Transaction, durability: Durability) -> Result<JournalEra> {
+		Self::write(&file_path, &[transaction.raw()], durability)?;
+		Self::open(file_path)
+	}
+
+	/// Writes transactions of consecutive eras to a new file.
+	fn write<P: AsRef<Path>>(file_path: P, transactions: &[&[u8]], durability: Durability) -> Result<()> {
+		let mut data = Vec::new();
+		if transactions.len() > 1 {
+			data.write_u32::<LittleEndian>(transactions.len() as u32)?;
+			for transaction in transactions {
+				data.write_u64::<LittleEndian>(transaction.len() as u64)?;
+			}
+		}
+		for transaction in transactions {
+			data.extend_from_slice(transaction);
+		}
+
+		let hash = sha3_256(&data);
 		let mut file = fs::OpenOptions::new()
 			.write(true)
 			.create_new(true)
 			.open(&file_path)?;
 
 		file.write_all(&hash)?;
-		file.write_all(transaction.raw())?;
+		file.write_all(&data)?;
 		file.flush()?;
-
-		Self::open(file_path)
+		durability.sync_file(&file)?;
+		Ok(())
 	}
 
+	/// Opens a file of a single era. Its index is set when it's added to the journal.
 	fn open<P: AsRef<Path>>(file: P) -> Result<JournalEra> {
+		Self::open_eras(file, 0, 1)
+	}
+
+	/// Opens a file of `count` consecutive eras starting with the `first` one.
+	fn open_eras<P: AsRef<Path>>(file: P, first: u64, count: usize) -> Result<JournalEra> {
 		let mmap = Mmap::open_path(&file, Protection::Read)?;
-		let cache = {
+		let (eras, cache) = {
 			let checksum = unsafe { &mmap.as_slice()[..CHECKSUM_SIZE] };
 			let data = unsafe { &mmap.as_slice()[CHECKSUM_SIZE..] };
 			let hash = sha3_256(data);
@@ -114,18 +233,83 @@ impl JournalEra {
 				).into());
 			}
 
-			unsafe { cache_memory(data) }
+			let eras = if count == 1 {
+				vec![Range { start: CHECKSUM_SIZE, end: mmap.len() }]
+			} else {
+				Self::read_eras(file.as_ref(), data, count)?
+			};
+
+			// transactions of consecutive eras are stored one after another
+			let cache = unsafe { cache_memory(&mmap.as_slice()[eras[0].start..]) };
+			(eras, cache)
 		};
 
 		let era = JournalEra {
 			file: file.as_ref().to_path_buf(),
-			mmap,
+			mmap: Arc::new(mmap),
+			first,
+			eras,
+			skipped: 0,
 			cache,
 		};
 
 		Ok(era)
 	}
 
+	/// Returns the file without `eras` more of its oldest eras, which have been flushed.
+	///
+	/// At least one era of the file must be left.
+	fn skip(&self, eras: usize) -> JournalEra {
+		let skipped = self.skipped + eras;
+		let cache = unsafe { cache_memory(&self.mmap.as_slice()[self.eras[skipped].start..]) };
+
+		JournalEra {
+			file: self.file.clone(),
+			mmap: self.mmap.clone(),
+			first: self.first,
+			eras: self.eras.clone(),
+			skipped,
+			cache,
+		}
+	}
+
+	/// Returns ranges of transactions of `count` eras squashed in the file with given `data`.
+	fn read_eras(file: &Path, data: &[u8], count: usize) -> Result<Vec<Range<usize>>> {
+		let header_len = 4 + 8 * count;
+		if data.len() < header_len || LittleEndian::read_u32(data) as usize != count {
+			return Err(ErrorKind::CorruptedJournal(file.into(), format!("Expected {} squashed eras", count)).into());
+		}
+
+		let mut start = CHECKSUM_SIZE + header_len;
+		let eras: Vec<_> = data[4..header_len].chunks(8).map(|len| {
+			let era = start..start + LittleEndian::read_u64(len) as usize;
+			start = era.end;
+			era
+		}).collect();
+
+		if start != CHECKSUM_SIZE + data.len() {
+			return Err(ErrorKind::CorruptedJournal(file.into(), "Invalid length of squashed eras".into()).into());
+		}
+
+		Ok(eras)
+	}
+
+	/// Returns index of the first era in the file, which hasn't been flushed.
+	pub fn first(&self) -> u64 {
+		self.first + self.skipped as u64
+	}
+
+	/// Returns number of eras in the file, which haven't been flushed.
+	pub fn len(&self) -> usize {
+		self.eras.len() - self.skipped
+	}
+
+	/// Returns transactions of all eras in the file, from the oldest one, including flushed ones.
+	fn transactions(&self) -> Vec<&[u8]> {
+		let data = unsafe { self.mmap.as_slice() };
+		self.eras.iter().map(|era| &data[era.clone()]).collect()
+	}
+
 	fn get<'a>(&'a self, key: &[u8]) -> Option<JournalOperation<&'a [u8]>> {
 		let key = JournalSlice::new(key);
 
@@ -133,77 +317,175 @@ impl JournalEra {
 			None => None,
 			Some(&JournalOperation::Insert(ref value)) => Some(JournalOperation::Insert(unsafe { value.as_slice() })),
 			Some(&JournalOperation::Delete) => Some(JournalOperation::Delete),
+			Some(&JournalOperation::Merge { ref base, ref operands }) => Some(JournalOperation::Merge {
+				base: base.as_ref().map(|value| value.as_ref().map(|value| unsafe { value.as_slice() })),
+				operands: operands.iter().map(|operand| unsafe { operand.as_slice() }).collect(),
+			}),
 		}
 	}
 
-	fn operations(&self) -> BTreeSet<Operation> {
-		let mut ops = BTreeSet::new();
+	/// Returns operations of eras in the file, which haven't been flushed, in the order they were written.
+	fn operations(&self) -> OperationsIterator {
+		unsafe { OperationsIterator::new(&self.mmap.as_slice()[self.eras[self.skipped].start..]) }
+	}
 
-		for o in unsafe { OperationsIterator::new(&self.mmap.as_slice()[CHECKSUM_SIZE..]) } {
-			ops.replace(o);
+	/// Returns operations of eras in the file within eras `from..to`, which haven't been flushed.
+	fn operations_between(&self, from: u64, to: u64) -> OperationsIterator {
+		let from = cmp::max(from, self.first());
+		let to = cmp::min(to, self.first() + self.len() as u64);
+		if from >= to {
+			return unsafe { OperationsIterator::new(&[]) };
 		}
 
-		ops
+		let start = self.eras[(from - self.first) as usize].start;
+		let end = self.eras[(to - self.first - 1) as usize].end;
+		unsafe { OperationsIterator::new(&self.mmap.as_slice()[start..end]) }
+	}
+
+	/// Returns an iterator over entries of `eras` oldest eras in the file, which haven't been flushed.
+	///
+	/// Merge operands follow the operation they're applied to.
+	pub fn iter_front(&self, eras: usize) -> vec::IntoIter<Operation> {
+		stack_operations(self.operations_between(self.first(), self.first() + eras as u64)).into_iter()
 	}
 
-	/// Returns an iterator over era entries
-	pub fn iter(&self) -> btree_set::IntoIter<Operation> {
-		self.operations().into_iter()
+	/// Returns size of the era file in bytes.
+	pub fn file_size(&self) -> u64 {
+		self.mmap.len() as u64
 	}
 
-	/// Deletes underlying file
-	pub fn delete(self) -> Result<()> {
-		fs::remove_file(self.file)?;
+	/// Deletes underlying file. Era memory remains valid until it's dropped.
+	pub fn delete(&self) -> Result<()> {
+		fs::remove_file(&self.file)?;
 		Ok(())
 	}
 }
 
+/// A transaction written to the journal directory, which is not committed yet.
+///
+/// Dropping it deletes the underlying file.
+#[derive(Debug)]
+pub struct PreparedTransaction {
+	era: Option<JournalEra>,
+	conditions: Vec<Condition>,
+	/// Counter of prepared transactions of the journal the transaction was prepared for.
+	journal: Arc<AtomicUsize>,
+}
+
+impl PreparedTransaction {
+	/// Returns preconditions of the transaction, which need to be checked before it's applied.
+	pub(crate) fn conditions(&self) -> &[Condition] {
+		&self.conditions
+	}
+
+	fn into_era(mut self) -> JournalEra {
+		self.era.take().expect("era is taken only when prepared transaction is consumed; qed")
+	}
+}
+
+impl Drop for PreparedTransaction {
+	fn drop(&mut self) {
+		if let Some(era) = self.era.take() {
+			let _ = era.delete();
+		}
+	}
+}
+
 mod dir {
+	use std::cmp;
 	use std::fs::read_dir;
 	use std::path::{Path, PathBuf};
 	use error::{ErrorKind, Result};
 
 	const ERA_EXTENSION: &str = ".era";
+	const PREPARED_EXTENSION: &str = ".prepared";
+	/// Separates indexes of the first and the last era in names of files squashing many eras.
+	const SQUASHED_SEPARATOR: char = '-';
+
+	/// Era files in the journal directory.
+	pub struct EraFiles {
+		/// Files of consecutive eras, from the oldest one.
+		pub eras: Vec<PathBuf>,
+		/// Files of eras, which are also in a file squashing more eras.
+		///
+		/// They are left if squashing eras or rolling them back is interrupted.
+		/// The file squashing more eras is written first, so it's always complete.
+		pub squashed: Vec<PathBuf>,
+	}
 
-	pub fn era_files<P: AsRef<Path>>(dir: P) -> Result<Vec<PathBuf>> {
+	pub fn era_files<P: AsRef<Path>>(dir: P) -> Result<EraFiles> {
 		if !dir.as_ref().is_dir() {
 			return Err(ErrorKind::InvalidJournalLocation(dir.as_ref().into()).into());
 		}
 
-		let mut era_files: Vec<_> = read_dir(dir)?
+		let mut era_files = read_dir(dir)?
 			.collect::<::std::result::Result<Vec<_>, _>>()?
 			.into_iter()
 			.filter(|entry| entry.file_name().to_string_lossy().ends_with(ERA_EXTENSION))
 			.map(|entry| entry.path())
-			.collect();
-
-		era_files.sort();
+			.map(|path| era_range(&path).map(|range| (range, path)))
+			.collect::<Result<Vec<_>>>()?;
 
-		let mut last = None;
+		// files squashing more eras go before the ones starting with the same era
+		era_files.sort_by_key(|&((first, last), _)| (first, cmp::Reverse(last)));
 
-		for era in &era_files {
-			let idx = era_index(era)?;
-			match last.take() {
-				Some(era) if idx == era + 1 => {},
+		let mut files = EraFiles {
+			eras: Vec::new(),
+			squashed: Vec::new(),
+		};
+		let mut last_era = None;
+
+		for ((first, last), path) in era_files {
+			match last_era {
+				Some(era) if last <= era => {
+					files.squashed.push(path);
+					continue;
+				},
+				Some(era) if first == era + 1 => {},
 				None => {},
+				Some(era) if first <= era => {
+					return Err(ErrorKind::CorruptedJournal(
+						path,
+						format!("Eras overlap with the era {} of another file", era),
+					).into());
+				},
 				_ => {
-					return Err(ErrorKind::JournalEraMissing(idx).into());
+					return Err(ErrorKind::JournalEraMissing(first + 1).into());
 				}
 			}
-			last = Some(idx);
+			last_era = Some(last);
+			files.eras.push(path);
 		}
 
-		Ok(era_files)
+		Ok(files)
 	}
 
-	fn era_index<P: AsRef<Path>>(path: P) -> Result<u64> {
-		let path = path.as_ref()
+	/// Returns indexes of the first and the last era in the file.
+	pub fn era_range<P: AsRef<Path>>(path: P) -> Result<(u64, u64)> {
+		let stem = path.as_ref()
 			.file_stem()
 			.ok_or(ErrorKind::CorruptedJournal(
 				path.as_ref().into(),
 				"No file stem found".to_string()))?
 			.to_string_lossy();
-		Ok(1u64 + path.parse::<u64>()?)
+
+		let range = match stem.find(SQUASHED_SEPARATOR) {
+			Some(separator) => (stem[..separator].parse::<u64>()?, stem[separator + 1..].parse::<u64>()?),
+			None => {
+				let era = stem.parse::<u64>()?;
+				(era, era)
+			},
+		};
+
+		if range.0 > range.1 {
+			return Err(ErrorKind::CorruptedJournal(path.as_ref().into(), "Invalid range of eras".to_string()).into());
+		}
+
+		Ok(range)
+	}
+
+	fn era_index<P: AsRef<Path>>(path: P) -> Result<u64> {
+		Ok(1u64 + era_range(path)?.1)
 	}
 
 	pub fn next_era_index<P: AsRef<Path>>(files: &[P]) -> Result<u64> {
@@ -219,9 +501,43 @@ mod dir {
 		dir
 	}
 
+	/// Returns the name of the file with eras from `first` to `last`.
+	pub fn era_range_filename<P: AsRef<Path>>(dir: P, first: u64, last: u64) -> PathBuf {
+		if first == last {
+			return next_era_filename(dir, first);
+		}
+
+		let mut dir = dir.as_ref().to_path_buf();
+		dir.push(format!("{}{}{}{}", first, SQUASHED_SEPARATOR, last, ERA_EXTENSION));
+		dir
+	}
+
+	/// Returns files of transactions which were prepared, but never committed.
+	pub fn prepared_files<P: AsRef<Path>>(dir: P) -> Result<Vec<PathBuf>> {
+		let prepared_files = read_dir(dir)?
+			.collect::<::std::result::Result<Vec<_>, _>>()?
+			.into_iter()
+			.filter(|entry| entry.file_name().to_string_lossy().ends_with(PREPARED_EXTENSION))
+			.map(|entry| entry.path())
+			.collect();
+
+		Ok(prepared_files)
+	}
+
+	pub fn prepared_filename<P: AsRef<Path>>(dir: P, index: usize) -> PathBuf {
+		let mut dir = dir.as_ref().to_path_buf();
+		dir.push(format!("{}{}", index, PREPARED_EXTENSION));
+		dir
+	}
+
 	#[cfg(test)]
 	mod tests {
-		use super::era_index;
+		extern crate tempdir;
+
+		use std::fs;
+		use std::path::PathBuf;
+		use error::ErrorKind;
+		use super::{era_files, era_index, era_range};
 
 		#[test]
 		fn test_era_index() {
@@ -230,87 +546,422 @@ mod dir {
 			assert!(era_index("a.era").is_err());
 			assert!(era_index("/path/..").is_err());
 		}
+
+		#[test]
+		fn test_era_range() {
+			assert_eq!((3, 3), era_range("/path/3.era").unwrap());
+			assert_eq!((3, 12), era_range("/path/3-12.era").unwrap());
+			assert_eq!(13u64, era_index("3-12.era").unwrap());
+			assert!(era_range("12-3.era").is_err());
+			assert!(era_range("3-.era").is_err());
+		}
+
+		#[test]
+		fn test_era_files() {
+			let temp = tempdir::TempDir::new("test_era_files").unwrap();
+			let create = |name: &str| fs::write(temp.path().join(name), b"").unwrap();
+			let names = |paths: Vec<PathBuf>| paths.iter()
+				.map(|path| path.file_name().unwrap().to_string_lossy().into_owned())
+				.collect::<Vec<_>>();
+
+			for name in &["2.era", "3.era", "4.era", "9.era", "10.era", "11.era"] {
+				create(name);
+			}
+			assert_eq!(*era_files(temp.path()).err().unwrap().kind(), ErrorKind::JournalEraMissing(10));
+
+			// eras squashed before a crash
+			create("2-8.era");
+			let files = era_files(temp.path()).unwrap();
+			assert_eq!(names(files.eras), vec!["2-8.era", "9.era", "10.era", "11.era"]);
+			assert_eq!(names(files.squashed), vec!["2.era", "3.era", "4.era"]);
+
+			create("5-9.era");
+			assert!(era_files(temp.path()).is_err());
+		}
+	}
+}
+
+/// Writes transactions to the journal directory, so that they're applied to the journal later.
+///
+/// It's shared by the journal and handles preparing transactions from other threads.
+#[derive(Debug, Clone)]
+pub struct JournalPreparer {
+	dir: PathBuf,
+	next_prepared_index: Arc<AtomicUsize>,
+	durability: Durability,
+}
+
+impl JournalPreparer {
+	/// Returns the path of a new file in the journal directory, which is not a part of the journal yet.
+	fn next_prepared_filename(&self) -> PathBuf {
+		let index = self.next_prepared_index.fetch_add(1, atomic::Ordering::SeqCst);
+		dir::prepared_filename(&self.dir, index)
+	}
+
+	/// Writes the transaction to a file, which is not a part of the journal yet.
+	pub fn prepare(&self, transaction: &Transaction) -> Result<PreparedTransaction> {
+		let era = JournalEra::create(self.next_prepared_filename(), transaction, self.durability)?;
+
+		Ok(PreparedTransaction {
+			era: Some(era),
+			conditions: transaction.conditions().to_vec(),
+			journal: self.next_prepared_index.clone(),
+		})
 	}
 }
 
 #[derive(Debug)]
 pub struct Journal {
 	dir: PathBuf,
-	eras: VecDeque<JournalEra>,
+	/// Era files, from the oldest one.
+	eras: VecDeque<Arc<JournalEra>>,
 	next_era_index: u64,
+	preparer: JournalPreparer,
+	durability: Durability,
+	read_only: bool,
 }
 
 impl Journal {
-	pub fn open<P: AsRef<Path>>(jdir: P) -> Result<Self> {
-		let era_files = dir::era_files(&jdir)?;
+	/// Opens the journal, which syncs its files according to `durability`.
+	pub fn open<P: AsRef<Path>>(jdir: P, durability: Durability) -> Result<Self> {
+		// transactions prepared before the journal was closed can't be committed anymore
+		for file in dir::prepared_files(&jdir)? {
+			fs::remove_file(file)?;
+		}
+
+		// files left by interrupted squashing or rollback
+		for file in dir::era_files(&jdir)?.squashed {
+			fs::remove_file(file)?;
+		}
+
+		Self::load(jdir, durability, false)
+	}
+
+	/// Opens the journal without modifying the journal directory.
+	pub fn open_read_only<P: AsRef<Path>>(jdir: P) -> Result<Self> {
+		// nothing is written to read-only journal
+		Self::load(jdir, Durability::None, true)
+	}
+
+	fn load<P: AsRef<Path>>(jdir: P, durability: Durability, read_only: bool) -> Result<Self> {
+		let era_files = dir::era_files(&jdir)?.eras;
 		let next_era_index = dir::next_era_index(&era_files)?;
 
 		let eras = era_files.into_iter()
-			.map(JournalEra::open)
+			.map(|file| {
+				let (first, last) = dir::era_range(&file)?;
+				JournalEra::open_eras(file, first, (last - first + 1) as usize).map(Arc::new)
+			})
 			.collect::<Result<VecDeque<_>>>()?;
 
 		let journal = Journal {
 			dir: jdir.as_ref().to_path_buf(),
 			eras,
 			next_era_index,
+			preparer: JournalPreparer {
+				dir: jdir.as_ref().to_path_buf(),
+				next_prepared_index: Arc::new(AtomicUsize::new(0)),
+				durability,
+			},
+			durability,
+			read_only,
 		};
 
 		Ok(journal)
 	}
 
 	pub fn push(&mut self, transaction: &Transaction) -> Result<()> {
+		let prepared = self.prepare(transaction)?;
+		self.apply(prepared)
+	}
+
+	/// Writes the transaction to a file, which is not a part of the journal yet.
+	pub fn prepare(&self, transaction: &Transaction) -> Result<PreparedTransaction> {
+		self.preparer.prepare(transaction)
+	}
+
+	/// Returns a handle preparing transactions of the journal, which can be sent to other threads.
+	pub fn preparer(&self) -> JournalPreparer {
+		self.preparer.clone()
+	}
+
+	/// Appends prepared transaction to the journal as a new era.
+	///
+	/// Fails if the transaction was prepared for another journal.
+	pub fn apply(&mut self, prepared: PreparedTransaction) -> Result<()> {
+		if !Arc::ptr_eq(&prepared.journal, &self.preparer.next_prepared_index) {
+			bail!(ErrorKind::ForeignTransaction);
+		}
+
+		let mut era = prepared.into_era();
 		let new_path = dir::next_era_filename(&self.dir, self.next_era_index);
-		self.next_era_index += 1;
+		if let Err(err) = fs::rename(&era.file, &new_path) {
+			let _ = era.delete();
+			return Err(err.into());
+		}
 
-		let new_era = JournalEra::create(new_path, &transaction)?;
-		self.eras.push_back(new_era);
+		era.file = new_path;
+		// the era is committed once its directory entry is synced, so it's visible only afterwards
+		if let Err(err) = self.durability.sync_dir(&self.dir) {
+			let _ = era.delete();
+			return Err(err.into());
+		}
 
+		era.first = self.next_era_index;
+		self.next_era_index += 1;
+		self.eras.push_back(Arc::new(era));
 		Ok(())
 	}
 
-	pub fn drain_front(&mut self, elems: usize) -> Drain<JournalEra> {
-		self.eras.drain(..elems)
-	}
+	/// Writes transactions of consecutive eras starting with the `first` one to a new era file.
+	///
+	/// The file is written completely before it's moved to the journal.
+	fn write_eras(&self, first: u64, transactions: &[&[u8]]) -> Result<JournalEra> {
+		let path = self.preparer.next_prepared_filename();
+		JournalEra::write(&path, transactions, self.durability)?;
+
+		let last = first + transactions.len() as u64 - 1;
+		let new_path = dir::era_range_filename(&self.dir, first, last);
+		if let Err(err) = fs::rename(&path, &new_path) {
+			let _ = fs::remove_file(&path);
+			return Err(err.into());
+		}
+		self.durability.sync_dir(&self.dir)?;
 
-	pub fn len(&self) -> usize {
-		self.eras.len()
+		JournalEra::open_eras(new_path, first, transactions.len())
 	}
 
-	pub fn get<'a>(&'a self, key: &[u8]) -> Option<&'a [u8]> {
-		for era in self.eras.iter().rev() {
-			if let Some(operation) = era.get(&key) {
-				return match operation {
-					JournalOperation::Insert(insert) => Some(insert),
-					JournalOperation::Delete => None,
+	/// Removes eras older than `next_era`, which have already been flushed.
+	///
+	/// Their files are deleted unless the journal is read-only. Files squashing
+	/// many eras are deleted only once all of their eras are flushed, until then
+	/// the flushed ones are skipped. New eras get indexes starting with `next_era`
+	/// at least, so that they're never mistaken for flushed ones.
+	pub fn skip_flushed(&mut self, next_era: u64) -> Result<()> {
+		let mut deleted = false;
+		while let Some(era) = self.eras.front().cloned() {
+			if era.first() + era.len() as u64 > next_era {
+				if era.first() < next_era {
+					self.eras[0] = Arc::new(era.skip((next_era - era.first()) as usize));
 				}
+				break;
+			}
+
+			if !self.read_only {
+				era.delete()?;
+				deleted = true;
 			}
+			self.eras.pop_front();
 		}
 
-		None
+		if deleted {
+			self.durability.sync_dir(&self.dir)?;
+		}
+		self.next_era_index = cmp::max(self.next_era_index, next_era);
+		Ok(())
+	}
+
+	/// Returns the oldest era file.
+	pub fn front(&self) -> Option<&Arc<JournalEra>> {
+		self.eras.front()
+	}
+
+	/// Returns index of the oldest era in the journal or the index of the next era if it's empty.
+	pub fn first_era_index(&self) -> u64 {
+		self.eras.front().map_or(self.next_era_index, |era| era.first())
+	}
+
+	/// Removes `elems` newest eras and deletes their files.
+	///
+	/// Eras are deleted starting from the newest one, so the remaining
+	/// era files are always consecutive. If only some eras of a file are removed,
+	/// the remaining ones are written to a new file before the old one is removed.
+	pub fn pop_back(&mut self, elems: usize) -> Result<()> {
+		if elems > self.len() {
+			return Err(ErrorKind::InvalidRollback(elems, self.len()).into());
+		}
+
+		let mut elems = elems;
+		while elems > 0 {
+			let era = self.eras.back().cloned().expect("number of eras has been checked; qed");
+			let removed = cmp::min(era.len(), elems);
+			// eras are removed from memory only once their file is removed
+			let remaining = if removed < era.len() {
+				let remaining = self.replace_eras(&era, removed)?;
+				Some(remaining.skip(era.skipped))
+			} else {
+				era.delete()?;
+				None
+			};
+
+			self.eras.pop_back();
+			self.eras.extend(remaining.map(Arc::new));
+			self.next_era_index -= removed as u64;
+			elems -= removed;
+		}
+
+		self.durability.sync_dir(&self.dir)?;
+		Ok(())
+	}
+
+	/// Writes eras of the file without `removed` newest ones to a new file and removes the old one.
+	///
+	/// The old file includes the eras of the new one, so it would be preferred
+	/// over it if both were left by a crash. It's moved out of the journal
+	/// atomically, so that the new file is used since then.
+	fn replace_eras(&self, era: &JournalEra, removed: usize) -> Result<JournalEra> {
+		let transactions = era.transactions();
+		let remaining = self.write_eras(era.first, &transactions[..transactions.len() - removed])?;
+
+		// prepared files are deleted when the journal is opened
+		let discarded = self.preparer.next_prepared_filename();
+		if let Err(err) = fs::rename(&era.file, &discarded) {
+			let _ = remaining.delete();
+			return Err(err.into());
+		}
+
+		fs::remove_file(&discarded)?;
+		Ok(remaining)
+	}
+
+	/// Squashes era files with eras older than `keep` most recent ones into a single file.
+	///
+	/// Squashed file is written before the old ones are deleted, so they are
+	/// ignored and deleted when the journal is opened after a crash.
+	/// Eras are squashed only with the whole files they're in.
+	///
+	/// Returns number of squashed files.
+	pub fn squash(&mut self, keep: usize) -> Result<usize> {
+		let files = self.len().saturating_sub(keep);
+		let files = {
+			let mut eras = 0;
+			self.eras.iter()
+				.take_while(|era| {
+					eras += era.len();
+					eras <= files
+				})
+				.count()
+		};
+
+		if files < 2 {
+			return Ok(0);
+		}
+
+		// flushed eras are squashed too, so that the squashed file includes the oldest one
+		let squashed = {
+			let transactions: Vec<_> = self.eras.iter()
+				.take(files)
+				.flat_map(|era| era.transactions())
+				.collect();
+			self.write_eras(self.eras[0].first, &transactions)?.skip(self.eras[0].skipped)
+		};
+
+		let old: Vec<_> = self.eras.drain(..files).collect();
+		self.eras.push_front(Arc::new(squashed));
+		for era in old {
+			era.delete()?;
+		}
+		self.durability.sync_dir(&self.dir)?;
+
+		Ok(files)
+	}
+
+	/// Returns number of eras in the journal.
+	pub fn len(&self) -> usize {
+		self.eras.iter().map(|era| era.len()).sum()
+	}
+
+	/// Returns total size of era files in bytes.
+	pub fn file_size(&self) -> u64 {
+		self.eras.iter().map(|era| era.file_size()).sum()
+	}
+
+	/// Returns the most recent operations on the key or `None` if it's not in the journal.
+	pub fn get<'a>(&'a self, key: &[u8]) -> Option<JournalEntry<'a>> {
+		get(self.eras.iter(), key)
 	}
 
 	/// Returns an iterator over the journal entries across all eras
-	pub fn iter(&self) -> btree_set::IntoIter<Operation> {
-		let mut ops = BTreeSet::new();
-		for era in self.eras.iter() {
-			// append should take the value from `era` for keys that are equal
-			ops.append(&mut era.operations())
+	///
+	/// Merge operands follow the operation they're applied to.
+	pub fn iter(&self) -> vec::IntoIter<Operation> {
+		iter(self.eras.iter())
+	}
+
+	/// Returns an iterator over the journal entries of eras `from..to`.
+	pub fn iter_eras(&self, from: u64, to: u64) -> vec::IntoIter<Operation> {
+		stack_operations(self.eras.iter().flat_map(|era| era.operations_between(from, to))).into_iter()
+	}
+
+	/// Returns current journal eras, which are not affected by later changes of the journal.
+	pub fn snapshot(&self) -> JournalSnapshot {
+		JournalSnapshot {
+			eras: self.eras.iter().cloned().collect(),
 		}
+	}
+}
+
+/// Journal eras pinned at some point in time.
+#[derive(Debug, Clone)]
+pub struct JournalSnapshot {
+	eras: Vec<Arc<JournalEra>>,
+}
+
+impl JournalSnapshot {
+	/// Returns the most recent operations on the key or `None` if it's not in the journal.
+	pub fn get<'a>(&'a self, key: &[u8]) -> Option<JournalEntry<'a>> {
+		get(self.eras.iter(), key)
+	}
+
+	/// Returns an iterator over the journal entries across all eras
+	pub fn iter(&self) -> vec::IntoIter<Operation> {
+		iter(self.eras.iter())
+	}
+}
+
+fn get<'a, I>(eras: I, key: &[u8]) -> Option<JournalEntry<'a>> where I: DoubleEndedIterator<Item = &'a Arc<JournalEra>> {
+	let mut operands = Vec::new();
+	for era in eras.rev() {
+		let base = match era.get(&key) {
+			None => continue,
+			Some(JournalOperation::Insert(insert)) => Some(insert),
+			Some(JournalOperation::Delete) => None,
+			Some(JournalOperation::Merge { base, operands: era_operands }) => {
+				// operands of older eras are applied first
+				operands.splice(0..0, era_operands);
+				match base {
+					Some(base) => base,
+					None => continue,
+				}
+			},
+		};
 
-		ops.into_iter()
+		return Some(JournalEntry { base: Some(base), operands });
+	}
+
+	if operands.is_empty() {
+		None
+	} else {
+		Some(JournalEntry { base: None, operands })
 	}
 }
 
+fn iter<'a, I>(eras: I) -> vec::IntoIter<Operation<'a>> where I: Iterator<Item = &'a Arc<JournalEra>> {
+	// operations from newer eras replace the ones with equal keys or stack on top of them
+	stack_operations(eras.flat_map(|era| era.operations())).into_iter()
+}
+
 #[cfg(test)]
 mod tests {
 	extern crate tempdir;
 
 	use self::tempdir::TempDir;
-	use std::fs;
+	use std::{fs, thread};
 	use std::io::Write;
 	use error::ErrorKind;
+	use options::Durability;
 	use transaction::{Operation, Transaction};
-	use super::{Journal, JournalEra, JournalOperation};
+	use super::{Journal, JournalEntry, JournalEra, JournalOperation};
 
 	#[test]
 	fn test_era_create() {
@@ -325,7 +976,7 @@ mod tests {
 		tx.insert(b"key2", b"value2").unwrap();
 		tx.delete(b"key3").unwrap();
 
-		let era = JournalEra::create(path, &tx).unwrap();
+		let era = JournalEra::create(path, &tx, Durability::default()).unwrap();
 		assert_eq!(JournalOperation::Insert(b"value" as &[u8]), era.get(b"key1").unwrap());
 		assert_eq!(JournalOperation::Insert(b"value2" as &[u8]), era.get(b"key2").unwrap());
 		assert_eq!(JournalOperation::Delete, era.get(b"key3").unwrap());
@@ -336,22 +987,150 @@ mod tests {
 	fn test_journal_new() {
 		let temp = TempDir::new("test_journal_new").unwrap();
 
-		let mut journal = Journal::open(temp.path()).unwrap();
+		let mut journal = Journal::open(temp.path(), Durability::default()).unwrap();
 		journal.push(&Transaction::new(1)).unwrap();
 		journal.push(&Transaction::new(1)).unwrap();
 		journal.push(&Transaction::new(1)).unwrap();
 		assert_eq!(journal.len(), 3);
 
-		journal.drain_front(2);
+		journal.skip_flushed(2).unwrap();
+
+		assert_eq!(journal.len(), 1);
+	}
+
+	#[test]
+	fn test_journal_pop_back() {
+		let temp = TempDir::new("test_journal_pop_back").unwrap();
+
+		let mut journal = Journal::open(temp.path(), Durability::default()).unwrap();
+		let mut tx = Transaction::new(4);
+		tx.insert(b"key1", b"value").unwrap();
+		journal.push(&tx).unwrap();
+
+		let mut tx = Transaction::new(4);
+		tx.insert(b"key1", b"value2").unwrap();
+		journal.push(&tx).unwrap();
+		journal.push(&Transaction::new(4)).unwrap();
+
+		assert_eq!(journal.get(b"key1"), Some(JournalEntry { base: Some(Some(b"value2" as &[u8])), operands: vec![] }));
+		assert_eq!(journal.pop_back(4).unwrap_err().kind(), &ErrorKind::InvalidRollback(4, 3));
+
+		journal.pop_back(2).unwrap();
+		assert_eq!(journal.len(), 1);
+		assert_eq!(journal.get(b"key1"), Some(JournalEntry { base: Some(Some(b"value" as &[u8])), operands: vec![] }));
+
+		journal.push(&Transaction::new(4)).unwrap();
+		let journal = Journal::open(temp.path(), Durability::default()).unwrap();
+		assert_eq!(journal.len(), 2);
+		assert_eq!(journal.next_era_index, 2);
+	}
+
+	#[test]
+	fn test_journal_squash() {
+		let temp = TempDir::new("test_journal_squash").unwrap();
+		let names = || {
+			let mut names = fs::read_dir(temp.path()).unwrap()
+				.map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+				.collect::<Vec<_>>();
+			names.sort();
+			names
+		};
+
+		let mut journal = Journal::open(temp.path(), Durability::default()).unwrap();
+		for (key, value) in &[(b"key2", b"value0"), (b"key3", b"value1"), (b"key4", b"value2"), (b"key5", b"value3")] {
+			let mut tx = Transaction::new(4);
+			tx.insert(b"key1", value).unwrap();
+			tx.insert(key, b"value").unwrap();
+			journal.push(&tx).unwrap();
+		}
+
+		assert_eq!(journal.squash(4).unwrap(), 0);
+		assert_eq!(journal.squash(1).unwrap(), 3);
+		assert_eq!(journal.squash(1).unwrap(), 0);
+		assert_eq!(journal.len(), 4);
+		assert_eq!(names(), vec!["0-2.era", "3.era"]);
+		assert_eq!(journal.get(b"key1"), Some(JournalEntry { base: Some(Some(b"value3" as &[u8])), operands: vec![] }));
+		assert_eq!(journal.get(b"key2"), Some(JournalEntry { base: Some(Some(b"value" as &[u8])), operands: vec![] }));
+		assert_eq!(journal.iter().count(), 5);
+
+		journal.pop_back(2).unwrap();
+		assert_eq!(journal.len(), 2);
+		assert_eq!(journal.next_era_index, 2);
+		assert_eq!(names(), vec!["0-1.era"]);
+		assert_eq!(journal.get(b"key1"), Some(JournalEntry { base: Some(Some(b"value1" as &[u8])), operands: vec![] }));
+		assert_eq!(journal.get(b"key4"), None);
+
+		journal.push(&Transaction::new(4)).unwrap();
+		// files of eras left by a crash before they were deleted
+		fs::copy(temp.path().join("2.era"), temp.path().join("1.era")).unwrap();
+
+		let read_only = Journal::open_read_only(temp.path()).unwrap();
+		assert_eq!(read_only.len(), 3);
+		assert_eq!(names(), vec!["0-1.era", "1.era", "2.era"]);
+
+		let mut journal = Journal::open(temp.path(), Durability::default()).unwrap();
+		assert_eq!(journal.len(), 3);
+		assert_eq!(journal.next_era_index, 3);
+		assert_eq!(names(), vec!["0-1.era", "2.era"]);
+		assert_eq!(journal.get(b"key1"), Some(JournalEntry { base: Some(Some(b"value1" as &[u8])), operands: vec![] }));
+
+		// flushed eras are skipped until the whole file is flushed
+		journal.skip_flushed(1).unwrap();
+		assert_eq!(journal.len(), 2);
+		assert_eq!(journal.first_era_index(), 1);
+		assert_eq!(names(), vec!["0-1.era", "2.era"]);
+		assert_eq!(journal.get(b"key2"), None);
+		assert_eq!(journal.get(b"key1"), Some(JournalEntry { base: Some(Some(b"value1" as &[u8])), operands: vec![] }));
+		assert_eq!(journal.iter().count(), 2);
+		assert_eq!(journal.iter_eras(0, 2).count(), 2);
+
+		journal.skip_flushed(2).unwrap();
+		assert_eq!(journal.len(), 1);
+		assert_eq!(names(), vec!["2.era"]);
+		assert_eq!(journal.get(b"key1"), None);
+	}
+
+	#[test]
+	fn test_journal_prepare_and_apply() {
+		let temp = TempDir::new("test_journal_prepare_and_apply").unwrap();
+
+		let mut journal = Journal::open(temp.path(), Durability::default()).unwrap();
+		let mut tx = Transaction::new(4);
+		tx.insert(b"key1", b"value").unwrap();
+
+		let prepared = journal.prepare(&tx).unwrap();
+		let dropped = journal.prepare(&tx).unwrap();
+		assert_eq!(journal.len(), 0);
+		assert_eq!(journal.get(b"key1"), None);
+
+		let prepared = thread::spawn(move || prepared).join().unwrap();
+		journal.apply(prepared).unwrap();
+		drop(dropped);
 
 		assert_eq!(journal.len(), 1);
+		assert_eq!(journal.get(b"key1"), Some(JournalEntry { base: Some(Some(b"value" as &[u8])), operands: vec![] }));
+		assert_eq!(fs::read_dir(temp.path()).unwrap().count(), 1);
+
+		let mut journal = Journal::open(temp.path(), Durability::default()).unwrap();
+		assert_eq!(journal.get(b"key1"), Some(JournalEntry { base: Some(Some(b"value" as &[u8])), operands: vec![] }));
+
+		// transactions prepared by a handle are applied to its journal only
+		let other = TempDir::new("test_journal_prepare_and_apply_other").unwrap();
+		let other = Journal::open(other.path(), Durability::default()).unwrap();
+		let prepared = other.prepare(&tx).unwrap();
+		assert_eq!(*journal.apply(prepared).unwrap_err().kind(), ErrorKind::ForeignTransaction);
+
+		let preparer = journal.preparer();
+		let prepared = thread::spawn(move || preparer.prepare(&tx).unwrap()).join().unwrap();
+		journal.apply(prepared).unwrap();
+		assert_eq!(journal.len(), 2);
 	}
 
 	#[test]
 	fn test_journal_iter() {
 		let temp = TempDir::new("test_journal_iter").unwrap();
 
-		let mut journal = Journal::open(temp.path()).unwrap();
+		let mut journal = Journal::open(temp.path(), Durability::default()).unwrap();
 
 		let mut tx1 = Transaction::new(4);
 		tx1.insert(b"key1", b"value").unwrap();
@@ -379,6 +1158,51 @@ mod tests {
 		);
 	}
 
+	#[test]
+	fn test_journal_merge() {
+		let temp = TempDir::new("test_journal_merge").unwrap();
+
+		let mut journal = Journal::open(temp.path(), Durability::default()).unwrap();
+
+		let mut tx1 = Transaction::new(4);
+		tx1.merge(b"key1", b"a").unwrap();
+		tx1.insert(b"key2", b"value").unwrap();
+		tx1.merge(b"key2", b"b").unwrap();
+		tx1.merge(b"key3", b"c").unwrap();
+		tx1.delete(b"key3").unwrap();
+		journal.push(&tx1).unwrap();
+
+		assert_eq!(journal.eras[0].get(b"key1"), Some(JournalOperation::Merge { base: None, operands: vec![b"a" as &[u8]] }));
+		assert_eq!(journal.eras[0].get(b"key2"), Some(JournalOperation::Merge { base: Some(Some(b"value" as &[u8])), operands: vec![b"b" as &[u8]] }));
+		assert_eq!(journal.eras[0].get(b"key3"), Some(JournalOperation::Delete));
+
+		let mut tx2 = Transaction::new(4);
+		tx2.merge(b"key1", b"d").unwrap();
+		tx2.merge(b"key2", b"e").unwrap();
+		tx2.merge(b"key3", b"f").unwrap();
+		tx2.merge(b"key1", b"g").unwrap();
+		journal.push(&tx2).unwrap();
+
+		assert_eq!(journal.get(b"key1"), Some(JournalEntry { base: None, operands: vec![b"a" as &[u8], b"d", b"g"] }));
+		assert_eq!(journal.get(b"key2"), Some(JournalEntry { base: Some(Some(b"value" as &[u8])), operands: vec![b"b" as &[u8], b"e"] }));
+		assert_eq!(journal.get(b"key3"), Some(JournalEntry { base: Some(None), operands: vec![b"f" as &[u8]] }));
+		assert_eq!(journal.get(b"key4"), None);
+
+		assert_eq!(
+			journal.iter().collect::<Vec<_>>(),
+			vec![
+				Operation::Merge(b"key1" as &[u8], b"a" as &[u8]),
+				Operation::Merge(b"key1" as &[u8], b"d" as &[u8]),
+				Operation::Merge(b"key1" as &[u8], b"g" as &[u8]),
+				Operation::Insert(b"key2" as &[u8], b"value" as &[u8]),
+				Operation::Merge(b"key2" as &[u8], b"b" as &[u8]),
+				Operation::Merge(b"key2" as &[u8], b"e" as &[u8]),
+				Operation::Delete(b"key3" as &[u8]),
+				Operation::Merge(b"key3" as &[u8], b"f" as &[u8]),
+			]
+		);
+	}
+
 	#[test]
 	fn should_detect_corrupted_era() {
 		let temp = TempDir::new("test_era_create").unwrap();
@@ -391,7 +1215,7 @@ mod tests {
 		tx.insert(b"key3", b"value").unwrap();
 		tx.insert(b"key2", b"value2").unwrap();
 		tx.delete(b"key3").unwrap();
-		let _ = JournalEra::create(&path, &tx).unwrap();
+		let _ = JournalEra::create(&path, &tx, Durability::default()).unwrap();
 
 		// alter hash
 		let mut file = fs::OpenOptions::new().write(true).open(&path).unwrap();
@@ -19,6 +19,8 @@ impl<'a> Key<'a> {
 		self.prefix as usize * field::field_size(field_body_size)
 	}
 
+	/// Keys shorter than the prefix are padded with zeros,
+	/// so the prefixes are ordered the same as the keys.
 	fn read_prefix(key: &'a [u8], prefix_bits: u8) -> u32 {
 		let mut prefix = 0u32;
 		let pos = prefix_bits as usize / 8;
@@ -26,12 +28,12 @@ impl<'a> Key<'a> {
 
 		for i in 0..pos {
 			prefix <<= 8;
-			prefix |= key[i] as u32;
+			prefix |= key.get(i).cloned().unwrap_or(0) as u32;
 		}
 
 		if bits > 0 {
 			prefix <<= bits;
-			prefix |= key[pos] as u32 >> (8 - bits);
+			prefix |= key.get(pos).cloned().unwrap_or(0) as u32 >> (8 - bits);
 		}
 
 		prefix
@@ -57,4 +59,13 @@ mod tests {
 		assert_eq!(Key::new(&k, 26).prefix, 0x3fffb72);
 		assert_eq!(Key::new(&k, 32).prefix, 0xfffedcba);
 	}
+
+	#[test]
+	fn should_pad_short_keys_with_zeros() {
+		let k = vec![0xff];
+
+		assert_eq!(Key::new(&k, 4).prefix, 0xf);
+		assert_eq!(Key::new(&k, 12).prefix, 0xff0);
+		assert_eq!(Key::new(&[], 8).prefix, 0x0);
+	}
 }
@@ -49,6 +49,9 @@
 //! ```
 //!
 //! The index of the field for a record is determined using the first X bytes of the key.
+//!
+//! Records which are shifted past the last field are stored in the overflow region,
+//! which extends the end of the database file.
 
 #![warn(missing_docs)]
 
@@ -73,19 +76,35 @@ mod error;
 mod field;
 mod find;
 mod flush;
+mod flush_lock;
 mod journal;
 mod key;
+mod merge;
 mod metadata;
+mod migration;
+mod namespace;
 mod options;
 mod prefix_tree;
+mod preparer;
+mod reader;
 mod record;
+mod snapshot;
 mod space;
+mod stats;
 mod transaction;
+mod verify;
 
-pub use database::{Database, Value};
+pub use database::{Database, Value, ValueReader};
 pub use error::{Error, Result, ErrorKind};
-pub use options::{Options, ValuesLen};
+pub use journal::PreparedTransaction;
+pub use merge::{MergeOperator, SharedMergeOperator};
+pub use options::{DatabaseOptions, Durability, Options, ValuesLen};
+pub use preparer::Preparer;
+pub use reader::DatabaseReader;
 pub use record::Record;
-pub use transaction::Transaction;
+pub use snapshot::Snapshot;
+pub use stats::Stats;
+pub use transaction::{NamespaceTransaction, Transaction};
+pub use verify::{ConsistencyReport, Inconsistency};
 #[doc(hidden)]
 pub use prefix_tree::PrefixTree;
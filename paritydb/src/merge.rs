@@ -0,0 +1,127 @@
+//! Merge operators
+//!
+//! Merge operands are written to the journal like inserts. They stack on top
+//! of the previous value of the key until they're folded into a single value
+//! when the journal is flushed. Reads resolve them on the fly.
+
+use std::fmt;
+use std::ops::Deref;
+use std::sync::Arc;
+
+use error::{ErrorKind, Result};
+use options::ValuesLen;
+
+/// Combines merge operands with the value of a key.
+pub trait MergeOperator: Send + Sync {
+	/// Returns the value of the `key` after applying `operands` to the `existing` one.
+	///
+	/// Operands are ordered from the oldest one. `existing` is `None` if the key
+	/// is not in the database. If values have constant length, returned value
+	/// must be of that length.
+	fn merge(&self, key: &[u8], existing: Option<&[u8]>, operands: &[&[u8]]) -> Vec<u8>;
+}
+
+/// Merge operator shared by the database, its snapshots and readers.
+#[derive(Clone)]
+pub struct SharedMergeOperator(Arc<dyn MergeOperator>);
+
+impl SharedMergeOperator {
+	/// Creates new shared merge operator.
+	pub fn new<M: MergeOperator + 'static>(operator: M) -> Self {
+		SharedMergeOperator(Arc::new(operator))
+	}
+}
+
+impl Deref for SharedMergeOperator {
+	type Target = dyn MergeOperator;
+
+	fn deref(&self) -> &Self::Target {
+		&*self.0
+	}
+}
+
+impl fmt::Debug for SharedMergeOperator {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.write_str("SharedMergeOperator")
+	}
+}
+
+impl PartialEq for SharedMergeOperator {
+	fn eq(&self, other: &Self) -> bool {
+		Arc::ptr_eq(&self.0, &other.0)
+	}
+}
+
+/// Returns the merge operator or fails if it's not set.
+pub(crate) fn operator(merge_operator: &Option<SharedMergeOperator>) -> Result<&dyn MergeOperator> {
+	match *merge_operator {
+		Some(ref operator) => Ok(&**operator),
+		None => bail!(ErrorKind::MissingMergeOperator),
+	}
+}
+
+/// Returns the value of the `key` after applying `operands` to the `existing` one.
+///
+/// Fails if values have constant length and the merged value is of another length.
+pub(crate) fn merge(
+	merge_operator: &Option<SharedMergeOperator>,
+	value_len: &ValuesLen,
+	key: &[u8],
+	existing: Option<&[u8]>,
+	operands: &[&[u8]],
+) -> Result<Vec<u8>> {
+	let merged = operator(merge_operator)?.merge(key, existing, operands);
+	if let ValuesLen::Constant(len) = *value_len {
+		if merged.len() != len {
+			bail!(ErrorKind::InvalidMergedValueLen(key.to_vec(), len, merged.len()));
+		}
+	}
+
+	Ok(merged)
+}
+
+#[cfg(test)]
+mod tests {
+	use error::ErrorKind;
+	use options::{Options, ValuesLen};
+	use super::{merge, operator, MergeOperator, SharedMergeOperator};
+
+	struct Concat;
+
+	impl MergeOperator for Concat {
+		fn merge(&self, _key: &[u8], existing: Option<&[u8]>, operands: &[&[u8]]) -> Vec<u8> {
+			let mut value = existing.unwrap_or_default().to_vec();
+			for operand in operands {
+				value.extend_from_slice(operand);
+			}
+			value
+		}
+	}
+
+	#[test]
+	fn test_operator() {
+		let mut options = Options::default();
+		assert_eq!(*operator(&options.merge_operator).err().unwrap().kind(), ErrorKind::MissingMergeOperator);
+
+		options.merge_operator = Some(SharedMergeOperator::new(Concat));
+		let operator = operator(&options.merge_operator).unwrap();
+		assert_eq!(operator.merge(b"key", Some(b"a"), &[b"b", b"c"]), b"abc");
+		assert_eq!(operator.merge(b"key", None, &[b"b"]), b"b");
+		assert_eq!(options.clone(), options);
+		assert!(Some(SharedMergeOperator::new(Concat)) != options.merge_operator);
+	}
+
+	#[test]
+	fn test_merge() {
+		let merge_operator = Some(SharedMergeOperator::new(Concat));
+		let value_len = ValuesLen::Constant(2);
+		assert_eq!(merge(&merge_operator, &value_len, b"key", Some(b"a"), &[b"b"]).unwrap(), b"ab");
+		assert_eq!(
+			*merge(&merge_operator, &value_len, b"key", Some(b"a"), &[b"b", b"c"]).unwrap_err().kind(),
+			ErrorKind::InvalidMergedValueLen(b"key".to_vec(), 2, 3)
+		);
+
+		let value_len = ValuesLen::Variable { expected: 2 };
+		assert_eq!(merge(&merge_operator, &value_len, b"key", Some(b"a"), &[b"b", b"c"]).unwrap(), b"abc");
+	}
+}
@@ -1,23 +1,51 @@
+use options::Options;
 use prefix_tree::PrefixTree;
 
 /// A structure holding database metadata information.
 ///
 /// Currently we store a prefix tree for fast lookups and iterations
 /// and number of bytes occupied by records for determining if
-/// key prefix should be increased.
+/// key prefix should be increased. The number of prefix bits is stored
+/// as well, cause it grows together with the database. Options which
+/// determine the layout of the database are stored, so they can't be mistaken.
+/// Index of the next journal era to flush is stored together with
+/// the flushed data, so that eras are never flushed twice.
 #[derive(Debug, Clone)]
 pub struct Metadata {
 	/// Database version
 	pub db_version: u16,
-	/// Number of bytes occupied by records
-	/// NOTE: it does not include field headers!
+	/// Number of bytes occupied by records, including field headers
 	pub occupied_bytes: u64,
+	/// Index of the oldest journal era, which is not flushed yet.
+	/// Older eras left in the journal by a crash are skipped.
+	pub next_era: u64,
+	/// Options which determine the layout of the database.
+	/// Other options have default values.
+	pub options: Options,
 	/// Prefix tree
 	pub prefixes: PrefixTree,
 }
 
 impl Metadata {
-	pub const DB_VERSION: u16 = 0;
+	pub const DB_VERSION: u16 = 2;
+
+	/// Creates empty metadata for given options and prefix bits.
+	pub fn new(options: &Options, prefix_bits: u8) -> Self {
+		Metadata {
+			db_version: Self::DB_VERSION,
+			occupied_bytes: 0,
+			next_era: 0,
+			options: Options {
+				key_index_bits: options.key_index_bits,
+				key_len: options.key_len,
+				variable_key_len: options.variable_key_len,
+				value_len: options.value_len.clone(),
+				record_checksum: options.record_checksum,
+				..Options::default()
+			},
+			prefixes: PrefixTree::new(prefix_bits),
+		}
+	}
 
 	/// Notify that record was inserted.
 	pub fn insert_record(&mut self, prefix: u32, len: usize) {
@@ -49,6 +77,8 @@ impl Metadata {
 pub mod bytes {
 	use byteorder::{LittleEndian, ByteOrder};
 
+	use error::{ErrorKind, Result};
+	use options::{Options, ValuesLen};
 	use prefix_tree::PrefixTree;
 
 	/// Bytes representation of `Metadata`.
@@ -59,6 +89,9 @@ pub mod bytes {
 	impl<'a> Metadata<'a> {
 		const VERSION_SIZE: usize = 2;
 		const OCCUPIED_SIZE: usize = 8;
+		const PREFIX_BITS_SIZE: usize = 1;
+		const NEXT_ERA_SIZE: usize = 8;
+		pub const OPTIONS_SIZE: usize = 20;
 
 		/// Create new.
 		pub fn new(metadata: &'a super::Metadata) -> Self {
@@ -72,6 +105,9 @@ pub mod bytes {
 			data[leaves_offset()..].copy_from_slice(leaves);
 			LittleEndian::write_u16(data, self.metadata.db_version);
 			LittleEndian::write_u64(&mut data[Self::VERSION_SIZE..], self.metadata.occupied_bytes);
+			data[prefix_bits_offset()] = self.metadata.prefixes.prefix_bits();
+			LittleEndian::write_u64(&mut data[next_era_offset()..], self.metadata.next_era);
+			write_options(&self.metadata.options, &mut data[options_offset()..leaves_offset()]);
 		}
 
 		/// Return bytes length of the `Metadata`.
@@ -81,28 +117,112 @@ pub mod bytes {
 	}
 
 	#[inline]
-	pub fn leaves_offset() -> usize {
+	fn prefix_bits_offset() -> usize {
 		Metadata::VERSION_SIZE + Metadata::OCCUPIED_SIZE
 	}
 
+	#[inline]
+	fn next_era_offset() -> usize {
+		prefix_bits_offset() + Metadata::PREFIX_BITS_SIZE
+	}
+
+	#[inline]
+	fn options_offset() -> usize {
+		next_era_offset() + Metadata::NEXT_ERA_SIZE
+	}
+
+	#[inline]
+	fn leaves_offset() -> usize {
+		options_offset() + Metadata::OPTIONS_SIZE
+	}
+
+	/// Writes options which determine the layout of the database to given slice.
+	///
+	/// Lengths are stored as 8 bytes, other fields as single bytes.
+	///
+	/// ```text
+	/// | key_index_bits | key_len | variable_key_len | value_len type | value_len | record_checksum |
+	/// ```
+	pub fn write_options(options: &Options, data: &mut [u8]) {
+		assert_eq!(data.len(), Metadata::OPTIONS_SIZE);
+		data[0] = options.key_index_bits;
+		LittleEndian::write_u64(&mut data[1..9], options.key_len as u64);
+		data[9] = options.variable_key_len as u8;
+		let (value_len_type, value_len) = match options.value_len {
+			ValuesLen::Constant(len) => (0, len),
+			ValuesLen::Variable { expected } => (1, expected),
+		};
+		data[10] = value_len_type;
+		LittleEndian::write_u64(&mut data[11..19], value_len as u64);
+		data[19] = options.record_checksum as u8;
+	}
+
+	/// Reads options written by `write_options`. Other options have default values.
+	fn read_options(data: &[u8]) -> Options {
+		let value_len = LittleEndian::read_u64(&data[11..19]) as usize;
+		Options {
+			key_index_bits: data[0],
+			key_len: LittleEndian::read_u64(&data[1..9]) as usize,
+			variable_key_len: data[9] != 0,
+			value_len: match data[10] {
+				0 => ValuesLen::Constant(value_len),
+				_ => ValuesLen::Variable { expected: value_len },
+			},
+			record_checksum: data[19] != 0,
+			..Options::default()
+		}
+	}
+
+	/// Returns prefix bits of `Metadata` stored in given slice.
+	pub fn read_prefix_bits(data: &[u8]) -> u8 {
+		data[prefix_bits_offset()]
+	}
+
 	/// Returns expected `Metadata` bytes len given prefix bits.
 	pub fn len(prefix_bits: u8) -> usize {
 		leaves_offset() + PrefixTree::leaf_data_len(prefix_bits)
 	}
 
+	/// Fails if the slice is shorter than `len`.
+	pub fn check_len(data: &[u8], len: usize) -> Result<()> {
+		if data.len() < len {
+			bail!(ErrorKind::CorruptedMetadata(format!("Expected at least {} bytes, got {}", len, data.len())));
+		}
+
+		Ok(())
+	}
+
+	/// Returns version of `Metadata` stored in given slice.
+	pub fn read_version(data: &[u8]) -> Result<u16> {
+		check_len(data, Metadata::VERSION_SIZE)?;
+		Ok(LittleEndian::read_u16(&data[..Metadata::VERSION_SIZE]))
+	}
+
 	/// Read `Metadata` from given slice.
-	pub fn read(data: &[u8], prefix_bits: u8) -> super::Metadata {
-		let db_version = LittleEndian::read_u16(&data[..Metadata::VERSION_SIZE]);
-		let occupied_bytes = LittleEndian::read_u64(&data[Metadata::VERSION_SIZE..]);
-		let prefixes = PrefixTree::from_leaves(&data[leaves_offset()..], prefix_bits);
+	/// The slice may be longer than the `Metadata` it contains.
+	///
+	/// Fails if the `Metadata` has other than the current version or it's truncated.
+	pub fn read(data: &[u8]) -> Result<super::Metadata> {
+		let db_version = read_version(data)?;
+		if db_version != super::Metadata::DB_VERSION {
+			bail!(ErrorKind::UnsupportedVersion(db_version));
+		}
 
-		assert_eq!(db_version, super::Metadata::DB_VERSION);
+		check_len(data, leaves_offset())?;
+		let occupied_bytes = LittleEndian::read_u64(&data[Metadata::VERSION_SIZE..]);
+		let prefix_bits = read_prefix_bits(data);
+		check_len(data, len(prefix_bits))?;
+		let next_era = LittleEndian::read_u64(&data[next_era_offset()..]);
+		let options = read_options(&data[options_offset()..leaves_offset()]);
+		let prefixes = PrefixTree::from_leaves(&data[leaves_offset()..len(prefix_bits)], prefix_bits);
 
-		super::Metadata {
+		Ok(super::Metadata {
 			db_version,
 			occupied_bytes,
+			next_era,
+			options,
 			prefixes,
-		}
+		})
 	}
 }
 
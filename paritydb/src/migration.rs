@@ -0,0 +1,153 @@
+//! Migrations of databases created by older versions
+//!
+//! Each migration upgrades raw metadata of one version to the next one.
+//! Upgraded metadata is written by a flush, so an interrupted migration
+//! is finished when the database is opened again.
+//!
+//! Flushes which were pending when the database was closed by version 0
+//! are recovered before the migration. Flushes of later versions store
+//! their version in metadata and need to be recovered by the version which created them.
+
+use byteorder::{LittleEndian, ByteOrder};
+
+use error::{ErrorKind, Result};
+use metadata::{self, Metadata};
+use options::InternalOptions;
+use prefix_tree::PrefixTree;
+
+/// Upgrades raw metadata to the next version.
+type Migration = fn(&[u8], &InternalOptions) -> Result<Vec<u8>>;
+
+/// Migrations indexed by the version they upgrade from.
+const MIGRATIONS: [Migration; Metadata::DB_VERSION as usize] = [
+	migrate_v0,
+	migrate_v1,
+];
+
+/// Offset of leaves of the prefix tree in metadata of version 0.
+const LEAVES_OFFSET_V0: usize = 10;
+
+/// Version 0 didn't store prefix bits, cause databases never grew.
+///
+/// ```text
+///  version  occupied bytes  leaves
+///   /        /               /
+/// |..|........|...................|
+/// ```
+fn migrate_v0(data: &[u8], options: &InternalOptions) -> Result<Vec<u8>> {
+	let meta_len = meta_len_v0(options);
+	metadata::bytes::check_len(data, meta_len)?;
+	let leaves = &data[LEAVES_OFFSET_V0..meta_len];
+
+	let mut upgraded = vec![0u8; 2];
+	LittleEndian::write_u16(&mut upgraded, 1);
+	upgraded.extend_from_slice(&data[2..LEAVES_OFFSET_V0]);
+	upgraded.push(options.external.key_index_bits);
+	upgraded.extend_from_slice(leaves);
+	Ok(upgraded)
+}
+
+/// Version 1 didn't store options the database was created with
+/// and the index of the next journal era to flush.
+/// Options are assumed to be the ones the database is opened with.
+/// No era is assumed to be flushed.
+///
+/// ```text
+///  version  occupied bytes  prefix bits  leaves
+///   /        /               /            /
+/// |..|........|.|...................|
+/// ```
+fn migrate_v1(data: &[u8], options: &InternalOptions) -> Result<Vec<u8>> {
+	const LEAVES_OFFSET: usize = 11;
+
+	metadata::bytes::check_len(data, LEAVES_OFFSET)?;
+	let prefix_bits = data[LEAVES_OFFSET - 1];
+	metadata::bytes::check_len(data, LEAVES_OFFSET + PrefixTree::leaf_data_len(prefix_bits))?;
+	let leaves = &data[LEAVES_OFFSET..LEAVES_OFFSET + PrefixTree::leaf_data_len(prefix_bits)];
+
+	let mut upgraded = data[..LEAVES_OFFSET].to_vec();
+	LittleEndian::write_u16(&mut upgraded, 2);
+	upgraded.extend_from_slice(&[0u8; 8]);
+	let mut stored_options = [0u8; metadata::bytes::Metadata::OPTIONS_SIZE];
+	metadata::bytes::write_options(&options.external, &mut stored_options);
+	upgraded.extend_from_slice(&stored_options);
+	upgraded.extend_from_slice(leaves);
+	Ok(upgraded)
+}
+
+/// Returns length of metadata of version 0, which never grew.
+pub fn meta_len_v0(options: &InternalOptions) -> usize {
+	LEAVES_OFFSET_V0 + PrefixTree::leaf_data_len(options.external.key_index_bits)
+}
+
+/// Returns metadata upgraded to the current version
+/// or `None` if it already has the current version.
+pub fn migrate(data: &[u8], options: &InternalOptions) -> Result<Option<Metadata>> {
+	let mut version = metadata::bytes::read_version(data)?;
+	if version == Metadata::DB_VERSION {
+		return Ok(None);
+	}
+
+	if version > Metadata::DB_VERSION {
+		bail!(ErrorKind::UnsupportedVersion(version));
+	}
+
+	let mut data = data.to_vec();
+	while version < Metadata::DB_VERSION {
+		data = MIGRATIONS[version as usize](&data, options)?;
+		version += 1;
+	}
+
+	metadata::bytes::read(&data).map(Some)
+}
+
+#[cfg(test)]
+mod tests {
+	use error::ErrorKind;
+	use metadata::Metadata;
+	use options::{InternalOptions, Options};
+	use super::migrate;
+
+	#[test]
+	fn test_migrate_v0() {
+		let options = InternalOptions::from_external(Options {
+			key_index_bits: 4,
+			key_len: 3,
+			..Default::default()
+		}).unwrap();
+		let data = [
+			0, 0,
+			100, 0, 0, 0, 0, 0, 0, 0,
+			0b00000001, 0b00000010,
+		];
+
+		let metadata = migrate(&data, &options).unwrap().unwrap();
+		assert_eq!(metadata.db_version, Metadata::DB_VERSION);
+		assert_eq!(metadata.occupied_bytes, 100);
+		assert_eq!(metadata.prefixes.prefix_bits(), 4);
+		assert_eq!(metadata.prefixes.prefixes_iter().collect::<Vec<_>>(), vec![0, 9]);
+		assert_eq!(metadata.options, options.external);
+
+		assert_eq!(
+			*migrate(&data[..11], &options).unwrap_err().kind(),
+			ErrorKind::CorruptedMetadata("Expected at least 12 bytes, got 11".into())
+		);
+		assert_eq!(
+			*migrate(&[0], &options).unwrap_err().kind(),
+			ErrorKind::CorruptedMetadata("Expected at least 2 bytes, got 1".into())
+		);
+	}
+
+	#[test]
+	fn test_migrate_current_version() {
+		let options = InternalOptions::from_external(Default::default()).unwrap();
+		let metadata = Metadata::new(&options.external, options.external.key_index_bits);
+		let mut data = vec![0u8; metadata.as_bytes().len()];
+		metadata.as_bytes().copy_to_slice(&mut data);
+
+		assert!(migrate(&data, &options).unwrap().is_none());
+
+		data[0] = 7;
+		assert_eq!(*migrate(&data, &options).unwrap_err().kind(), ErrorKind::UnsupportedVersion(7));
+	}
+}
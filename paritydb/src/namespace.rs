@@ -0,0 +1,565 @@
+//! Database namespaces
+//!
+//! Each namespace has its own data and metadata files. All namespaces of
+//! the database share a single journal, so one transaction may modify many
+//! of them atomically. If the database has any named namespaces, journal keys
+//! are prefixed with the index of their namespace.
+//!
+//! ```text
+//!  namespace index   key
+//!   /                 /
+//! |.|..................|
+//! ```
+
+use std::borrow::Cow;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::{cmp, fs, vec};
+
+use memmap::{Mmap, Protection};
+
+use database;
+use error::{ErrorKind, Result};
+use flush::{Flush, FlushV0};
+use key::Key;
+use metadata::{self, Metadata};
+use migration;
+use options::{Durability, InternalOptions, Options};
+use record::append_record;
+use transaction::Operation;
+use verify::{self, Inconsistency};
+
+/// Returns the key of the journal operation.
+pub(crate) fn journal_key(prefix: Option<u8>, key: &[u8]) -> Cow<[u8]> {
+	match prefix {
+		Some(prefix) => {
+			let mut prefixed = Vec::with_capacity(key.len() + 1);
+			prefixed.push(prefix);
+			prefixed.extend_from_slice(key);
+			Cow::Owned(prefixed)
+		},
+		None => Cow::Borrowed(key),
+	}
+}
+
+/// Returns the index of the namespace and the key of the journal operation.
+pub(crate) fn split_journal_key(namespaced: bool, key: &[u8]) -> (usize, &[u8]) {
+	if namespaced {
+		(key[0] as usize, &key[1..])
+	} else {
+		(0, key)
+	}
+}
+
+/// Returns the journal operations of the namespace with stripped prefixes.
+pub(crate) fn journal_operations<'a>(
+	operations: vec::IntoIter<Operation<'a>>,
+	prefix: Option<u8>,
+) -> vec::IntoIter<Operation<'a>> {
+	let prefix = match prefix {
+		Some(prefix) => prefix,
+		None => return operations,
+	};
+
+	operations
+		.filter(|o| o.key()[0] == prefix)
+		.map(|o| match o {
+			Operation::Insert(key, value) => Operation::Insert(&key[1..], value),
+			Operation::Delete(key) => Operation::Delete(&key[1..]),
+			Operation::Merge(key, operand) => Operation::Merge(&key[1..], operand),
+		})
+		.collect::<Vec<_>>()
+		.into_iter()
+}
+
+/// Data and metadata files of a single namespace.
+#[derive(Debug)]
+pub(crate) struct Namespace {
+	pub path: PathBuf,
+	pub options: InternalOptions,
+	pub metadata: Arc<Metadata>,
+	metadata_mmap: Mmap,
+	/// Shared with snapshots. It's replaced by a map of a copy of the file before it's modified
+	/// while any snapshot exists, so that snapshots keep reading the old data.
+	pub mmap: Arc<Mmap>,
+}
+
+impl Namespace {
+	const DB_FILE: &'static str = "data.db";
+	const DB_COPY_FILE: &'static str = "data.db.copy";
+	const META_FILE: &'static str = "meta.db";
+
+	/// Creates data and metadata files of new namespace in given directory.
+	pub fn create<P: AsRef<Path>>(path: P, options: &InternalOptions) -> Result<()> {
+		// Create DB file.
+		{
+			let db_file_path = path.as_ref().join(Self::DB_FILE);
+			let mut file = fs::OpenOptions::new()
+				.write(true)
+				.create_new(true)
+				.open(&db_file_path)?;
+			file.set_len(options.initial_db_size)?;
+			file.flush()?;
+			options.external.durability.sync_file(&file)?;
+		}
+
+		// Create Metadata file.
+		{
+			let meta_file_path = path.as_ref().join(Self::META_FILE);
+			let mut file = fs::OpenOptions::new()
+				.write(true)
+				.create_new(true)
+				.open(&meta_file_path)?;
+			let metadata = Metadata::new(&options.external, options.external.key_index_bits);
+			let meta = metadata.as_bytes();
+			let mut data = vec![0u8; meta.len()];
+			meta.copy_to_slice(&mut data);
+			file.write_all(&data)?;
+			file.flush()?;
+			options.external.durability.sync_file(&file)?;
+		}
+
+		options.external.durability.sync_dir(path)?;
+		Ok(())
+	}
+
+	/// Opens the namespace and recovers its pending flush, unless it's opened read-only.
+	///
+	/// Namespace of an older version is migrated, unless it's opened read-only.
+	/// Options are checked against the stored ones before any file is modified.
+	pub fn open<P: AsRef<Path>>(path: P, options: InternalOptions, read_only: bool) -> Result<Self> {
+		let db_file_path = path.as_ref().join(Self::DB_FILE);
+		let meta_file_path = path.as_ref().join(Self::META_FILE);
+		let protection = if read_only { Protection::Read } else { Protection::ReadWrite };
+
+		let flush = if read_only {
+			None
+		} else {
+			Self::recover_v0(path.as_ref(), &options)?;
+			// copy of the data file left by a crash before it replaced the file
+			match fs::remove_file(path.as_ref().join(Self::DB_COPY_FILE)) {
+				Err(ref err) if err.kind() == io::ErrorKind::NotFound => {},
+				result => result?,
+			}
+			match Flush::open(path.as_ref(), options.external.durability)? {
+				Some(flush) => {
+					// metadata of the pending flush is newer than the one in the file
+					options.external.check_stored(&flush.metadata().options)?;
+					Some(flush)
+				},
+				// older versions don't store options
+				None => Self::migrate(path.as_ref(), &options)?,
+			}
+		};
+
+		if let Some(flush) = flush {
+			let prefix_bits = flush.prefix_bits();
+			let db_len = cmp::max(options.db_size_for_prefix_bits(prefix_bits), flush.db_len());
+			let mut mmap = Self::map_file(&db_file_path, db_len, options.external.durability)?;
+			let mut metadata_mmap = Self::map_file(&meta_file_path, metadata::bytes::len(prefix_bits) as u64, options.external.durability)?;
+			flush.flush(unsafe { mmap.as_mut_slice() }, unsafe { metadata_mmap.as_mut_slice() });
+			mmap.flush()?;
+			metadata_mmap.flush()?;
+			flush.delete()?;
+		}
+
+		let mmap = Arc::new(Mmap::open_path(db_file_path, protection)?);
+		let metadata_mmap = Mmap::open_path(meta_file_path, protection)?;
+		let metadata = Arc::new(metadata::bytes::read(unsafe { metadata_mmap.as_slice() })?);
+		options.external.check_stored(&metadata.options)?;
+
+		Ok(Namespace {
+			path: path.as_ref().to_owned(),
+			options,
+			metadata,
+			metadata_mmap,
+			mmap,
+		})
+	}
+
+	/// Returns options the namespace in given directory was created with.
+	///
+	/// Fails with `OptionsNotStored` if the namespace has an older version,
+	/// unless its migration was interrupted.
+	pub fn stored_options<P: AsRef<Path>>(path: P) -> Result<Options> {
+		let data = fs::read(path.as_ref().join(Self::META_FILE))?;
+		let version = metadata::bytes::read_version(&data)?;
+		if version >= Metadata::DB_VERSION {
+			return Ok(metadata::bytes::read(&data)?.options);
+		}
+
+		// the flush is only read, so it's never deleted
+		match Self::migration_flush(path.as_ref()) {
+			Some(flush) => Ok(flush.metadata().options.clone()),
+			None => bail!(ErrorKind::OptionsNotStored(version)),
+		}
+	}
+
+	/// Recovers a flush left by version 0, so that the namespace can be migrated.
+	///
+	/// Version 0 stored metadata after operations of the flush.
+	fn recover_v0(path: &Path, options: &InternalOptions) -> Result<()> {
+		let meta_file_path = path.join(Self::META_FILE);
+		if metadata::bytes::read_version(&fs::read(&meta_file_path)?)? != 0 {
+			return Ok(());
+		}
+
+		if Self::migration_flush(path).is_some() {
+			return Ok(());
+		}
+
+		let durability = options.external.durability;
+		let flush = match FlushV0::open(path, migration::meta_len_v0(options), durability)? {
+			Some(flush) => flush,
+			None => return Ok(()),
+		};
+
+		let mut mmap = Self::map_file(&path.join(Self::DB_FILE), 0, durability)?;
+		let mut metadata_mmap = Self::map_file(&meta_file_path, flush.meta_len() as u64, durability)?;
+		flush.flush(unsafe { mmap.as_mut_slice() }, &mut unsafe { metadata_mmap.as_mut_slice() }[..flush.meta_len()]);
+		mmap.flush()?;
+		metadata_mmap.flush()?;
+		flush.delete()
+	}
+
+	/// Returns the pending flush written by interrupted migration, if there is one.
+	///
+	/// Flush of the current version, which only replaces metadata, is not mistaken
+	/// for a flush of version 0, which stored its metadata after operations.
+	fn migration_flush(path: &Path) -> Option<Flush> {
+		match Flush::open(path, Durability::None) {
+			Ok(Some(flush)) if flush.is_metadata_only() => Some(flush),
+			_ => None,
+		}
+	}
+
+	/// Returns a flush which upgrades metadata of the namespace, if it has an older version.
+	fn migrate(path: &Path, options: &InternalOptions) -> Result<Option<Flush>> {
+		let data = fs::read(path.join(Self::META_FILE))?;
+		match migration::migrate(&data, options)? {
+			Some(metadata) => Ok(Some(Flush::with_metadata(path, metadata, options.external.durability)?)),
+			None => Ok(None),
+		}
+	}
+
+	/// Rebuilds the metadata file of the namespace from its data file.
+	///
+	/// Pending flush is applied to the data file first. New metadata is written
+	/// using a flush, so it's recovered when the namespace is opened after a crash.
+	/// Journal eras starting with `next_era` are considered not flushed,
+	/// unless the pending flush says otherwise.
+	///
+	/// Number of prefix bits is determined by the size of the data file. Fails without
+	/// modifying the metadata file if any record is stored before the offset of its prefix.
+	pub fn rebuild_metadata<P: AsRef<Path>>(path: P, options: &InternalOptions, next_era: u64) -> Result<()> {
+		let db_file_path = path.as_ref().join(Self::DB_FILE);
+		let meta_file_path = path.as_ref().join(Self::META_FILE);
+
+		let (prefix_bits, next_era) = match Flush::open(path.as_ref(), options.external.durability)? {
+			Some(flush) => {
+				let prefix_bits = flush.prefix_bits();
+				let db_len = cmp::max(options.db_size_for_prefix_bits(prefix_bits), flush.db_len());
+				let mut mmap = Self::map_file(&db_file_path, db_len, options.external.durability)?;
+				// metadata of the flush is replaced with the rebuilt one
+				let mut metadata = vec![0u8; metadata::bytes::len(prefix_bits)];
+				flush.flush(unsafe { mmap.as_mut_slice() }, &mut metadata);
+				mmap.flush()?;
+				let next_era = flush.metadata().next_era;
+				flush.delete()?;
+				(prefix_bits, next_era)
+			},
+			None => (Self::prefix_bits_for_db_len(options, fs::metadata(&db_file_path)?.len()), next_era),
+		};
+
+		let mut mmap = Self::map_file(&db_file_path, 0, options.external.durability)?;
+		let flush = Flush::rebuild_metadata(path.as_ref(), options, unsafe { mmap.as_slice() }, prefix_bits, next_era)?;
+		let report = verify::verify(unsafe { mmap.as_slice() }, flush.metadata(), options);
+		let misplaced = report.inconsistencies.iter().filter_map(|inconsistency| match *inconsistency {
+			Inconsistency::MisplacedRecord { offset, min_offset } => Some((offset, min_offset)),
+			_ => None,
+		}).next();
+		if let Some((offset, min_offset)) = misplaced {
+			flush.delete()?;
+			bail!(ErrorKind::MisplacedRecord(offset, min_offset));
+		}
+
+		// the metadata file is resized only once the flush exists, so that it's never left truncated
+		let meta_len = metadata::bytes::len(prefix_bits);
+		let file = fs::OpenOptions::new()
+			.write(true)
+			.create(true)
+			.truncate(false)
+			.open(&meta_file_path)?;
+		file.set_len(meta_len as u64)?;
+		options.external.durability.sync_file(&file)?;
+		options.external.durability.sync_dir(path.as_ref())?;
+		let mut metadata_mmap = Self::map_file(&meta_file_path, meta_len as u64, options.external.durability)?;
+		flush.flush(unsafe { mmap.as_mut_slice() }, unsafe { metadata_mmap.as_mut_slice() });
+		metadata_mmap.flush()?;
+		flush.delete()
+	}
+
+	/// Returns the greatest number of prefix bits a data file of given `len` may have.
+	///
+	/// The size of the file is doubled when prefix bits are increased.
+	/// Records in the overflow region extend it by far less than that.
+	fn prefix_bits_for_db_len(options: &InternalOptions, len: u64) -> u8 {
+		let mut prefix_bits = options.external.key_index_bits;
+		while prefix_bits < options.max_prefix_bits() && options.db_size_for_prefix_bits(prefix_bits + 1) <= len {
+			prefix_bits += 1;
+		}
+		prefix_bits
+	}
+
+	/// Extends the file to given `len` if it's shorter and maps it to memory.
+	///
+	/// The new length is synced according to `durability`.
+	fn map_file(path: &Path, len: u64, durability: Durability) -> Result<Mmap> {
+		let file = fs::OpenOptions::new()
+			.read(true)
+			.write(true)
+			.open(path)?;
+		if file.metadata()?.len() < len {
+			file.set_len(len)?;
+			durability.sync_file(&file)?;
+		}
+		Ok(Mmap::open(&file, Protection::ReadWrite)?)
+	}
+
+	/// Returns flushed data of the namespace.
+	pub fn data(&self) -> &[u8] {
+		unsafe { self.mmap.as_slice() }
+	}
+
+	/// Returns true if flushed data is pinned by a snapshot.
+	pub fn is_shared(&self) -> bool {
+		Arc::strong_count(&self.mmap) > 1
+	}
+
+	/// Replaces the data file pinned by snapshots with its copy and maps the copy to memory,
+	/// so that the data can be modified while snapshots read the old file.
+	///
+	/// The copy replaces the file only once it's complete, so a crash leaves one of them intact.
+	fn unshare(&mut self) -> Result<()> {
+		if !self.is_shared() {
+			return Ok(());
+		}
+
+		let db_file_path = self.path.join(Self::DB_FILE);
+		let copy_path = self.path.join(Self::DB_COPY_FILE);
+		fs::copy(&db_file_path, &copy_path)?;
+		{
+			let file = fs::OpenOptions::new().write(true).open(&copy_path)?;
+			self.options.external.durability.sync_file(&file)?;
+		}
+		fs::rename(&copy_path, &db_file_path)?;
+		self.options.external.durability.sync_dir(&self.path)?;
+		self.mmap = Arc::new(Mmap::open_path(db_file_path, Protection::ReadWrite)?);
+		Ok(())
+	}
+
+	/// Applies the flush to the namespace files and deletes it.
+	/// Namespace files are extended if the flush increases number of prefix bits
+	/// or writes records to the overflow region past the end of the database.
+	///
+	/// Data file pinned by snapshots is copied first.
+	pub fn apply_flush(&mut self, flush: Flush) -> Result<()> {
+		self.unshare()?;
+		let prefix_bits = flush.prefix_bits();
+		let db_len = cmp::max(self.options.db_size_for_prefix_bits(prefix_bits), flush.db_len());
+		if db_len > self.mmap.len() as u64 {
+			self.mmap = Arc::new(Self::map_file(&self.path.join(Self::DB_FILE), db_len, self.options.external.durability)?);
+		}
+
+		let meta_len = metadata::bytes::len(prefix_bits);
+		if meta_len > self.metadata_mmap.len() {
+			self.metadata_mmap = Self::map_file(&self.path.join(Self::META_FILE), meta_len as u64, self.options.external.durability)?;
+		}
+
+		{
+			let mmap = Arc::get_mut(&mut self.mmap).expect("data is unshared before the flush is applied; qed");
+			flush.flush(unsafe { mmap.as_mut_slice() }, unsafe { self.metadata_mmap.as_mut_slice() });
+			mmap.flush()?;
+		}
+		self.metadata_mmap.flush()?;
+		self.metadata = Arc::new(flush.metadata().clone());
+		flush.delete()
+	}
+
+	/// Writes sorted records directly to the empty namespace in a single pass.
+	/// Metadata is written by a flush afterwards.
+	///
+	/// If it fails, the written records are cleared.
+	pub fn bulk_load<I, K, V>(&mut self, records: I) -> Result<()>
+		where I: IntoIterator<Item = (K, V)>, K: AsRef<[u8]>, V: AsRef<[u8]> {
+
+		if self.metadata.occupied_bytes != 0 {
+			bail!(ErrorKind::NotEmpty);
+		}
+
+		// records left by a bulk load interrupted by a crash are not in the metadata
+		if self.data().iter().any(|&byte| byte != 0) {
+			bail!(ErrorKind::NotEmpty);
+		}
+
+		self.unshare()?;
+
+		let mut metadata = (*self.metadata).clone();
+		// end of the last written record
+		let mut end = 0;
+		let result = self.write_sorted(records, &mut metadata, &mut end);
+		if result.is_err() {
+			let mmap = Arc::get_mut(&mut self.mmap).expect("data is unshared before records are loaded; qed");
+			for byte in &mut unsafe { mmap.as_mut_slice() }[..end] {
+				*byte = 0;
+			}
+			mmap.flush()?;
+			end = 0;
+		}
+
+		// overflow region is extended in big steps while loading, so that it's not remapped for every record
+		let db_len = cmp::max(self.options.db_size_for_prefix_bits(metadata.prefixes.prefix_bits()) as usize, end);
+		if db_len < self.mmap.len() {
+			let db_file_path = self.path.join(Self::DB_FILE);
+			let file = fs::OpenOptions::new().write(true).open(&db_file_path)?;
+			file.set_len(db_len as u64)?;
+			self.options.external.durability.sync_file(&file)?;
+			self.mmap = Arc::new(Self::map_file(&db_file_path, db_len as u64, self.options.external.durability)?);
+		}
+		result?;
+
+		let flush = Flush::with_metadata(&self.path, metadata, self.options.external.durability)?;
+		self.apply_flush(flush)
+	}
+
+	/// Writes records at the offsets determined by their keys or directly after the previous record.
+	fn write_sorted<I, K, V>(&mut self, records: I, metadata: &mut Metadata, end: &mut usize) -> Result<()>
+		where I: IntoIterator<Item = (K, V)>, K: AsRef<[u8]>, V: AsRef<[u8]> {
+
+		let prefix_bits = metadata.prefixes.prefix_bits();
+		let field_body_size = self.options.field_body_size;
+		let mut buffer = Vec::new();
+		let mut last_key = Vec::new();
+
+		for (index, (key, value)) in records.into_iter().enumerate() {
+			let (key, value) = (key.as_ref(), value.as_ref());
+			database::validate_key_len(&self.options, key)?;
+			if index > 0 && key <= &last_key[..] {
+				bail!(ErrorKind::UnsortedKey(key.to_vec()));
+			}
+
+			buffer.clear();
+			append_record(
+				&mut buffer,
+				key,
+				value,
+				field_body_size,
+				self.options.key_size.is_const(),
+				self.options.external.value_len.is_const(),
+				self.options.external.record_checksum,
+			);
+
+			let prefixed_key = Key::new(key, prefix_bits);
+			let offset = cmp::max(*end, prefixed_key.offset(field_body_size));
+			if offset + buffer.len() > self.mmap.len() {
+				// records past the end of the database are written to the overflow region
+				let len = cmp::max(offset + buffer.len(), 2 * self.mmap.len());
+				self.mmap = Arc::new(Self::map_file(&self.path.join(Self::DB_FILE), len as u64, self.options.external.durability)?);
+			}
+
+			let mmap = Arc::get_mut(&mut self.mmap).expect("data is unshared before records are loaded; qed");
+			let data = unsafe { mmap.as_mut_slice() };
+			data[offset..offset + buffer.len()].copy_from_slice(&buffer);
+			metadata.insert_record(prefixed_key.prefix, buffer.len());
+			*end = offset + buffer.len();
+
+			last_key.clear();
+			last_key.extend_from_slice(key);
+		}
+
+		let mmap = Arc::get_mut(&mut self.mmap).expect("data is unshared before records are loaded; qed");
+		mmap.flush()?;
+		Ok(())
+	}
+
+	/// Returns true if records occupy more than `extend_threshold_percent` of the namespace.
+	pub fn should_grow(&self) -> bool {
+		let threshold = self.options.external.extend_threshold_percent as u64;
+		self.metadata.occupied_bytes * 100 > threshold * self.mmap.len() as u64
+	}
+
+	/// Writes the flush moving all records as close to the offsets determined by their keys as possible.
+	pub fn prepare_compact(&self) -> Result<Flush> {
+		let prefix_bits = self.metadata.prefixes.prefix_bits();
+		Flush::relayout(
+			&self.path,
+			&self.options,
+			self.data(),
+			&self.metadata,
+			prefix_bits,
+		)
+	}
+
+	/// Re-allocates the namespace to twice as big size and moves all records
+	/// to the locations determined by increased number of prefix bits.
+	///
+	/// Does nothing if the number of prefix bits can't be increased anymore.
+	pub fn grow(&mut self) -> Result<()> {
+		match self.prepare_grow()? {
+			Some(flush) => self.apply_flush(flush),
+			None => Ok(()),
+		}
+	}
+
+	/// Writes the flush doubling the number of prefixes, unless it's already the maximum.
+	pub fn prepare_grow(&self) -> Result<Option<Flush>> {
+		let prefix_bits = self.metadata.prefixes.prefix_bits();
+		if prefix_bits >= self.options.max_prefix_bits() {
+			return Ok(None);
+		}
+
+		let flush = Flush::relayout(
+			&self.path,
+			&self.options,
+			self.data(),
+			&self.metadata,
+			prefix_bits + 1,
+		)?;
+		Ok(Some(flush))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use transaction::Operation;
+	use super::{journal_key, journal_operations, split_journal_key};
+
+	#[test]
+	fn test_journal_key() {
+		assert_eq!(&*journal_key(None, b"abc"), b"abc");
+		assert_eq!(&*journal_key(Some(2), b"abc"), b"\x02abc");
+		assert_eq!(split_journal_key(false, b"\x02abc"), (0, &b"\x02abc"[..]));
+		assert_eq!(split_journal_key(true, b"\x02abc"), (2, &b"abc"[..]));
+	}
+
+	#[test]
+	fn test_journal_operations() {
+		let operations = vec![
+			Operation::Insert(b"\x00abc", b"001"),
+			Operation::Delete(b"\x01abc"),
+			Operation::Insert(b"\x01def", b"002"),
+			Operation::Merge(b"\x01def", b"003"),
+		];
+
+		let all: Vec<_> = journal_operations(operations.clone().into_iter(), None).collect();
+		assert_eq!(all, operations);
+
+		let second: Vec<_> = journal_operations(operations.into_iter(), Some(1)).collect();
+		assert_eq!(second, vec![
+			Operation::Delete(b"abc"),
+			Operation::Insert(b"def", b"002"),
+			Operation::Merge(b"def", b"003"),
+		]);
+	}
+}
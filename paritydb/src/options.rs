@@ -1,9 +1,13 @@
+use std::{cmp, fmt, fs, io};
+use std::path::Path;
+
 use error::{ErrorKind, Result};
 use field;
+use merge::SharedMergeOperator;
 use record;
 
 /// A length of values stored in the DB.
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub enum ValuesLen {
 	/// Values have constant length.
 	Constant(usize),
@@ -40,8 +44,52 @@ impl ValuesLen {
 	}
 }
 
+/// Determines when written files are synced to the disk.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum Durability {
+	/// Files are never synced. Committed eras may be lost if the system crashes,
+	/// but not if only the process does. It's the default.
+	#[default]
+	None,
+	/// Data of written files is synced, but their directory entries are not.
+	/// A file created just before the system crashes may be missing.
+	Data,
+	/// Data of written files and entries of directories
+	/// they're created in or deleted from are synced.
+	DataAndDirectory,
+}
+
+impl Durability {
+	/// Syncs data written to the `file`.
+	pub(crate) fn sync_file(&self, file: &fs::File) -> io::Result<()> {
+		match *self {
+			Durability::None => Ok(()),
+			Durability::Data | Durability::DataAndDirectory => file.sync_data(),
+		}
+	}
+
+	/// Syncs entries of files created in or deleted from the `dir`.
+	pub(crate) fn sync_dir<P: AsRef<Path>>(&self, dir: P) -> io::Result<()> {
+		match *self {
+			Durability::None | Durability::Data => Ok(()),
+			Durability::DataAndDirectory => sync_dir(dir.as_ref()),
+		}
+	}
+}
+
+#[cfg(unix)]
+fn sync_dir(dir: &Path) -> io::Result<()> {
+	fs::File::open(dir)?.sync_all()
+}
+
+/// Directories can't be opened as files on other platforms.
+#[cfg(not(unix))]
+fn sync_dir(_dir: &Path) -> io::Result<()> {
+	Ok(())
+}
+
 /// Database options.
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub struct Options {
 	/// Number of eras to keep in the journal.
 	pub journal_eras: usize,
@@ -49,11 +97,27 @@ pub struct Options {
 	/// than `extend_threshold_percent` occupied entries.
 	pub extend_threshold_percent: u8,
 	/// Number of bits from the key used to create search index.
+	/// It's only used when the database is created. Later it's increased
+	/// each time the database is re-allocated.
 	pub key_index_bits: u8,
 	/// Key length in bytes.
 	pub key_len: usize,
+	/// If true, keys may be of any length up to `key_len`.
+	/// Each record stores the length of its key then.
+	pub variable_key_len: bool,
 	/// Value length in bytes.
 	pub value_len: ValuesLen,
+	/// If true, each record is stored with a checksum of its key and value,
+	/// which is validated every time the record is read.
+	pub record_checksum: bool,
+	/// Combines merge operands with values. It's required to merge values.
+	///
+	/// It's not stored in the database, so it needs to be set each time it's opened.
+	pub merge_operator: Option<SharedMergeOperator>,
+	/// Determines when journal eras and flushes are synced to the disk.
+	///
+	/// It's not stored in the database, so it may be different each time it's opened.
+	pub durability: Durability,
 }
 
 impl Default for Options {
@@ -63,14 +127,128 @@ impl Default for Options {
 			extend_threshold_percent: 80,
 			key_index_bits: 8,
 			key_len: 32,
+			variable_key_len: false,
 			value_len: ValuesLen::Constant(64),
+			record_checksum: false,
+			merge_operator: None,
+			durability: Durability::default(),
+		}
+	}
+}
+
+impl Options {
+	/// Adds a named namespace stored besides the default one, which has these options.
+	pub fn namespace<S: Into<String>>(self, name: S, options: Options) -> DatabaseOptions {
+		DatabaseOptions::from(self).namespace(name, options)
+	}
+
+	/// Fails if options which determine the layout of the database
+	/// differ from the `stored` ones it was created with.
+	pub(crate) fn check_stored(&self, stored: &Options) -> Result<()> {
+		fn conflict<T: fmt::Debug>(field: &'static str, given: T, stored: T) -> Result<()> {
+			bail!(ErrorKind::InvalidOptions(
+				field,
+				format!("{:?} differs from {:?} the database was created with.", given, stored)
+			))
+		}
+
+		if self.key_index_bits != stored.key_index_bits {
+			return conflict("key_index_bits", self.key_index_bits, stored.key_index_bits);
+		}
+		if self.key_len != stored.key_len {
+			return conflict("key_len", self.key_len, stored.key_len);
+		}
+		if self.variable_key_len != stored.variable_key_len {
+			return conflict("variable_key_len", self.variable_key_len, stored.variable_key_len);
+		}
+		if self.value_len != stored.value_len {
+			return conflict("value_len", &self.value_len, &stored.value_len);
+		}
+		if self.record_checksum != stored.record_checksum {
+			return conflict("record_checksum", self.record_checksum, stored.record_checksum);
+		}
+
+		Ok(())
+	}
+}
+
+/// Options of the default namespace and named namespaces of the database.
+///
+/// It's created from the options of the default namespace by `Options::namespace`
+/// or by converting them if there are no named namespaces.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct DatabaseOptions {
+	pub(crate) options: Options,
+	pub(crate) namespaces: Vec<(String, Options)>,
+}
+
+impl From<Options> for DatabaseOptions {
+	fn from(options: Options) -> Self {
+		DatabaseOptions {
+			options,
+			namespaces: Vec::new(),
 		}
 	}
 }
 
-#[derive(Debug, PartialEq)]
+impl DatabaseOptions {
+	/// Adds a named namespace stored besides the default one, with its own options.
+	/// Names may contain only ASCII letters, digits, `-` and `_`.
+	///
+	/// Namespaces share the journal of the database, so `journal_eras` of their options is not used.
+	/// They can't be changed after the database is created.
+	pub fn namespace<S: Into<String>>(mut self, name: S, options: Options) -> Self {
+		self.namespaces.push((name.into(), options));
+		self
+	}
+
+	/// Returns options of the default namespace.
+	pub fn options(&self) -> &Options {
+		&self.options
+	}
+
+	/// Returns named namespaces and their options in the order they were added.
+	pub fn namespaces(&self) -> &[(String, Options)] {
+		&self.namespaces
+	}
+
+	/// Returns names of named namespaces.
+	pub(crate) fn names(&self) -> Vec<String> {
+		self.namespaces.iter().map(|(name, _)| name.clone()).collect()
+	}
+
+	/// Returns internal options of the default namespace followed by named namespaces.
+	pub(crate) fn to_internal(&self) -> Result<Vec<InternalOptions>> {
+		// journal keys are prefixed with a single byte index of the namespace
+		if self.namespaces.len() > u8::MAX as usize {
+			bail!(ErrorKind::InvalidOptions(
+				"namespaces",
+				format!("{} is too many. Only up to {} namespaces are supported.", self.namespaces.len(), u8::MAX)
+			));
+		}
+
+		let mut internal = vec![InternalOptions::from_external(self.options.clone())?];
+		for (i, (name, options)) in self.namespaces.iter().enumerate() {
+			let valid_name = !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+			if !valid_name {
+				bail!(ErrorKind::InvalidOptions("namespaces", format!("{:?} is not a valid name.", name)));
+			}
+
+			if self.namespaces[..i].iter().any(|(other, _)| other == name) {
+				bail!(ErrorKind::InvalidOptions("namespaces", format!("{:?} is used more than once.", name)));
+			}
+
+			internal.push(InternalOptions::from_external(options.clone())?);
+		}
+
+		Ok(internal)
+	}
+}
+
+#[derive(Debug, PartialEq, Clone)]
 pub struct InternalOptions {
 	pub external: Options,
+	pub key_size: record::KeySize,
 	pub value_size: record::ValueSize,
 	pub field_body_size: usize,
 	pub initial_db_size: u64,
@@ -92,6 +270,13 @@ impl InternalOptions {
 			));
 		}
 
+		if external.variable_key_len && external.key_len > u8::MAX as usize {
+			bail!(ErrorKind::InvalidOptions(
+				"key_len",
+				format!("{} is too large. Variable length keys can have up to {} bytes.", external.key_len, u8::MAX)
+			));
+		}
+
 		if external.key_index_bits == 0 {
 			bail!(ErrorKind::InvalidOptions(
 				"key_index_bits",
@@ -106,29 +291,69 @@ impl InternalOptions {
 			));
 		}
 
+		let (key_size, key_header_size) = if external.variable_key_len {
+			(record::KeySize::Variable, record::KEY_HEADER_SIZE)
+		} else {
+			(record::KeySize::Constant(external.key_len), 0)
+		};
 		let value_size = external.value_len.to_value_size();
-		let field_body_size = external.key_len + external.value_len.size();
+		let checksum_size = if external.record_checksum { record::CHECKSUM_SIZE } else { 0 };
+		let field_body_size = key_header_size + external.key_len + checksum_size + external.value_len.size();
 		let record_offset = field::field_size(field_body_size as usize);
-		// +1 for last record with prefix 0xffff....
-		let initial_db_size = (2u64 << external.key_index_bits + 1) * record_offset as u64;
+		let initial_db_size = Self::db_size(external.key_index_bits, record_offset);
 
 		Ok(InternalOptions {
 			external,
+			key_size,
 			value_size,
 			field_body_size,
 			initial_db_size,
 			record_offset,
 		})
 	}
+
+	fn db_size(prefix_bits: u8, record_offset: usize) -> u64 {
+		// +1 for last record with prefix 0xffff....
+		(2u64 << (prefix_bits + 1)) * record_offset as u64
+	}
+
+	/// Returns size of the database file for given prefix bits.
+	pub fn db_size_for_prefix_bits(&self, prefix_bits: u8) -> u64 {
+		Self::db_size(prefix_bits, self.record_offset)
+	}
+
+	/// Returns the maximum number of prefix bits the database can grow to.
+	pub fn max_prefix_bits(&self) -> u8 {
+		cmp::min(32, self.external.key_len * 8) as u8
+	}
 }
 
 #[cfg(test)]
 mod tests {
-	use super::ValuesLen;
+	use error::ErrorKind;
+	use super::{DatabaseOptions, Options, ValuesLen};
 
 	#[test]
 	fn test_values_len_const() {
 		assert_eq!(true, ValuesLen::Constant(1).is_const());
 		assert_eq!(false, ValuesLen::Variable { expected: 5 }.is_const());
 	}
+
+	#[test]
+	fn test_invalid_namespaces() {
+		let options = |names: &[&str]| names.iter()
+			.fold(DatabaseOptions::default(), |options, name| options.namespace(*name, Options::default()));
+
+		assert_eq!(options(&["accounts", "code-1"]).to_internal().unwrap().len(), 3);
+		assert_eq!(
+			*options(&["accounts", "accounts"]).to_internal().unwrap_err().kind(),
+			ErrorKind::InvalidOptions("namespaces", "\"accounts\" is used more than once.".into())
+		);
+		assert_eq!(
+			*options(&["../accounts"]).to_internal().unwrap_err().kind(),
+			ErrorKind::InvalidOptions("namespaces", "\"../accounts\" is not a valid name.".into())
+		);
+		assert!(options(&[""]).to_internal().is_err());
+		assert_eq!(Options::default().namespace("code", Options::default()).namespaces().len(), 1);
+	}
 }
@@ -106,6 +106,60 @@ impl PrefixTree {
 		}
 	}
 
+	/// Returns an iterator of occupied prefixes starting at `prefix`.
+	pub fn prefixes_iter_from(&self, prefix: u32) -> OccupiedPrefixesIterator {
+		let first_leaf_idx = Self::leaf_index(0, self.prefix_bits);
+		// continue as if the previous leaf has just been returned
+		let idx = match prefix {
+			0 => 0,
+			prefix => Self::leaf_index(prefix, self.prefix_bits) - 1,
+		};
+
+		OccupiedPrefixesIterator {
+			tree: &self.tree,
+			idx,
+			first_leaf_idx,
+		}
+	}
+
+	/// Returns the greatest occupied prefix lower than `prefix`.
+	pub fn prev_occupied(&self, prefix: u32) -> Option<u32> {
+		let first_leaf_idx = Self::leaf_index(0, self.prefix_bits);
+
+		let mut idx = if prefix as usize >= first_leaf_idx {
+			// search the whole tree
+			1
+		} else {
+			let mut idx = Self::leaf_index(prefix, self.prefix_bits);
+			// go up until there is an occupied subtree on the left
+			loop {
+				if idx <= 1 {
+					return None;
+				}
+
+				if idx % 2 == 1 && self.tree.get(idx - 1).unwrap() {
+					break idx - 1;
+				}
+
+				idx >>= 1;
+			}
+		};
+
+		if !self.tree.get(idx).unwrap() {
+			return None;
+		}
+
+		// descent to the last occupied leaf of the subtree
+		while idx < first_leaf_idx {
+			idx <<= 1;
+			if self.tree.get(idx + 1).unwrap() {
+				idx += 1;
+			}
+		}
+
+		Some((idx - first_leaf_idx) as u32)
+	}
+
 	/// Returns current prefix bits.
 	pub fn prefix_bits(&self) -> u8 {
 		self.prefix_bits
@@ -280,6 +334,59 @@ mod tests {
 		assert_eq!(it.next(), None);
 	}
 
+	#[test]
+	fn test_prefixes_iterator_from() {
+		let prefix_bits = 5;
+		let mut tree = PrefixTree::new(prefix_bits);
+		tree.insert(0);
+		tree.insert(6);
+		tree.insert(7);
+		tree.insert(8);
+		tree.insert(19);
+		tree.insert(24);
+		tree.insert(31);
+
+		assert_eq!(tree.prefixes_iter_from(0).collect::<Vec<_>>(), vec![0, 6, 7, 8, 19, 24, 31]);
+		assert_eq!(tree.prefixes_iter_from(1).collect::<Vec<_>>(), vec![6, 7, 8, 19, 24, 31]);
+		assert_eq!(tree.prefixes_iter_from(7).collect::<Vec<_>>(), vec![7, 8, 19, 24, 31]);
+		assert_eq!(tree.prefixes_iter_from(8).collect::<Vec<_>>(), vec![8, 19, 24, 31]);
+		assert_eq!(tree.prefixes_iter_from(9).collect::<Vec<_>>(), vec![19, 24, 31]);
+		assert_eq!(tree.prefixes_iter_from(20).collect::<Vec<_>>(), vec![24, 31]);
+		assert_eq!(tree.prefixes_iter_from(31).collect::<Vec<_>>(), vec![31]);
+
+		let tree = PrefixTree::new(prefix_bits);
+		assert_eq!(tree.prefixes_iter_from(16).next(), None);
+	}
+
+	#[test]
+	fn test_prev_occupied() {
+		let prefix_bits = 5;
+		let mut tree = PrefixTree::new(prefix_bits);
+		tree.insert(0);
+		tree.insert(6);
+		tree.insert(7);
+		tree.insert(8);
+		tree.insert(19);
+		tree.insert(24);
+		tree.insert(31);
+
+		assert_eq!(tree.prev_occupied(100), Some(31));
+		assert_eq!(tree.prev_occupied(32), Some(31));
+		assert_eq!(tree.prev_occupied(31), Some(24));
+		assert_eq!(tree.prev_occupied(24), Some(19));
+		assert_eq!(tree.prev_occupied(20), Some(19));
+		assert_eq!(tree.prev_occupied(9), Some(8));
+		assert_eq!(tree.prev_occupied(8), Some(7));
+		assert_eq!(tree.prev_occupied(7), Some(6));
+		assert_eq!(tree.prev_occupied(6), Some(0));
+		assert_eq!(tree.prev_occupied(1), Some(0));
+		assert_eq!(tree.prev_occupied(0), None);
+
+		let tree = PrefixTree::new(prefix_bits);
+		assert_eq!(tree.prev_occupied(32), None);
+		assert_eq!(tree.prev_occupied(16), None);
+	}
+
 	#[test]
 	fn test_removing() {
 		let prefix_bits = 4;
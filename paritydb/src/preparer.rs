@@ -0,0 +1,99 @@
+//! Shared transaction preparer
+
+use error::Result;
+use journal::{JournalPreparer, PreparedTransaction};
+use merge::{self, SharedMergeOperator};
+use namespace;
+use transaction::{Operation, Transaction};
+
+/// A cloneable handle which prepares transactions from many threads
+/// while the database is used by the `Database`.
+///
+/// Prepared transactions are committed with `Database::apply`.
+#[derive(Debug, Clone)]
+pub struct Preparer {
+	journal: JournalPreparer,
+	/// Merge operators of the default namespace followed by named namespaces.
+	merge_operators: Vec<Option<SharedMergeOperator>>,
+}
+
+impl Preparer {
+	pub(crate) fn new(journal: JournalPreparer, merge_operators: Vec<Option<SharedMergeOperator>>) -> Self {
+		Preparer {
+			journal,
+			merge_operators,
+		}
+	}
+
+	/// Fails if the transaction merges values in a namespace without a merge operator.
+	pub(crate) fn check_merges(&self, tx: &Transaction) -> Result<()> {
+		let namespaced = self.merge_operators.len() > 1;
+		for operation in tx.operations() {
+			if let Operation::Merge(key, _) = operation {
+				let (namespace, _) = namespace::split_journal_key(namespaced, key);
+				merge::operator(&self.merge_operators[namespace])?;
+			}
+		}
+
+		Ok(())
+	}
+
+	/// Writes the transaction to the journal directory without committing it.
+	pub fn prepare(&self, tx: &Transaction) -> Result<PreparedTransaction> {
+		self.check_merges(tx)?;
+		self.journal.prepare(tx)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	extern crate tempdir;
+
+	use std::thread;
+
+	use {Database, ErrorKind, Options, ValuesLen};
+	use super::Preparer;
+
+	fn assert_send_sync<T: Send + Sync>() {}
+
+	#[test]
+	fn test_preparer_is_send_and_sync() {
+		assert_send_sync::<Preparer>();
+	}
+
+	#[test]
+	fn test_prepare_while_writing() {
+		let temp = tempdir::TempDir::new("test_prepare_while_writing").unwrap();
+		let options = || Options {
+			journal_eras: 0,
+			key_len: 3,
+			value_len: ValuesLen::Constant(3),
+			..Default::default()
+		};
+
+		let mut db = Database::create(temp.path(), options()).unwrap();
+		let handles: Vec<_> = (0..4u8).map(|i| {
+			let preparer = db.preparer().unwrap();
+			let mut tx = db.create_transaction();
+			tx.insert([b'k', i, 0], [i, i, i]).unwrap();
+			thread::spawn(move || preparer.prepare(&tx).unwrap())
+		}).collect();
+
+		let mut tx = db.create_transaction();
+		tx.insert("abc", "001").unwrap();
+		db.commit(&tx).unwrap();
+
+		for handle in handles {
+			db.apply(handle.join().unwrap()).unwrap();
+		}
+		assert_eq!(db.get([b'k', 3, 0]).unwrap().unwrap(), [3, 3, 3]);
+		db.flush_journal(None).unwrap();
+		assert_eq!(db.iter().unwrap().count(), 5);
+
+		// transactions are applied only to the database they were prepared for
+		let prepared = db.preparer().unwrap().prepare(&tx).unwrap();
+		drop(db);
+		let mut db = Database::open(temp.path(), options()).unwrap();
+		assert_eq!(*db.apply(prepared).unwrap_err().kind(), ErrorKind::ForeignTransaction);
+	}
+}
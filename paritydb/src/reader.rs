@@ -0,0 +1,270 @@
+//! Shared database reader
+
+use std::ops::Range;
+use std::sync::Arc;
+
+use parking_lot::{RwLock, RwLockWriteGuard};
+
+use database::DatabaseIterator;
+use error::{ErrorKind, Result};
+use snapshot::Snapshot;
+
+/// A cloneable handle which reads the database from many threads
+/// while it is written by the `Database`.
+///
+/// The `Database` publishes a new state after every commit, rollback and flush.
+/// Readers always see the most recently published state. Once the database is
+/// dropped, readers keep serving its last state.
+#[derive(Debug, Clone)]
+pub struct DatabaseReader {
+	/// Names of named namespaces in the order of their snapshots.
+	names: Arc<Vec<String>>,
+	/// Currently published snapshots of the default namespace followed by named namespaces.
+	/// It's `None` only while flushed data is being modified.
+	current: Arc<RwLock<Option<Arc<Vec<Snapshot>>>>>,
+}
+
+impl DatabaseReader {
+	/// Creates a reader of namespaces with given `names`. State must be published before it's used.
+	pub(crate) fn new(names: Vec<String>) -> Self {
+		DatabaseReader {
+			names: Arc::new(names),
+			current: Arc::new(RwLock::new(None)),
+		}
+	}
+
+	/// Replaces the state seen by the readers.
+	pub(crate) fn publish(&self, snapshots: Vec<Snapshot>) {
+		*self.current.write() = Some(Arc::new(snapshots));
+	}
+
+	/// Blocks readers and releases the published state,
+	/// so the flushed data can be modified in place.
+	///
+	/// New state must be published before the guard is released.
+	pub(crate) fn lock(&self) -> RwLockWriteGuard<Option<Arc<Vec<Snapshot>>>> {
+		let mut current = self.current.write();
+		*current = None;
+		current
+	}
+
+	/// Returns the index of the namespace with given `name`.
+	fn namespace_index(&self, name: &str) -> Result<usize> {
+		self.names.iter()
+			.position(|namespace| namespace == name)
+			.map(|index| index + 1)
+			.ok_or_else(|| ErrorKind::UnknownNamespace(name.to_owned()).into())
+	}
+
+	/// Calls `f` with the snapshot of the namespace, blocking modifications of flushed data meanwhile.
+	fn read<F, T>(&self, index: usize, f: F) -> T where F: FnOnce(&Snapshot) -> T {
+		let current = self.current.read();
+		let snapshots = current.as_ref().expect("state is always published before the lock is released; qed");
+		f(&snapshots[index])
+	}
+
+	/// Returns currently published snapshots without blocking modifications of flushed data.
+	fn snapshots(&self) -> Arc<Vec<Snapshot>> {
+		let current = self.current.read();
+		current.as_ref().expect("state is always published before the lock is released; qed").clone()
+	}
+
+	/// Lookup a value associated with given `key`.
+	pub fn get<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<Vec<u8>>> {
+		self.get_at(0, key.as_ref())
+	}
+
+	/// Lookup a value associated with given `key` in the namespace with given `name`.
+	pub fn get_in<K: AsRef<[u8]>>(&self, name: &str, key: K) -> Result<Option<Vec<u8>>> {
+		let index = self.namespace_index(name)?;
+		self.get_at(index, key.as_ref())
+	}
+
+	fn get_at(&self, index: usize, key: &[u8]) -> Result<Option<Vec<u8>>> {
+		self.read(index, |snapshot| {
+			let value = snapshot.get(key)?;
+			Ok(value.map(|value| value.to_vec()))
+		})
+	}
+
+	/// Lookup values associated with given `keys`.
+	///
+	/// Values are returned in the order of `keys`.
+	pub fn get_many<K: AsRef<[u8]>>(&self, keys: &[K]) -> Result<Vec<Option<Vec<u8>>>> {
+		self.get_many_at(0, keys)
+	}
+
+	/// Lookup values associated with given `keys` in the namespace with given `name`.
+	pub fn get_many_in<K: AsRef<[u8]>>(&self, name: &str, keys: &[K]) -> Result<Vec<Option<Vec<u8>>>> {
+		let index = self.namespace_index(name)?;
+		self.get_many_at(index, keys)
+	}
+
+	fn get_many_at<K: AsRef<[u8]>>(&self, index: usize, keys: &[K]) -> Result<Vec<Option<Vec<u8>>>> {
+		self.read(index, |snapshot| {
+			let values = snapshot.get_many(keys)?;
+			Ok(values.into_iter().map(|value| value.map(|value| value.to_vec())).collect())
+		})
+	}
+
+	/// Calls `f` with an iterator over the database key-value pairs.
+	///
+	/// The iterator reads the state published when it was created until `f` returns.
+	/// The database can be flushed meanwhile, the iterator keeps reading the old data.
+	pub fn iter<F, T>(&self, f: F) -> Result<T> where F: FnOnce(DatabaseIterator) -> T {
+		let snapshots = self.snapshots();
+		let iter = snapshots[0].iter()?;
+		Ok(f(iter))
+	}
+
+	/// Calls `f` with an iterator over the key-value pairs of the namespace with given `name`.
+	pub fn iter_in<F, T>(&self, name: &str, f: F) -> Result<T> where F: FnOnce(DatabaseIterator) -> T {
+		let index = self.namespace_index(name)?;
+		let snapshots = self.snapshots();
+		let iter = snapshots[index].iter()?;
+		Ok(f(iter))
+	}
+
+	/// Calls `f` with an iterator over the database key-value pairs with keys in `range`.
+	pub fn range<K, F, T>(&self, range: Range<K>, f: F) -> Result<T> where K: AsRef<[u8]>, F: FnOnce(DatabaseIterator) -> T {
+		let snapshots = self.snapshots();
+		let iter = snapshots[0].range(range)?;
+		Ok(f(iter))
+	}
+
+	/// Calls `f` with an iterator over the key-value pairs of the namespace with given `name`
+	/// with keys in `range`.
+	pub fn range_in<K, F, T>(&self, name: &str, range: Range<K>, f: F) -> Result<T> where K: AsRef<[u8]>, F: FnOnce(DatabaseIterator) -> T {
+		let index = self.namespace_index(name)?;
+		let snapshots = self.snapshots();
+		let iter = snapshots[index].range(range)?;
+		Ok(f(iter))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	extern crate tempdir;
+
+	use std::thread;
+	use std::sync::Arc;
+	use std::sync::atomic::{AtomicBool, Ordering};
+
+	use {Database, ErrorKind, Options, ValuesLen};
+	use super::DatabaseReader;
+
+	fn assert_send_sync<T: Send + Sync>() {}
+
+	#[test]
+	fn test_reader_is_send_and_sync() {
+		assert_send_sync::<DatabaseReader>();
+	}
+
+	#[test]
+	fn test_read_while_writing() {
+		let temp = tempdir::TempDir::new("test_read_while_writing").unwrap();
+
+		let mut db = Database::create(temp.path(), Options {
+			journal_eras: 1,
+			key_len: 3,
+			value_len: ValuesLen::Constant(3),
+			..Default::default()
+		}).unwrap();
+
+		let mut tx = db.create_transaction();
+		tx.insert("abc", "001").unwrap();
+		db.commit(&tx).unwrap();
+
+		let done = Arc::new(AtomicBool::new(false));
+		let handles: Vec<_> = (0..4).map(|_| {
+			let reader = db.reader();
+			let done = done.clone();
+			thread::spawn(move || {
+				while !done.load(Ordering::SeqCst) {
+					assert_eq!(reader.get("abc").unwrap().unwrap(), b"001");
+					let count = reader.iter(|iter| iter.count()).unwrap();
+					assert!(count >= 1);
+				}
+			})
+		}).collect();
+
+		for i in 0..50u8 {
+			let mut tx = db.create_transaction();
+			tx.insert([b'k', i, 0], [i, i, i]).unwrap();
+			db.commit(&tx).unwrap();
+			db.flush_journal(None).unwrap();
+		}
+
+		done.store(true, Ordering::SeqCst);
+		for handle in handles {
+			handle.join().unwrap();
+		}
+
+		let reader = db.reader();
+		assert_eq!(reader.get([b'k', 49, 0]).unwrap().unwrap(), vec![49, 49, 49]);
+		assert_eq!(reader.iter(|iter| iter.count()).unwrap(), 51);
+	}
+
+	#[test]
+	fn test_flush_while_iterating() {
+		let temp = tempdir::TempDir::new("test_flush_while_iterating").unwrap();
+
+		let mut db = Database::create(temp.path(), Options {
+			journal_eras: 0,
+			key_len: 3,
+			value_len: ValuesLen::Constant(3),
+			..Default::default()
+		}).unwrap();
+
+		let mut tx = db.create_transaction();
+		tx.insert("abc", "001").unwrap();
+		db.commit(&tx).unwrap();
+
+		let reader = db.reader();
+		reader.iter(|iter| {
+			db.flush_journal(None).unwrap();
+			assert_eq!(iter.count(), 1);
+		}).unwrap();
+		assert_eq!(reader.get("abc").unwrap().unwrap(), b"001");
+		assert_eq!(reader.iter(|iter| iter.count()).unwrap(), 1);
+	}
+
+	#[test]
+	fn test_read_namespaces() {
+		let temp = tempdir::TempDir::new("test_read_namespaces").unwrap();
+
+		let options = Options {
+			journal_eras: 1,
+			key_len: 3,
+			value_len: ValuesLen::Constant(3),
+			..Default::default()
+		};
+		let mut db = Database::create(temp.path(), options.namespace("code", Options {
+			key_len: 2,
+			value_len: ValuesLen::Constant(1),
+			..Default::default()
+		})).unwrap();
+
+		let mut tx = db.create_transaction();
+		tx.insert("abc", "001").unwrap();
+		tx.insert("def", "002").unwrap();
+		tx.namespace("code").unwrap().insert("ab", "1").unwrap();
+		tx.namespace("code").unwrap().insert("cd", "2").unwrap();
+		db.commit(&tx).unwrap();
+		let mut tx = db.create_transaction();
+		tx.insert("ghi", "003").unwrap();
+		tx.namespace("code").unwrap().delete("ab").unwrap();
+		db.commit(&tx).unwrap();
+		db.flush_journal(None).unwrap();
+
+		let reader = db.reader();
+		assert_eq!(reader.get_many(&["ghi", "xyz", "abc"]).unwrap(), vec![Some(b"003".to_vec()), None, Some(b"001".to_vec())]);
+		assert_eq!(reader.range("abc".."ghi", |iter| iter.count()).unwrap(), 2);
+		assert_eq!(reader.get_in("code", "ab").unwrap(), None);
+		assert_eq!(reader.get_in("code", "cd").unwrap().unwrap(), b"2");
+		assert_eq!(reader.get_many_in("code", &["cd", "ab"]).unwrap(), vec![Some(b"2".to_vec()), None]);
+		assert_eq!(reader.iter_in("code", |iter| iter.count()).unwrap(), 1);
+		assert_eq!(reader.range_in("code", "aa".."cc", |iter| iter.count()).unwrap(), 0);
+		assert_eq!(*reader.get_in("storage", "ab").unwrap_err().kind(), ErrorKind::UnknownNamespace("storage".into()));
+	}
+}
@@ -2,15 +2,24 @@ use std::{slice, io};
 use std::io::Read;
 use byteorder::{LittleEndian, ByteOrder};
 use field::{Header, field_size};
+use record::{checksum, CHECKSUM_SIZE};
 
 struct RawRecordIterator<'a> {
+	key_len: Option<u8>,
 	key: slice::Iter<'a, u8>,
+	checksum: Option<io::Bytes<io::Cursor<[u8; CHECKSUM_SIZE]>>>,
 	value: slice::Iter<'a, u8>,
 	value_len: Option<io::Bytes<io::Cursor<[u8; 4]>>>,
 }
 
 impl<'a> RawRecordIterator<'a> {
-	fn new(key: &'a [u8], value: &'a [u8], const_value: bool) -> Self {
+	fn new(key: &'a [u8], value: &'a [u8], const_key: bool, const_value: bool, with_checksum: bool) -> Self {
+		let key_len = if const_key {
+			None
+		} else {
+			Some(key.len() as u8)
+		};
+
 		let value_len = if const_value {
 			None
 		} else {
@@ -19,8 +28,16 @@ impl<'a> RawRecordIterator<'a> {
 			Some(io::Cursor::new(value_len).bytes())
 		};
 
+		let checksum = if with_checksum {
+			Some(io::Cursor::new(checksum(key, value)).bytes())
+		} else {
+			None
+		};
+
 		RawRecordIterator {
+			key_len,
 			key: key.iter(),
+			checksum,
 			value: value.iter(),
 			value_len,
 		}
@@ -31,10 +48,20 @@ impl<'a> Iterator for RawRecordIterator<'a> {
 	type Item = u8;
 
 	fn next(&mut self) -> Option<Self::Item> {
+		if let Some(key_len) = self.key_len.take() {
+			return Some(key_len);
+		}
+
 		if let Some(item) = self.key.next() {
 			return Some(*item);
 		}
 
+		if let Some(ref mut checksum) = self.checksum {
+			if let Some(item) = checksum.next() {
+				return Some(item.expect("io::Bytes<io::Cursor<[u8; 4]>> should never return error; qed"));
+			}
+		}
+
 		if let Some(ref mut value_len) = self.value_len {
 			if let Some(item) = value_len.next() {
 				return Some(item.expect("io::Bytes<io::Cursor<[u8; 4]>> should never return error; qed"));
@@ -100,13 +127,14 @@ impl<T: Iterator<Item = u8>> Iterator for RecordIterator<T> {
 	}
 }
 
-pub fn append_record(buffer: &mut Vec<u8>, key: &[u8], value: &[u8], field_body_size: usize, const_value: bool) {
-	let raw_record = RawRecordIterator::new(key, value, const_value);
+pub fn append_record(buffer: &mut Vec<u8>, key: &[u8], value: &[u8], field_body_size: usize, const_key: bool, const_value: bool, with_checksum: bool) {
+	let raw_record = RawRecordIterator::new(key, value, const_key, const_value, with_checksum);
 	buffer.extend(RecordIterator::new_inserted(raw_record, field_size(field_body_size)));
 }
 
 #[cfg(test)]
 mod tests {
+	use record::checksum;
 	use super::{append_record};
 
 	#[test]
@@ -118,7 +146,7 @@ mod tests {
 		let const_value = true;
 		let expected = b"\x01key\x02val\x02ue\x00";
 
-		append_record(&mut buffer, key, value, field_body_size, const_value);
+		append_record(&mut buffer, key, value, field_body_size, true, const_value, false);
 		assert_eq!(expected as &[u8], &buffer as &[u8]);
 	}
 
@@ -131,7 +159,7 @@ mod tests {
 		let const_value = true;
 		let expected = b"\x01keyvalue";
 
-		append_record(&mut buffer, key, value, field_body_size, const_value);
+		append_record(&mut buffer, key, value, field_body_size, true, const_value, false);
 		assert_eq!(expected as &[u8], &buffer as &[u8]);
 	}
 
@@ -144,7 +172,7 @@ mod tests {
 		let const_value = true;
 		let expected = b"\x01keyvalue\x00\x00";
 
-		append_record(&mut buffer, key, value, field_body_size, const_value);
+		append_record(&mut buffer, key, value, field_body_size, true, const_value, false);
 		assert_eq!(expected as &[u8], &buffer as &[u8]);
 	}
 
@@ -157,7 +185,7 @@ mod tests {
 		let const_value = false;
 		let expected = b"\x01key\x02\x05\x00\x00\x02\x00va\x02lue";
 
-		append_record(&mut buffer, key, value, field_body_size, const_value);
+		append_record(&mut buffer, key, value, field_body_size, true, const_value, false);
 		assert_eq!(expected as &[u8], &buffer as &[u8]);
 	}
 
@@ -170,7 +198,7 @@ mod tests {
 		let const_value = false;
 		let expected = b"\x01key\x05\x00\x00\x00value";
 
-		append_record(&mut buffer, key, value, field_body_size, const_value);
+		append_record(&mut buffer, key, value, field_body_size, true, const_value, false);
 		assert_eq!(expected as &[u8], &buffer as &[u8]);
 	}
 
@@ -183,7 +211,36 @@ mod tests {
 		let const_value = false;
 		let expected = b"\x01key\x05\x00\x00\x00value\x00\x00";
 
-		append_record(&mut buffer, key, value, field_body_size, const_value);
+		append_record(&mut buffer, key, value, field_body_size, true, const_value, false);
+		assert_eq!(expected as &[u8], &buffer as &[u8]);
+	}
+
+	#[test]
+	fn test_append_record_variable_key() {
+		let mut buffer = Vec::new();
+		let key = b"key";
+		let value = b"value";
+		let field_body_size = 10;
+		let const_value = true;
+		let expected = b"\x01\x03keyvalue\x00";
+
+		append_record(&mut buffer, key, value, field_body_size, false, const_value, false);
 		assert_eq!(expected as &[u8], &buffer as &[u8]);
 	}
+
+	#[test]
+	fn test_append_record_with_checksum() {
+		let mut buffer = Vec::new();
+		let key = b"key";
+		let value = b"value";
+		let field_body_size = 13;
+		let const_value = true;
+		let sum = checksum(key, value);
+		let mut expected = b"\x01\x03key".to_vec();
+		expected.extend_from_slice(&sum);
+		expected.extend_from_slice(b"value");
+
+		append_record(&mut buffer, key, value, field_body_size, false, const_value, true);
+		assert_eq!(expected, buffer);
+	}
 }
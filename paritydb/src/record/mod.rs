@@ -2,4 +2,4 @@ mod append;
 mod record;
 
 pub use self::append::{append_record};
-pub use self::record::{Record, ValueSize, HEADER_SIZE};
+pub use self::record::{checksum, Record, KeySize, ValueSize, CHECKSUM_SIZE, HEADER_SIZE, KEY_HEADER_SIZE};
@@ -1,10 +1,48 @@
 use byteorder::{LittleEndian, ByteOrder};
+use tiny_keccak::Keccak;
 
+use error::{ErrorKind, Result};
+use field;
 use field::view::FieldsView;
 
 /// Optional size of header for variable-len records.
 pub const HEADER_SIZE: usize = 4;
 
+/// Optional size of header for variable-len keys.
+pub const KEY_HEADER_SIZE: usize = 1;
+
+/// Size of optional checksum of the record key and value.
+pub const CHECKSUM_SIZE: usize = 4;
+
+/// Returns checksum of the record key and value.
+pub fn checksum(key: &[u8], value: &[u8]) -> [u8; CHECKSUM_SIZE] {
+	let mut keccak = Keccak::new_sha3_256();
+	keccak.update(key);
+	keccak.update(value);
+	let mut checksum = [0u8; CHECKSUM_SIZE];
+	keccak.finalize(&mut checksum);
+	checksum
+}
+
+/// Key size
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum KeySize {
+	/// Variable key size (needs to be read from header).
+	Variable,
+	/// Constant key size.
+	Constant(usize),
+}
+
+impl KeySize {
+	#[inline]
+	pub(crate) fn is_const(&self) -> bool {
+		match *self {
+			KeySize::Constant(_) => true,
+			KeySize::Variable => false,
+		}
+	}
+}
+
 /// Value size
 #[derive(Debug, PartialEq, Clone, Copy)]
 pub enum ValueSize {
@@ -15,42 +53,110 @@ pub enum ValueSize {
 }
 
 /// A view onto database record.
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub struct Record<'a> {
 	key: &'a [u8],
+	/// Stored right after the key, if records have checksums.
+	checksum: Option<&'a [u8]>,
 	value: FieldsView<'a>,
 	len: usize,
 }
 
 impl<'a> Record<'a> {
 	/// Creates new record given the data slice, field body and value and key size.
-	pub fn new(data: &'a [u8], field_body_size: usize, value_size: ValueSize, key_size: usize) -> Self {
-		assert!(key_size <= field_body_size);
+	///
+	/// If `has_checksum` is true, the checksum is expected after the key.
+	/// It's not validated until `has_valid_checksum` is called.
+	/// Panics if the record doesn't have a valid layout.
+	pub fn new(data: &'a [u8], field_body_size: usize, value_size: ValueSize, key_size: KeySize, has_checksum: bool) -> Self {
+		let key = Self::extract_key(data, key_size).expect("record has a valid layout; qed");
+		let mut key_end = match key_size {
+			KeySize::Constant(_) => key.len(),
+			KeySize::Variable => KEY_HEADER_SIZE + key.len(),
+		};
+
+		let checksum = if has_checksum {
+			let start = field::HEADER_SIZE + key_end;
+			key_end += CHECKSUM_SIZE;
+			Some(&data[start..start + CHECKSUM_SIZE])
+		} else {
+			None
+		};
+		assert!(key_end <= field_body_size);
 
 		let view = FieldsView::new(data, field_body_size);
-		let (key, rest) = view.split_at(key_size);
-		let key = key.raw_slice().expect("only returns None when addressed value isn't stored in a single field; \
-										  keys are always stored in a single field; qed");
+		let (_, rest) = view.split_at(key_end);
 
 		match value_size {
 			ValueSize::Constant(value_size) => {
 				let (value, _) = rest.split_at(value_size);
 
-				Record { key, value, len: value_size }
+				Record { key, checksum, value, len: value_size }
 			},
 			ValueSize::Variable => {
 				let (header, rest) = rest.split_at(HEADER_SIZE);
 				let value_len = Self::read_value_len(header) as usize;
 				let (value, _) = rest.split_at(value_len);
 
-				Record { key, value, len: value_len }
+				Record { key, checksum, value, len: value_len }
 			}
 		}
 	}
 
+	/// Returns true if the record at the beginning of `data` can hold its key and value.
+	///
+	/// Lengths read from corrupted data may point past the end of the record or the data.
+	pub(crate) fn has_valid_layout(data: &[u8], field_body_size: usize, value_size: ValueSize, key_size: KeySize, has_checksum: bool) -> bool {
+		if data.len() < field::field_size(field_body_size) {
+			return false;
+		}
+
+		let mut key_end = match key_size {
+			KeySize::Constant(len) => len,
+			KeySize::Variable => KEY_HEADER_SIZE + data[field::HEADER_SIZE] as usize,
+		};
+		if has_checksum {
+			key_end += CHECKSUM_SIZE;
+		}
+
+		// keys are always stored in the first field
+		if key_end > field_body_size {
+			return false;
+		}
+
+		let view = FieldsView::new(data, field_body_size);
+		let body_len = data.len() / field::field_size(field_body_size) * field_body_size;
+		match value_size {
+			ValueSize::Constant(len) => key_end + len <= body_len,
+			ValueSize::Variable => {
+				let mut header = [0u8; HEADER_SIZE];
+				if view.read_at(key_end, &mut header) < HEADER_SIZE {
+					return false;
+				}
+				let value_len = LittleEndian::read_u32(&header) as usize;
+				key_end + HEADER_SIZE + value_len <= body_len
+			},
+		}
+	}
+
+	/// Returns the key of the record stored at the beginning of `data`.
+	///
+	/// Keys are always stored in the first field of the record.
+	/// Fails with `InvalidRecord` if the length of a variable length key points past the end of `data`.
 	#[inline]
-	pub(crate) fn extract_key(data: &'a [u8], field_body_size: usize, key_size: usize) -> FieldsView<'a> {
-		FieldsView::with_options(data, field_body_size, 0, key_size)
+	pub(crate) fn extract_key(data: &'a [u8], key_size: KeySize) -> Result<&'a [u8]> {
+		let (start, len) = match key_size {
+			KeySize::Constant(len) => (field::HEADER_SIZE, len),
+			KeySize::Variable => match data.get(field::HEADER_SIZE) {
+				Some(&len) => (field::HEADER_SIZE + KEY_HEADER_SIZE, len as usize),
+				None => bail!(ErrorKind::InvalidRecord),
+			},
+		};
+
+		match data.get(start..start + len) {
+			Some(key) => Ok(key),
+			None => bail!(ErrorKind::InvalidRecord),
+		}
 	}
 
 	fn read_value_len(field: FieldsView<'a>) -> u32 {
@@ -81,41 +187,71 @@ impl<'a> Record<'a> {
 		self.value.copy_to_slice(slice);
 	}
 
+	/// Reads value starting at `offset` to given slice.
+	///
+	/// Returns number of bytes read, which is lower than the size of the slice
+	/// only if the end of the value is reached.
+	pub fn read_value_at(&self, offset: usize, slice: &mut [u8]) -> usize {
+		self.value.read_at(offset, slice)
+	}
+
 	/// Returns record value length.
 	pub fn value_len(&self) -> usize {
 		self.len
 	}
+
+	/// Returns false if the record has a checksum and it doesn't match its key and value.
+	pub fn has_valid_checksum(&self) -> bool {
+		let checksum = match self.checksum {
+			Some(checksum) => checksum,
+			None => return true,
+		};
+
+		let mut keccak = Keccak::new_sha3_256();
+		keccak.update(self.key);
+		let mut pos = 0;
+		while pos < self.len {
+			let chunk = self.value.chunk_at(pos);
+			keccak.update(chunk);
+			pos += chunk.len();
+		}
+
+		let mut expected = [0u8; CHECKSUM_SIZE];
+		keccak.finalize(&mut expected);
+		checksum == expected
+	}
 }
 
 #[cfg(test)]
 mod tests {
-	use super::{Record, ValueSize};
+	use super::{checksum, KeySize, Record, ValueSize};
+	use error::ErrorKind;
 	use field;
 
 	#[test]
 	fn test_extract_key() {
 		let body_size = 8;
-		let key_size = 3;
+		let key_size = KeySize::Constant(3);
 		let data = [
 			1, 0xfa, 0xfb, 0xfc, 1, 2, 3, 4, 5,
 			1, 0xfd, 0xfe, 0xff, 6, 7, 8, 9, 10,
 		];
 
-		assert_eq!(Record::extract_key(&data, body_size, key_size), &[0xfa, 0xfb, 0xfc]);
-		assert_eq!(Record::extract_key(&data[body_size + field::HEADER_SIZE..], body_size, key_size), &[0xfd, 0xfe, 0xff]);
+		assert_eq!(Record::extract_key(&data, key_size).unwrap(), &[0xfa, 0xfb, 0xfc]);
+		assert_eq!(Record::extract_key(&data[body_size + field::HEADER_SIZE..], key_size).unwrap(), &[0xfd, 0xfe, 0xff]);
 	}
 
 	#[test]
 	fn test_constant_size_record() {
 		let body_size = 8;
 		let value_size = ValueSize::Constant(5);
-		let key_size = 3;
+		let key_size = KeySize::Constant(3);
 		let data = [
 			1, 0xfa, 0xfb, 0xfc, 1, 2, 3, 4, 5,
 			1, 0xfd, 0xfe, 0xff, 6, 7, 8, 9, 10,
 		];
 
-		let record = Record::new(&data, body_size, value_size, key_size);
+		let record = Record::new(&data, body_size, value_size, key_size, false);
 		let key = record.key();
 		assert_eq!(key, [0xfa, 0xfb, 0xfc]);
 
@@ -124,7 +260,7 @@ mod tests {
 		record.read_value(&mut value);
 		assert_eq!(value, [1, 2, 3, 4, 5]);
 
-		let record = Record::new(&data[body_size + field::HEADER_SIZE..], body_size, value_size, key_size);
+		let record = Record::new(&data[body_size + field::HEADER_SIZE..], body_size, value_size, key_size, false);
 		let key = record.key();
 		assert_eq!(key, [0xfd, 0xfe, 0xff]);
 
@@ -137,7 +273,7 @@ mod tests {
 	fn test_variable_size_record() {
 		let body_size = 10;
 		let value_size = ValueSize::Variable;
-		let key_size = 2;
+		let key_size = KeySize::Constant(2);
 		let data = [
 			1, 0xfa, 0xfb, 3, 0, 0, 0, 1, 2, 3, 99,
 			1, 0xfc, 0xfd, 1, 0, 0, 0, 4, 0, 0, 0,
@@ -145,7 +281,7 @@ mod tests {
 		let mut value1 = [0; 3];
 		let mut value2 = [0; 1];
 
-		let record1 = Record::new(&data, body_size, value_size, key_size);
+		let record1 = Record::new(&data, body_size, value_size, key_size, false);
 		let key1 = record1.key();
 		assert_eq!(key1, [0xfa, 0xfb]);
 
@@ -153,7 +289,7 @@ mod tests {
 		record1.read_value(&mut value1);
 		assert_eq!(value1, [1, 2, 3]);
 
-		let record2 = Record::new(&data[body_size + field::HEADER_SIZE..], body_size, value_size, key_size);
+		let record2 = Record::new(&data[body_size + field::HEADER_SIZE..], body_size, value_size, key_size, false);
 		let key2 = record2.key();
 		assert_eq!(key2, [0xfc, 0xfd]);
 
@@ -161,4 +297,97 @@ mod tests {
 		record2.read_value(&mut value2);
 		assert_eq!(value2, [4]);
 	}
+
+	#[test]
+	fn test_read_value_at() {
+		let body_size = 4;
+		let value_size = ValueSize::Variable;
+		let key_size = KeySize::Constant(2);
+		let data = [
+			1, 0xfa, 0xfb, 7, 0,
+			2, 0, 0, 1, 2,
+			2, 3, 4, 5, 6,
+			2, 7, 0, 0, 0,
+		];
+
+		let record = Record::new(&data, body_size, value_size, key_size, false);
+		assert_eq!(record.value_len(), 7);
+
+		let mut value = [0; 4];
+		assert_eq!(record.read_value_at(0, &mut value), 4);
+		assert_eq!(value, [1, 2, 3, 4]);
+		assert_eq!(record.read_value_at(4, &mut value), 3);
+		assert_eq!(value, [5, 6, 7, 4]);
+		assert_eq!(record.read_value_at(7, &mut value), 0);
+	}
+
+	#[test]
+	fn test_has_valid_layout() {
+		let body_size = 4;
+		let data = [
+			1, 0xfa, 0xfb, 7, 0,
+			2, 0, 0, 1, 2,
+			2, 3, 4, 5, 6,
+			2, 7, 0, 0, 0,
+		];
+
+		assert!(Record::has_valid_layout(&data, body_size, ValueSize::Variable, KeySize::Constant(2), false));
+		// value length points past the end of the data
+		assert!(!Record::has_valid_layout(&data[..15], body_size, ValueSize::Variable, KeySize::Constant(2), false));
+		assert!(!Record::has_valid_layout(&data[..3], body_size, ValueSize::Variable, KeySize::Constant(2), false));
+		// key doesn't fit in the first field
+		assert!(!Record::has_valid_layout(&data, body_size, ValueSize::Constant(1), KeySize::Constant(5), false));
+		assert!(!Record::has_valid_layout(&data, body_size, ValueSize::Constant(1), KeySize::Variable, false));
+		assert!(Record::has_valid_layout(&data, body_size, ValueSize::Constant(14), KeySize::Constant(2), false));
+		assert!(!Record::has_valid_layout(&data, body_size, ValueSize::Constant(15), KeySize::Constant(2), false));
+	}
+
+	#[test]
+	fn test_variable_size_key() {
+		let body_size = 6;
+		let value_size = ValueSize::Constant(2);
+		let key_size = KeySize::Variable;
+		let data = [
+			1, 3, 0xfa, 0xfb, 0xfc, 1, 2,
+			1, 1, 0xfd, 3, 4, 0, 0,
+		];
+
+		assert_eq!(Record::extract_key(&data, key_size).unwrap(), &[0xfa, 0xfb, 0xfc]);
+		assert_eq!(Record::extract_key(&data[body_size + field::HEADER_SIZE..], key_size).unwrap(), &[0xfd]);
+		// length of the key points past the end of the data
+		assert_eq!(*Record::extract_key(&data[..4], key_size).unwrap_err().kind(), ErrorKind::InvalidRecord);
+		assert_eq!(*Record::extract_key(&data[..1], key_size).unwrap_err().kind(), ErrorKind::InvalidRecord);
+
+		let mut value = [0; 2];
+		let record = Record::new(&data[body_size + field::HEADER_SIZE..], body_size, value_size, key_size, false);
+		assert_eq!(record.key(), [0xfd]);
+		record.read_value(&mut value);
+		assert_eq!(value, [3, 4]);
+	}
+
+	#[test]
+	fn test_record_checksum() {
+		let body_size = 6;
+		let value_size = ValueSize::Variable;
+		let key_size = KeySize::Constant(1);
+		let value = [1, 2, 3, 4, 5, 6];
+		let sum = checksum(&[0xfa], &value);
+		let mut data = vec![
+			1, 0xfa, sum[0], sum[1], sum[2], sum[3], 6,
+			2, 0, 0, 0, 1, 2, 3,
+			2, 4, 5, 6, 0, 0, 0,
+		];
+
+		{
+			let record = Record::new(&data, body_size, value_size, key_size, true);
+			assert_eq!(record.key(), [0xfa]);
+			assert_eq!(record.value_len(), 6);
+			assert!(record.has_valid_checksum());
+		}
+
+		data[16] = 0;
+		let record = Record::new(&data, body_size, value_size, key_size, true);
+		assert!(!record.has_valid_checksum());
+		assert!(Record::new(&data, body_size, ValueSize::Constant(1), key_size, false).has_valid_checksum());
+	}
 }
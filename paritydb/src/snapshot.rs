@@ -0,0 +1,144 @@
+//! Database snapshot
+
+use std::ops::Range;
+use std::sync::Arc;
+
+use memmap::Mmap;
+
+use database::{self, DatabaseIterator, Value};
+use error::Result;
+use flush_lock::{self, ReadLock};
+use journal::JournalSnapshot;
+use metadata::Metadata;
+use namespace;
+use options::InternalOptions;
+
+/// A read-only view onto the database pinned at the time it was created.
+///
+/// Later commits, rollbacks and flushes do not change what the snapshot sees.
+/// Snapshots of read-only handles fail with `FlushedDataChanged` instead,
+/// once the writer modifies the flushed data.
+#[derive(Debug)]
+pub struct Snapshot {
+	options: InternalOptions,
+	journal: JournalSnapshot,
+	/// Prefix of journal keys of the namespace.
+	journal_prefix: Option<u8>,
+	metadata: Arc<Metadata>,
+	mmap: Arc<Mmap>,
+	/// Locked around each read of the flushed data of a read-only handle.
+	read_lock: Option<ReadLock>,
+}
+
+impl Snapshot {
+	pub(crate) fn new(
+		options: InternalOptions,
+		journal: JournalSnapshot,
+		journal_prefix: Option<u8>,
+		metadata: Arc<Metadata>,
+		mmap: Arc<Mmap>,
+		read_lock: Option<ReadLock>,
+	) -> Self {
+		Snapshot {
+			options,
+			journal,
+			journal_prefix,
+			metadata,
+			mmap,
+			read_lock,
+		}
+	}
+
+	/// Lookup a value associated with given `key`.
+	pub fn get<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<Value>> {
+		let key = key.as_ref();
+		database::validate_key_len(&self.options, key)?;
+
+		let read_guard = flush_lock::lock_read(&self.read_lock)?;
+		let get_flushed = || database::get_flushed(&self.options, &self.metadata, unsafe { self.mmap.as_slice() }, key);
+		let value = match self.journal.get(&namespace::journal_key(self.journal_prefix, key)) {
+			Some(entry) => {
+				let external = &self.options.external;
+				database::resolve_journal_entry(&external.merge_operator, &external.value_len, key, entry, get_flushed)?
+			},
+			None => get_flushed()?,
+		};
+
+		Ok(database::detach_value(&read_guard, value))
+	}
+
+	/// Lookup values associated with given `keys`.
+	///
+	/// Values are returned in the order of `keys`.
+	pub fn get_many<K: AsRef<[u8]>>(&self, keys: &[K]) -> Result<Vec<Option<Value>>> {
+		let read_guard = flush_lock::lock_read(&self.read_lock)?;
+		let values = database::get_many_with_journal(
+			&self.options,
+			&self.metadata,
+			unsafe { self.mmap.as_slice() },
+			|key| self.journal.get(&namespace::journal_key(self.journal_prefix, key)),
+			keys,
+		)?;
+
+		Ok(values.into_iter().map(|value| database::detach_value(&read_guard, value)).collect())
+	}
+
+	/// Returns an iterator over the snapshot key-value pairs.
+	pub fn iter(&self) -> Result<DatabaseIterator> {
+		self.iter_range(None, None, false)
+	}
+
+	/// Returns an iterator over the snapshot key-value pairs starting at `key`.
+	pub fn iter_from<K: AsRef<[u8]>>(&self, key: K) -> Result<DatabaseIterator> {
+		let key = key.as_ref();
+		database::validate_key_len(&self.options, key)?;
+		self.iter_range(Some(key), None, false)
+	}
+
+	/// Returns an iterator over the snapshot key-value pairs with keys in `range`.
+	pub fn range<K: AsRef<[u8]>>(&self, range: Range<K>) -> Result<DatabaseIterator> {
+		let start = range.start.as_ref();
+		let end = range.end.as_ref();
+		database::validate_key_len(&self.options, start)?;
+		database::validate_key_len(&self.options, end)?;
+		self.iter_range(Some(start), Some(end), false)
+	}
+
+	/// Returns an iterator over the snapshot key-value pairs in descending order.
+	pub fn iter_rev(&self) -> Result<DatabaseIterator> {
+		self.iter_range(None, None, true)
+	}
+
+	/// Returns an iterator over the snapshot key-value pairs with keys lower than `key`
+	/// in descending order.
+	pub fn iter_rev_before<K: AsRef<[u8]>>(&self, key: K) -> Result<DatabaseIterator> {
+		let key = key.as_ref();
+		database::validate_key_len(&self.options, key)?;
+		self.iter_range(None, Some(key), true)
+	}
+
+	/// Returns an iterator over the snapshot key-value pairs with keys in `range`
+	/// in descending order.
+	pub fn range_rev<K: AsRef<[u8]>>(&self, range: Range<K>) -> Result<DatabaseIterator> {
+		let start = range.start.as_ref();
+		let end = range.end.as_ref();
+		database::validate_key_len(&self.options, start)?;
+		database::validate_key_len(&self.options, end)?;
+		self.iter_range(Some(start), Some(end), true)
+	}
+
+	fn iter_range(&self, start: Option<&[u8]>, end: Option<&[u8]>, descending: bool) -> Result<DatabaseIterator> {
+		let read_guard = flush_lock::lock_read(&self.read_lock)?;
+		let iter = database::iter_with_journal(
+			&self.options,
+			&self.metadata,
+			unsafe { self.mmap.as_slice() },
+			namespace::journal_operations(self.journal.iter(), self.journal_prefix),
+			start,
+			end,
+			descending,
+		)?;
+
+		Ok(iter.with_read_guard(read_guard))
+	}
+}
@@ -37,6 +37,7 @@ pub struct SpaceIterator<'a> {
 	data: &'a [u8],
 	field_body_size: usize,
 	offset: usize,
+	overflow: bool,
 }
 
 impl<'a> SpaceIterator<'a> {
@@ -45,6 +46,18 @@ impl<'a> SpaceIterator<'a> {
 			data,
 			field_body_size,
 			offset,
+			overflow: false,
+		}
+	}
+
+	/// Creates an iterator which never ends. After the end of data it returns
+	/// empty fields of the overflow region, which can be appended to the database.
+	pub fn with_overflow(data: &'a [u8], field_body_size: usize, offset: usize) -> Self {
+		SpaceIterator {
+			data,
+			field_body_size,
+			offset,
+			overflow: true,
 		}
 	}
 
@@ -64,14 +77,28 @@ impl<'a> SpaceIterator<'a> {
 		self.offset = offset;
 		next
 	}
+
+	/// Returns next empty field of the overflow region or `None` if the iterator does not overflow.
+	fn next_overflow(&mut self) -> Option<Result<Space<'a>>> {
+		if !self.overflow {
+			return None;
+		}
+
+		let offset = self.offset;
+		self.offset += field_size(self.field_body_size);
+		Some(Ok(Space::Empty(EmptySpace {
+			offset,
+			len: self.offset - offset,
+		})))
+	}
 }
 
 impl<'a> Iterator for SpaceIterator<'a> {
 	type Item = Result<Space<'a>>;
 
 	fn next(&mut self) -> Option<Self::Item> {
-		if self.data[self.offset..].is_empty() {
-			return None;
+		if self.offset >= self.data.len() {
+			return self.next_overflow();
 		}
 
 		let mut first_header = None;
@@ -132,6 +159,10 @@ impl<'a> Iterator for SpaceIterator<'a> {
 		}
 
 		if first_header.is_none() {
+			if self.overflow {
+				// record continued till the end of data
+				return self.next_overflow();
+			}
 			// continuation was called
 			return Some(Err(ErrorKind::Field(field::ErrorKind::InvalidHeader).into()))
 		}
@@ -298,6 +329,26 @@ mod tests {
 		assert!(iterator.next().unwrap().is_err());
 	}
 
+	#[test]
+	fn test_space_iterator_overflow() {
+		let data = &[
+			0, 0, 0, 0,
+			1, 0, 0, 0,
+			2, 0, 0, 0,
+		];
+		let field_body_size = 3;
+		let offset = 0;
+
+		let mut iterator = SpaceIterator::with_overflow(data, field_body_size, offset);
+		assert_eq!(Space::Empty(EmptySpace { offset: 0, len: 4 }), iterator.next().unwrap().unwrap());
+		assert_eq!(Space::Occupied(OccupiedSpace { offset: 4, data: &data[4..12] }), iterator.next().unwrap().unwrap());
+		assert_eq!(Space::Empty(EmptySpace { offset: 12, len: 4 }), iterator.next().unwrap().unwrap());
+		assert_eq!(Space::Empty(EmptySpace { offset: 16, len: 4 }), iterator.next().unwrap().unwrap());
+
+		let mut iterator = SpaceIterator::with_overflow(data, field_body_size, 8);
+		assert_eq!(Space::Empty(EmptySpace { offset: 12, len: 4 }), iterator.next().unwrap().unwrap());
+	}
+
 	#[test]
 	fn test_space_iterator_short_insert_after_long_insert() {
 		let data = &[
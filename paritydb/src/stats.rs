@@ -0,0 +1,109 @@
+//! Database statistics
+
+use error::Result;
+use field::field_size;
+use flush::record_prefix;
+use metadata::Metadata;
+use options::InternalOptions;
+use space::{Space, SpaceIterator};
+
+/// Statistics of the database, which help to tune its options.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Stats {
+	/// Number of bytes occupied by records, including field headers.
+	pub occupied_bytes: u64,
+	/// Size of the database determined by its prefix bits.
+	/// It's `initial_db_size` until the database grows.
+	pub db_size: u64,
+	/// Ratio of `occupied_bytes` to `db_size`.
+	pub fill_ratio: f64,
+	/// Number of records.
+	pub records: u64,
+	/// Current number of prefix bits.
+	pub prefix_bits: u8,
+	/// Number of prefixes marked as occupied in the prefix tree.
+	pub occupied_prefixes: u64,
+	/// Number of eras in the journal.
+	pub journal_eras: usize,
+	/// Total size of journal era files in bytes.
+	pub journal_bytes: u64,
+	/// Number of records by their displacement, which is the number of fields
+	/// between the beginning of the record and the offset determined by its key.
+	pub displacement: Vec<u64>,
+}
+
+/// Collects statistics of flushed `data` of the database.
+///
+/// Journal statistics are left empty.
+pub(crate) fn stats(data: &[u8], metadata: &Metadata, options: &InternalOptions) -> Result<Stats> {
+	let prefix_bits = metadata.prefixes.prefix_bits();
+	let db_size = options.db_size_for_prefix_bits(prefix_bits);
+	let field_size = field_size(options.field_body_size);
+
+	let mut records = 0;
+	let mut displacement = Vec::new();
+	for space in SpaceIterator::new(data, options.field_body_size, 0) {
+		let space = match space? {
+			Space::Occupied(space) => space,
+			Space::Empty(_) => continue,
+		};
+
+		let prefix = record_prefix(space.data, options.key_size, prefix_bits)?;
+		let fields = space.offset.saturating_sub(prefix as usize * field_size) / field_size;
+		if displacement.len() <= fields {
+			displacement.resize(fields + 1, 0);
+		}
+		displacement[fields] += 1;
+		records += 1;
+	}
+
+	Ok(Stats {
+		occupied_bytes: metadata.occupied_bytes,
+		db_size,
+		fill_ratio: metadata.occupied_bytes as f64 / db_size as f64,
+		records,
+		prefix_bits,
+		occupied_prefixes: metadata.prefixes.prefixes_iter().count() as u64,
+		journal_eras: 0,
+		journal_bytes: 0,
+		displacement,
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use metadata::Metadata;
+	use options::{InternalOptions, Options, ValuesLen};
+	use super::stats;
+
+	#[test]
+	fn test_stats() {
+		let options = InternalOptions::from_external(Options {
+			key_len: 2,
+			key_index_bits: 2,
+			value_len: ValuesLen::Constant(1),
+			..Default::default()
+		}).unwrap();
+		let data = [
+			1, 0x00, 0x01, 1,
+			1, 0x00, 0x02, 2,
+			1, 0x00, 0x03, 3,
+			0, 0, 0, 0,
+			1, 0x80, 0x01, 4,
+		];
+		let mut metadata = Metadata::new(&options.external, 2);
+		metadata.insert_record(0, 4);
+		metadata.insert_record(0, 4);
+		metadata.insert_record(0, 4);
+		metadata.insert_record(2, 4);
+
+		let stats = stats(&data, &metadata, &options).unwrap();
+		assert_eq!(stats.occupied_bytes, 16);
+		assert_eq!(stats.db_size, options.initial_db_size);
+		assert_eq!(stats.fill_ratio, 16.0 / options.initial_db_size as f64);
+		assert_eq!(stats.records, 4);
+		assert_eq!(stats.prefix_bits, 2);
+		assert_eq!(stats.occupied_prefixes, 2);
+		assert_eq!(stats.displacement, vec![1, 1, 2]);
+	}
+}
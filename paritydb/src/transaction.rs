@@ -1,12 +1,16 @@
+use std::borrow::Cow;
 use std::cmp::Ordering;
 use byteorder::{LittleEndian, ByteOrder, WriteBytesExt};
 use error::{ErrorKind, Result};
+use namespace;
 
 /// Database operations
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub enum Operation<'a> {
 	Insert(&'a [u8], &'a [u8]),
 	Delete(&'a [u8]),
+	/// Merge operand applied to the value of the key.
+	Merge(&'a [u8], &'a [u8]),
 }
 
 impl<'a> PartialOrd for Operation<'a> {
@@ -24,10 +28,11 @@ impl<'a> Ord for Operation<'a> {
 impl<'a> Operation<'a> {
 	const INSERT: u8 = 0;
 	const DELETE: u8 = 1;
+	const MERGE: u8 = 2;
 
 	pub fn key(&self) -> &'a [u8] {
 		match *self {
-			Operation::Insert(key, _) | Operation::Delete(key) => key,
+			Operation::Insert(key, _) | Operation::Delete(key) | Operation::Merge(key, _) => key,
 		}
 	}
 
@@ -39,14 +44,17 @@ impl<'a> Operation<'a> {
 	/// | type |  size(s) | data |
 	/// ```
 	fn write_to_buf(&self, buf: &mut Vec<u8>) {
+		fn write_key_value(buf: &mut Vec<u8>, operation_type: u8, key: &[u8], value: &[u8]) {
+			buf.push(operation_type);
+			buf.write_u32::<LittleEndian>(key.len() as u32).unwrap();
+			buf.write_u32::<LittleEndian>(value.len() as u32).unwrap();
+			buf.extend_from_slice(key);
+			buf.extend_from_slice(value);
+		}
+
 		match *self {
-			Operation::Insert(key, value) => {
-				buf.push(Operation::INSERT);
-				buf.write_u32::<LittleEndian>(key.len() as u32).unwrap();
-				buf.write_u32::<LittleEndian>(value.len() as u32).unwrap();
-				buf.extend_from_slice(key);
-				buf.extend_from_slice(value);
-			},
+			Operation::Insert(key, value) => write_key_value(buf, Operation::INSERT, key, value),
+			Operation::Merge(key, operand) => write_key_value(buf, Operation::MERGE, key, operand),
 			Operation::Delete(key) => {
 				buf.push(Operation::DELETE);
 				buf.write_u32::<LittleEndian>(key.len() as u32).unwrap();
@@ -61,12 +69,17 @@ impl<'a> Operation<'a> {
 		}
 
 		match buf[0] {
-			Operation::INSERT => {
+			Operation::INSERT | Operation::MERGE => {
 				let key_len = LittleEndian::read_u32(&buf[1..5]) as usize;
 				let value_len = LittleEndian::read_u32(&buf[5..9]) as usize;
 				let key_end = 9 + key_len;
 				let value_end = key_end + value_len;
-				let o = Operation::Insert(&buf[9..key_end], &buf[key_end..value_end]);
+				let (key, value) = (&buf[9..key_end], &buf[key_end..value_end]);
+				let o = if buf[0] == Operation::INSERT {
+					Operation::Insert(key, value)
+				} else {
+					Operation::Merge(key, value)
+				};
 				Some((o, value_end))
 			},
 			Operation::DELETE => {
@@ -80,6 +93,33 @@ impl<'a> Operation<'a> {
 	}
 }
 
+/// Precondition of a conditional operation. It's checked against the state
+/// of the database before the transaction, when the transaction is committed.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Condition {
+	/// The key must not be in the database.
+	Absent(Vec<u8>),
+	/// The key must be in the database with given value.
+	Equals(Vec<u8>, Vec<u8>),
+}
+
+impl Condition {
+	pub fn key(&self) -> &[u8] {
+		match *self {
+			Condition::Absent(ref key) | Condition::Equals(ref key, _) => key,
+		}
+	}
+}
+
+/// Returns true if the key of given `len` may be stored in the database.
+pub(crate) fn key_len_is_valid(key_len: usize, variable_key_len: bool, len: usize) -> bool {
+	if variable_key_len {
+		len <= key_len
+	} else {
+		len == key_len
+	}
+}
+
 /// Database operations.
 pub struct Transaction {
 	/// key length, it's used to determine whether an insert
@@ -87,41 +127,80 @@ pub struct Transaction {
 	/// use `Options` or `InternalOptions` here, but right now
 	/// we only care about key size, so it's enough info.
 	key_len: usize,
+	/// If true, keys may be shorter than `key_len`.
+	variable_key_len: bool,
+	/// Names, key lengths and variable key length flags of named namespaces of the database.
+	namespaces: Vec<(String, usize, bool)>,
 	operations: Vec<u8>,
+	/// Preconditions are not written to the journal.
+	conditions: Vec<Condition>,
 }
 
 impl Transaction {
-	/// This should only be called in `Database` and some unit tests.
+	/// This should only be called in some unit tests.
 	/// Use `db.create_transaction()` in any other cases.
+	#[cfg(test)]
 	pub(crate) fn new(key_len: usize) -> Transaction {
+		Transaction::with_namespaces(key_len, false, Vec::new())
+	}
+
+	/// Creates a transaction of the database with named namespaces.
+	pub(crate) fn with_namespaces(key_len: usize, variable_key_len: bool, namespaces: Vec<(String, usize, bool)>) -> Transaction {
 		Transaction {
 			key_len: key_len,
+			variable_key_len,
+			namespaces,
 			operations: Vec::new(),
+			conditions: Vec::new(),
 		}
 	}
 
 	/// Append new insert operation to the list of transactions.
 	#[inline]
 	pub fn insert<K: AsRef<[u8]>, V: AsRef<[u8]>>(&mut self, key: K, value: V) -> Result<()> {
-		let key = key.as_ref();
-		if key.len() != self.key_len {
-			Err(ErrorKind::InvalidKeyLen(self.key_len, key.len()).into())
-		} else {
-			self.push(Operation::Insert(key, value.as_ref()));
-			Ok(())
-		}
+		self.insert_at(0, key.as_ref(), value.as_ref())
 	}
 
 	/// Append new delete operation to the list of transactions.
 	#[inline]
 	pub fn delete<K: AsRef<[u8]>>(&mut self, key: K) -> Result<()> {
-		let key = key.as_ref();
-		if key.len() != self.key_len {
-			Err(ErrorKind::InvalidKeyLen(self.key_len, key.len()).into())
-		} else {
-			self.push(Operation::Delete(key));
-			Ok(())
-		}
+		self.delete_at(0, key.as_ref())
+	}
+
+	/// Append new merge operation to the list of transactions.
+	///
+	/// The operand is combined with the value of the key by the merge operator of the database.
+	#[inline]
+	pub fn merge<K: AsRef<[u8]>, V: AsRef<[u8]>>(&mut self, key: K, operand: V) -> Result<()> {
+		self.merge_at(0, key.as_ref(), operand.as_ref())
+	}
+
+	/// Append new insert operation, which succeeds only if the key is not in the database.
+	pub fn insert_if_absent<K: AsRef<[u8]>, V: AsRef<[u8]>>(&mut self, key: K, value: V) -> Result<()> {
+		self.insert_if_absent_at(0, key.as_ref(), value.as_ref())
+	}
+
+	/// Append new delete operation, which succeeds only if the key has `expected` value.
+	pub fn delete_if_equals<K: AsRef<[u8]>, V: AsRef<[u8]>>(&mut self, key: K, expected: V) -> Result<()> {
+		self.delete_if_equals_at(0, key.as_ref(), expected.as_ref())
+	}
+
+	/// Append new insert operation, which succeeds only if the key has `expected` value.
+	pub fn swap<K: AsRef<[u8]>, E: AsRef<[u8]>, V: AsRef<[u8]>>(&mut self, key: K, expected: E, value: V) -> Result<()> {
+		self.swap_at(0, key.as_ref(), expected.as_ref(), value.as_ref())
+	}
+
+	/// Returns a handle which appends operations on the namespace with given `name`.
+	pub fn namespace(&mut self, name: &str) -> Result<NamespaceTransaction> {
+		let index = match self.namespaces.iter().position(|(namespace, _, _)| namespace == name) {
+			Some(position) => position + 1,
+			None => return Err(ErrorKind::UnknownNamespace(name.to_owned()).into()),
+		};
+
+		Ok(NamespaceTransaction {
+			transaction: self,
+			index,
+		})
 	}
 
 	/// Returns double-ended iterator over all operations in a transaction.
@@ -135,12 +214,108 @@ impl Transaction {
 		&self.operations
 	}
 
+	pub(crate) fn conditions(&self) -> &[Condition] {
+		&self.conditions
+	}
+
+	/// Returns the key under which an operation on the namespace is written to the journal.
+	fn journal_key<'k>(&self, index: usize, key: &'k [u8]) -> Result<Cow<'k, [u8]>> {
+		let (key_len, variable_key_len) = match index {
+			0 => (self.key_len, self.variable_key_len),
+			_ => (self.namespaces[index - 1].1, self.namespaces[index - 1].2),
+		};
+
+		if !key_len_is_valid(key_len, variable_key_len, key.len()) {
+			return Err(ErrorKind::InvalidKeyLen(key_len, key.len()).into());
+		}
+
+		let prefix = if self.namespaces.is_empty() { None } else { Some(index as u8) };
+		Ok(namespace::journal_key(prefix, key))
+	}
+
+	fn insert_at(&mut self, index: usize, key: &[u8], value: &[u8]) -> Result<()> {
+		let key = self.journal_key(index, key)?;
+		self.push(Operation::Insert(&key, value));
+		Ok(())
+	}
+
+	fn delete_at(&mut self, index: usize, key: &[u8]) -> Result<()> {
+		let key = self.journal_key(index, key)?;
+		self.push(Operation::Delete(&key));
+		Ok(())
+	}
+
+	fn merge_at(&mut self, index: usize, key: &[u8], operand: &[u8]) -> Result<()> {
+		let key = self.journal_key(index, key)?;
+		self.push(Operation::Merge(&key, operand));
+		Ok(())
+	}
+
+	fn insert_if_absent_at(&mut self, index: usize, key: &[u8], value: &[u8]) -> Result<()> {
+		let key = self.journal_key(index, key)?.into_owned();
+		self.push(Operation::Insert(&key, value));
+		self.conditions.push(Condition::Absent(key));
+		Ok(())
+	}
+
+	fn delete_if_equals_at(&mut self, index: usize, key: &[u8], expected: &[u8]) -> Result<()> {
+		let key = self.journal_key(index, key)?.into_owned();
+		self.push(Operation::Delete(&key));
+		self.conditions.push(Condition::Equals(key, expected.to_vec()));
+		Ok(())
+	}
+
+	fn swap_at(&mut self, index: usize, key: &[u8], expected: &[u8], value: &[u8]) -> Result<()> {
+		let key = self.journal_key(index, key)?.into_owned();
+		self.push(Operation::Insert(&key, value));
+		self.conditions.push(Condition::Equals(key, expected.to_vec()));
+		Ok(())
+	}
+
 	#[inline]
 	fn push<'a>(&mut self, operation: Operation<'a>) {
 		operation.write_to_buf(&mut self.operations);
 	}
 }
 
+/// Appends operations on a single namespace to the transaction.
+pub struct NamespaceTransaction<'a> {
+	transaction: &'a mut Transaction,
+	index: usize,
+}
+
+impl<'a> NamespaceTransaction<'a> {
+	/// Append new insert operation to the list of transactions.
+	pub fn insert<K: AsRef<[u8]>, V: AsRef<[u8]>>(&mut self, key: K, value: V) -> Result<()> {
+		self.transaction.insert_at(self.index, key.as_ref(), value.as_ref())
+	}
+
+	/// Append new delete operation to the list of transactions.
+	pub fn delete<K: AsRef<[u8]>>(&mut self, key: K) -> Result<()> {
+		self.transaction.delete_at(self.index, key.as_ref())
+	}
+
+	/// Append new merge operation to the list of transactions.
+	pub fn merge<K: AsRef<[u8]>, V: AsRef<[u8]>>(&mut self, key: K, operand: V) -> Result<()> {
+		self.transaction.merge_at(self.index, key.as_ref(), operand.as_ref())
+	}
+
+	/// Append new insert operation, which succeeds only if the key is not in the namespace.
+	pub fn insert_if_absent<K: AsRef<[u8]>, V: AsRef<[u8]>>(&mut self, key: K, value: V) -> Result<()> {
+		self.transaction.insert_if_absent_at(self.index, key.as_ref(), value.as_ref())
+	}
+
+	/// Append new delete operation, which succeeds only if the key has `expected` value.
+	pub fn delete_if_equals<K: AsRef<[u8]>, V: AsRef<[u8]>>(&mut self, key: K, expected: V) -> Result<()> {
+		self.transaction.delete_if_equals_at(self.index, key.as_ref(), expected.as_ref())
+	}
+
+	/// Append new insert operation, which succeeds only if the key has `expected` value.
+	pub fn swap<K: AsRef<[u8]>, E: AsRef<[u8]>, V: AsRef<[u8]>>(&mut self, key: K, expected: E, value: V) -> Result<()> {
+		self.transaction.swap_at(self.index, key.as_ref(), expected.as_ref(), value.as_ref())
+	}
+}
+
 /// Iterator over serialized transaction operations.
 /// Operations integrity is guaranteed.
 pub struct OperationsIterator<'a> {
@@ -173,7 +348,7 @@ impl<'a> Iterator for OperationsIterator<'a> {
 
 #[cfg(test)]
 mod tests {
-	use super::{Transaction, Operation};
+	use super::{Condition, Transaction, Operation};
 	use quickcheck::TestResult;
 
 	#[test]
@@ -181,14 +356,38 @@ mod tests {
 		let mut t = Transaction::new(3);
 		t.insert(b"key", b"value").unwrap();
 		t.delete(b"key").unwrap();
+		t.merge(b"key", b"operand").unwrap();
+		assert!(t.merge(b"key1", b"operand").is_err());
 
 		let mut operations = t.operations();
 
 		assert_eq!(operations.next(), Some(Operation::Insert(b"key", b"value")));
 		assert_eq!(operations.next(), Some(Operation::Delete(b"key")));
+		assert_eq!(operations.next(), Some(Operation::Merge(b"key", b"operand")));
 		assert_eq!(operations.next(), None);
 	}
 
+	#[test]
+	fn test_transaction_conditional_operations() {
+		let mut t = Transaction::new(3);
+		t.insert_if_absent(b"abc", b"001").unwrap();
+		t.delete_if_equals(b"def", b"002").unwrap();
+		t.swap(b"ghi", b"003", b"004").unwrap();
+		assert!(t.swap(b"ghij", b"003", b"004").is_err());
+
+		let operations: Vec<_> = t.operations().collect();
+		assert_eq!(operations, vec![
+			Operation::Insert(b"abc", b"001"),
+			Operation::Delete(b"def"),
+			Operation::Insert(b"ghi", b"004"),
+		]);
+		assert_eq!(t.conditions(), &[
+			Condition::Absent(b"abc".to_vec()),
+			Condition::Equals(b"def".to_vec(), b"002".to_vec()),
+			Condition::Equals(b"ghi".to_vec(), b"003".to_vec()),
+		]);
+	}
+
 	#[test]
 	fn test_transaction_invalid_key_len_for_insert() {
 		let mut t = Transaction::new(4);
@@ -225,6 +424,18 @@ mod tests {
 		}
 	}
 
+	quickcheck! {
+		fn quickcheck_merge_operation_roundtrips_to_and_from_buf(key: Vec<u8>, operand: Vec<u8>) -> TestResult {
+			let mut buf: Vec<u8> = Vec::new();
+			let op = Operation::Merge(key.as_slice(), operand.as_slice());
+			op.write_to_buf(&mut buf);
+			let (op_read, consumed_bytes) = Operation::read_from_buf(&buf).unwrap();
+			TestResult::from_bool(
+				op == op_read && consumed_bytes == 1 + 4 + key.len() + 4 + operand.len()
+			)
+		}
+	}
+
 	quickcheck! {
 		fn quickcheck_iterate_transaction_operations(key: Vec<u8>, value: Vec<u8>) -> TestResult {
 			let mut tx = Transaction::new(key.len());
@@ -0,0 +1,294 @@
+//! Database consistency checks
+//!
+//! Walks all fields of the flushed data and compares them with the metadata.
+//! Journal is not verified, cause its eras are protected by hashes when read.
+
+use field::{field_size, Header};
+use key::Key;
+use metadata::Metadata;
+use options::InternalOptions;
+use record::Record;
+
+/// An inconsistency found in the flushed data of the database.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Inconsistency {
+	/// Field header can't be decoded.
+	InvalidHeader {
+		/// Offset of the field.
+		offset: usize,
+		/// Header byte.
+		header: u8,
+	},
+	/// `Continued` field doesn't follow a field of a record.
+	OrphanedContinuation {
+		/// Offset of the field.
+		offset: usize,
+	},
+	/// Record is too short to hold its key and value.
+	TruncatedRecord {
+		/// Offset of the record.
+		offset: usize,
+	},
+	/// Record key is not greater than the key of the previous record.
+	UnorderedKey {
+		/// Offset of the record.
+		offset: usize,
+		/// Key of the record.
+		key: Vec<u8>,
+	},
+	/// Record is stored before the offset determined by its key.
+	MisplacedRecord {
+		/// Offset of the record.
+		offset: usize,
+		/// The lowest offset the record may be stored at.
+		min_offset: usize,
+	},
+	/// Checksum of the record doesn't match its key and value.
+	InvalidChecksum {
+		/// Offset of the record.
+		offset: usize,
+		/// Key of the record.
+		key: Vec<u8>,
+	},
+	/// Prefix of a record is not marked as occupied in the prefix tree.
+	MissingPrefix {
+		/// Unmarked prefix.
+		prefix: u32,
+	},
+	/// Number of bytes occupied by records is different than stored in metadata.
+	OccupiedBytesMismatch {
+		/// Number of bytes stored in metadata.
+		expected: u64,
+		/// Number of bytes occupied by records.
+		actual: u64,
+	},
+}
+
+/// Result of the database consistency check.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct ConsistencyReport {
+	/// Number of records.
+	pub records: u64,
+	/// Number of bytes occupied by records, including field headers.
+	pub occupied_bytes: u64,
+	/// Number of prefixes marked as occupied which have no records.
+	///
+	/// Prefixes are not unmarked when records are deleted, so these are expected.
+	pub stale_prefixes: u64,
+	/// Found inconsistencies.
+	pub inconsistencies: Vec<Inconsistency>,
+}
+
+impl ConsistencyReport {
+	/// Returns true if no inconsistencies were found.
+	pub fn is_consistent(&self) -> bool {
+		self.inconsistencies.is_empty()
+	}
+}
+
+/// Accumulates results of the verification of consecutive records.
+struct Verifier<'a> {
+	data: &'a [u8],
+	options: &'a InternalOptions,
+	prefix_bits: u8,
+	report: ConsistencyReport,
+	last_key: Option<&'a [u8]>,
+	/// Prefixes of all records.
+	prefixes: Vec<u32>,
+}
+
+impl<'a> Verifier<'a> {
+	fn verify_record(&mut self, offset: usize, end: usize) {
+		let data = &self.data[offset..end];
+		self.report.records += 1;
+		self.report.occupied_bytes += data.len() as u64;
+
+		if !Record::has_valid_layout(data, self.options.field_body_size, self.options.value_size, self.options.key_size, self.options.external.record_checksum) {
+			self.report.inconsistencies.push(Inconsistency::TruncatedRecord { offset });
+			return;
+		}
+
+		let record = Record::new(data, self.options.field_body_size, self.options.value_size, self.options.key_size, self.options.external.record_checksum);
+		let key = record.key();
+		match self.last_key {
+			Some(last) if key <= last => self.report.inconsistencies.push(Inconsistency::UnorderedKey { offset, key: key.to_vec() }),
+			_ => {},
+		}
+		self.last_key = Some(key);
+
+		let prefixed_key = Key::new(key, self.prefix_bits);
+		let min_offset = prefixed_key.offset(self.options.field_body_size);
+		if offset < min_offset {
+			self.report.inconsistencies.push(Inconsistency::MisplacedRecord { offset, min_offset });
+		}
+
+		if !record.has_valid_checksum() {
+			self.report.inconsistencies.push(Inconsistency::InvalidChecksum { offset, key: key.to_vec() });
+		}
+
+		if self.prefixes.last() != Some(&prefixed_key.prefix) {
+			self.prefixes.push(prefixed_key.prefix);
+		}
+	}
+}
+
+/// Verifies flushed `data` of the database against its `metadata`.
+pub(crate) fn verify(data: &[u8], metadata: &Metadata, options: &InternalOptions) -> ConsistencyReport {
+	let mut verifier = Verifier {
+		data,
+		options,
+		prefix_bits: metadata.prefixes.prefix_bits(),
+		report: ConsistencyReport::default(),
+		last_key: None,
+		prefixes: Vec::new(),
+	};
+
+	let field_size = field_size(options.field_body_size);
+	// offset of the first field of the current record
+	let mut record_start = None;
+	for (index, field) in data.chunks(field_size).enumerate() {
+		let offset = index * field_size;
+		if field.len() < field_size {
+			break;
+		}
+
+		match Header::from_u8(field[0]) {
+			Ok(Header::Continued) => if record_start.is_none() {
+				verifier.report.inconsistencies.push(Inconsistency::OrphanedContinuation { offset });
+			},
+			header => {
+				if let Some(start) = record_start.take() {
+					verifier.verify_record(start, offset);
+				}
+
+				match header {
+					Ok(Header::Inserted) => record_start = Some(offset),
+					Ok(_) => {},
+					Err(_) => verifier.report.inconsistencies.push(Inconsistency::InvalidHeader { offset, header: field[0] }),
+				}
+			},
+		}
+	}
+
+	if let Some(start) = record_start {
+		let end = data.len() - data.len() % field_size;
+		verifier.verify_record(start, end);
+	}
+
+	let Verifier { mut report, mut prefixes, .. } = verifier;
+
+	// prefixes are out of order only if keys are
+	prefixes.sort();
+	prefixes.dedup();
+	let mut records_prefixes = prefixes.into_iter().peekable();
+	for prefix in metadata.prefixes.prefixes_iter() {
+		while let Some(&missing) = records_prefixes.peek().filter(|&&p| p < prefix) {
+			report.inconsistencies.push(Inconsistency::MissingPrefix { prefix: missing });
+			let _ = records_prefixes.next();
+		}
+
+		if records_prefixes.peek() == Some(&prefix) {
+			let _ = records_prefixes.next();
+		} else {
+			report.stale_prefixes += 1;
+		}
+	}
+	report.inconsistencies.extend(records_prefixes.map(|prefix| Inconsistency::MissingPrefix { prefix }));
+
+	if metadata.occupied_bytes != report.occupied_bytes {
+		report.inconsistencies.push(Inconsistency::OccupiedBytesMismatch {
+			expected: metadata.occupied_bytes,
+			actual: report.occupied_bytes,
+		});
+	}
+
+	report
+}
+
+#[cfg(test)]
+mod tests {
+	use metadata::Metadata;
+	use options::{InternalOptions, Options, ValuesLen};
+	use super::{verify, Inconsistency};
+
+	fn options() -> InternalOptions {
+		InternalOptions::from_external(Options {
+			key_len: 2,
+			key_index_bits: 2,
+			value_len: ValuesLen::Constant(1),
+			..Default::default()
+		}).unwrap()
+	}
+
+	#[test]
+	fn test_verify_consistent_data() {
+		let options = options();
+		let data = [
+			1, 0x00, 0x01, 1,
+			1, 0x40, 0x01, 2,
+			1, 0x40, 0x02, 3,
+			0, 0, 0, 0,
+		];
+		let mut metadata = Metadata::new(&options.external, 2);
+		metadata.insert_record(0, 4);
+		metadata.insert_record(1, 4);
+		metadata.insert_record(1, 4);
+		// prefix left after a deletion
+		metadata.prefixes.insert(3);
+
+		let report = verify(&data, &metadata, &options);
+		assert!(report.is_consistent(), "{:?}", report);
+		assert_eq!(report.records, 3);
+		assert_eq!(report.occupied_bytes, 12);
+		assert_eq!(report.stale_prefixes, 1);
+	}
+
+	#[test]
+	fn test_verify_inconsistent_data() {
+		let options = options();
+		let data = [
+			2, 0, 0, 0,
+			1, 0x40, 0x02, 1,
+			1, 0x40, 0x01, 2,
+			1, 0x00, 0x01, 3,
+			7, 0, 0, 0,
+		];
+		let mut metadata = Metadata::new(&options.external, 2);
+		metadata.insert_record(1, 4);
+
+		let report = verify(&data, &metadata, &options);
+		assert_eq!(report.records, 3);
+		assert_eq!(report.inconsistencies, vec![
+			Inconsistency::OrphanedContinuation { offset: 0 },
+			Inconsistency::UnorderedKey { offset: 8, key: vec![0x40, 0x01] },
+			Inconsistency::UnorderedKey { offset: 12, key: vec![0x00, 0x01] },
+			Inconsistency::InvalidHeader { offset: 16, header: 7 },
+			Inconsistency::MissingPrefix { prefix: 0 },
+			Inconsistency::OccupiedBytesMismatch { expected: 4, actual: 12 },
+		]);
+	}
+
+	#[test]
+	fn test_verify_misplaced_and_truncated_records() {
+		let options = InternalOptions::from_external(Options {
+			key_len: 2,
+			key_index_bits: 2,
+			value_len: ValuesLen::Variable { expected: 0 },
+			..Default::default()
+		}).unwrap();
+		// field body: key (2) + value len (4) + value (0)
+		let data = [
+			1, 0x80, 0x01, 0, 0, 0, 0,
+			0, 0, 0, 0, 0, 0, 0,
+			1, 0x80, 0x02, 9, 0, 0, 0,
+		];
+		let mut metadata = Metadata::new(&options.external, 2);
+		metadata.insert_record(2, 14);
+
+		let report = verify(&data, &metadata, &options);
+		assert_eq!(report.inconsistencies, vec![
+			Inconsistency::MisplacedRecord { offset: 0, min_offset: 14 },
+			Inconsistency::TruncatedRecord { offset: 14 },
+		]);
+	}
+}